@@ -0,0 +1,240 @@
+//! The Internals behind the client's tunnels. Backends with a health check
+//! are probed in the background, and connections only go to the ones that
+//! pass, spread over them by each tunnel's balance.
+use crate::config::client as config;
+use crate::config::Balance;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+// Health check responses are only read this far, looking for the status line
+const MAX_CHECK_RESPONSE: usize = 8 * 1024;
+
+/// Held by each connection to a backend, so that its live connections can
+/// be counted for least-connections
+pub type Lease = Arc<()>;
+
+// Backends are checked once for each tunnel they're in, named as in the
+// backend_healthy metric, so the same Internal can be checked differently by
+// two tunnels
+type Key = (String, config::Backend, Option<config::HealthCheck>);
+
+struct State {
+    // Backends start out healthy, and those without checks stay that way
+    healthy: Arc<AtomicBool>,
+    lease: Lease,
+    // Stops the health checks
+    token: CancellationToken,
+}
+
+impl State {
+    fn live(&self) -> usize {
+        Arc::strong_count(&self.lease) - 1
+    }
+}
+
+pub struct Backends {
+    token: CancellationToken,
+    states: HashMap<Key, State>,
+    // Where round-robin picks up next, by tunnel
+    next: HashMap<Vec<Key>, usize>,
+    // Woken when a backend goes up or down
+    changed: Arc<Notify>,
+}
+
+impl Backends {
+    /// Checks run until `token` is cancelled or this is dropped
+    pub fn new(token: CancellationToken) -> Self {
+        Backends {
+            token: token.child_token(),
+            states: HashMap::new(),
+            next: HashMap::new(),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Notified whenever a backend goes up or down
+    pub fn changed(&self) -> Arc<Notify> {
+        self.changed.clone()
+    }
+
+    /// Whether any backend of `tunnel` is up, or None if it isn't checked
+    pub fn healthy(&self, tunnel: &config::Tunnel) -> Option<bool> {
+        tunnel.health_check.as_ref()?;
+        Some(keys_of(tunnel).iter().any(|k| {
+            self.states
+                .get(k)
+                .is_some_and(|s| s.healthy.load(Ordering::Relaxed))
+        }))
+    }
+
+    /// Start checking the backends of `tunnels` that aren't checked yet, and
+    /// stop checking the ones no tunnel has anymore
+    pub fn sync<'a>(&mut self, tunnels: impl Iterator<Item = &'a config::Tunnel>) {
+        let mut keys = Vec::new();
+        for t in tunnels {
+            keys.extend(keys_of(t));
+        }
+        self.states.retain(|key, state| {
+            let keep = keys.contains(key);
+            if !keep {
+                state.token.cancel();
+                if key.2.is_some() {
+                    let _ = crate::metrics::metrics()
+                        .backend_healthy
+                        .remove_label_values(&[&key.0, &key.1.to_string()]);
+                }
+            }
+            keep
+        });
+        self.next
+            .retain(|tunnel, _| tunnel.iter().all(|k| keys.contains(k)));
+        for key in keys {
+            if self.states.contains_key(&key) {
+                continue;
+            }
+            let state = State {
+                healthy: Arc::new(AtomicBool::new(true)),
+                lease: Arc::new(()),
+                token: self.token.child_token(),
+            };
+            if let Some(ref check) = key.2 {
+                tokio::spawn(run_checks(
+                    key.0.clone(),
+                    key.1.clone(),
+                    check.clone(),
+                    state.healthy.clone(),
+                    self.changed.clone(),
+                    state.token.clone(),
+                ));
+            }
+            self.states.insert(key, state);
+        }
+    }
+
+    /// The healthy backends of `tunnel`, in the order they should be tried
+    /// for the next connection, each with the lease to hold while connected
+    pub fn pick(&mut self, tunnel: &config::Tunnel) -> Vec<(config::Backend, Lease)> {
+        let keys = keys_of(tunnel);
+        let next = self.next.entry(keys.clone()).or_default();
+        let start = *next % keys.len();
+        *next = next.wrapping_add(1);
+
+        let mut candidates: Vec<_> = keys[start..]
+            .iter()
+            .chain(keys[..start].iter())
+            .filter_map(|k| self.states.get(k).map(|s| (k, s)))
+            .filter(|(_, s)| s.healthy.load(Ordering::Relaxed))
+            .collect();
+        if tunnel.balance == Balance::LeastConnections {
+            // Stable, so that ties go round-robin
+            candidates.sort_by_key(|(_, s)| s.live());
+        }
+        candidates
+            .into_iter()
+            .map(|(k, s)| (k.1.clone(), s.lease.clone()))
+            .collect()
+    }
+}
+
+impl Drop for Backends {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+fn keys_of(tunnel: &config::Tunnel) -> Vec<Key> {
+    // The remote_port, or the hostname of a tunnel that doesn't have one
+    let name = match tunnel.vhost() {
+        Some(vhost) => vhost.to_string(),
+        None => tunnel.remote_port.to_string(),
+    };
+    tunnel
+        .backends()
+        .into_iter()
+        .map(|b| (name.clone(), b, tunnel.health_check.clone()))
+        .collect()
+}
+
+#[tracing::instrument(name = "HealthCheck", level = "info", skip_all, fields(tunnel = tunnel, backend = %backend))]
+async fn run_checks(
+    tunnel: String,
+    backend: config::Backend,
+    check: config::HealthCheck,
+    healthy: Arc<AtomicBool>,
+    changed: Arc<Notify>,
+    token: CancellationToken,
+) {
+    let gauge = crate::metrics::metrics()
+        .backend_healthy
+        .with_label_values(&[&tunnel, &backend.to_string()]);
+    gauge.set(1);
+    let timeout = Duration::from_secs(check.timeout);
+    let interval = Duration::from_secs(check.interval);
+    loop {
+        let ret = tokio::select! {
+            ret = tokio::time::timeout(timeout, probe(&backend, &check)) => match ret {
+                Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+                Ok(ret) => ret,
+            },
+            _ = token.cancelled() => return,
+        };
+        let was_healthy = healthy.swap(ret.is_ok(), Ordering::Relaxed);
+        match ret {
+            Ok(()) if !was_healthy => info!("backend is healthy again"),
+            Err(e) if was_healthy => warn!(cause = %e, "backend failed its health check"),
+            _ => (),
+        }
+        if was_healthy != healthy.load(Ordering::Relaxed) {
+            changed.notify_one();
+        }
+        gauge.set(healthy.load(Ordering::Relaxed) as i64);
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => (),
+            _ = token.cancelled() => return,
+        }
+    }
+}
+
+// Connect to the backend, and GET the check's path if it has one. Checks are
+// always plaintext, even for tunnels to an Internal speaking TLS
+async fn probe(backend: &config::Backend, check: &config::HealthCheck) -> std::io::Result<()> {
+    let mut stream =
+        TcpStream::connect((backend.local_hostname.as_str(), backend.local_port)).await?;
+    let Some(ref path) = check.http_path else {
+        return Ok(());
+    };
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {backend}\r\nUser-Agent: nat-tunnel\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = Vec::new();
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+        if let Ok(httparse::Status::Complete(_)) = response.parse(&buf) {
+            return match response.code {
+                Some(code) if (200..400).contains(&code) => Ok(()),
+                code => Err(std::io::Error::other(format!(
+                    "health check got status {code:?}"
+                ))),
+            };
+        }
+        if buf.len() >= MAX_CHECK_RESPONSE {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
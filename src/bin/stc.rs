@@ -1,15 +1,22 @@
 use clap::Parser;
 use color_eyre::eyre::Report;
+use nat_tunnel::control::ConnectionState;
 use nat_tunnel::net::Error;
-use nat_tunnel::net::IoSnafu;
-use nat_tunnel::{client, config::client as config, net as stnet};
+use nat_tunnel::net::{IoSnafu, ResolveSnafu};
+use nat_tunnel::{client, config::client as config, config::client::ServerMode, net as stnet};
 use snafu::ResultExt;
 use std::process::exit;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net as tnet;
+use tokio::sync::watch;
 use tokio_rustls::TlsConnector;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use tracing::{error, info, info_span, warn, Instrument};
+
+// How long the server holding the tunnels in failover mode may be down
+// before they are moved to another one
+const FAILOVER_GRACE: Duration = Duration::from_secs(3);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -18,11 +25,63 @@ struct Args {
     pub config: std::path::PathBuf,
     #[arg(long, default_value = "false")]
     pub allow_insecure_transport: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Print the status of the stc running with this config and exit
+    /// non-zero if it is not connected
+    Status {
+        /// Print the raw JSON status
+        #[arg(long, default_value = "false")]
+        json: bool,
+    },
+    /// Drain the stc running with this config: it stops taking new
+    /// connections and exits once the live ones are done, or drain_timeout
+    /// passes
+    Drain,
+}
+
+async fn status(c: &config::Config, json: bool) -> color_eyre::Result<()> {
+    let Some(ref path) = c.control_socket else {
+        eprintln!("control_socket is not set in the config");
+        exit(2);
+    };
+    let report = match nat_tunnel::control::query(path).await {
+        Err(e) => {
+            eprintln!("stc is not running: {e}");
+            exit(2);
+        }
+        Ok(r) => r,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+    }
+    if report.state != ConnectionState::Connected {
+        exit(1);
+    }
+    Ok(())
+}
+
+async fn drain(c: &config::Config) -> color_eyre::Result<()> {
+    let Some(ref path) = c.control_socket else {
+        eprintln!("control_socket is not set in the config");
+        exit(2);
+    };
+    if let Err(e) = nat_tunnel::control::drain(path).await {
+        eprintln!("stc is not running: {e}");
+        exit(2);
+    }
+    println!("draining");
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
-    tracing_subscriber::fmt::init();
     color_eyre::install().unwrap();
     let provider = rustls::crypto::aws_lc_rs::default_provider();
     provider
@@ -30,7 +89,24 @@ async fn main() -> color_eyre::Result<()> {
         .expect("failed to install crypto provider");
     let args = Args::parse();
 
-    let c = config::load_config(&args.config).expect("invalid config");
+    let c = nat_tunnel::logging::with_default(|| config::load_config(&args.config))
+        .expect("invalid config");
+    let telemetry = nat_tunnel::logging::with_default(|| {
+        c.otel
+            .as_ref()
+            .map(|o| nat_tunnel::otel::init(o, "stc"))
+            .transpose()
+    })?;
+    let access_log = nat_tunnel::logging::AccessLog {
+        target: nat_tunnel::http_tunnel::ACCESS_TARGET,
+        file: None,
+    };
+    nat_tunnel::logging::init(&c.logging, Some(access_log), telemetry.as_ref())?;
+    match args.command {
+        Some(Command::Status { json }) => return status(&c, json).await,
+        Some(Command::Drain) => return drain(&c).await,
+        None => (),
+    }
     if c.crypto.is_none() && !args.allow_insecure_transport {
         panic!("Insecure transport in use without --allow-insecure-transport");
     }
@@ -42,8 +118,105 @@ async fn main() -> color_eyre::Result<()> {
     });
 
     let token = CancellationToken::new();
-    let mut failures = 0;
-    let mut last_failure = std::time::Instant::now();
+    // Cancelled to stop taking new connections and to exit once the live
+    // ones are done
+    let drain = CancellationToken::new();
+
+    if let Some(addr) = c.metrics_addr {
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = nat_tunnel::metrics::serve(addr, token).await {
+                error!(cause = ?e, "metrics endpoint failed");
+            }
+        });
+    }
+
+    // Each server gets its own Client, fed its own view of the config so
+    // that the tunnels can be moved between servers like any reload
+    let servers = c.servers();
+    let persist = servers.len() > 1;
+    let mut views = Vec::with_capacity(servers.len());
+    let mut supervisors = tokio::task::JoinSet::new();
+    for (i, addr) in servers.into_iter().enumerate() {
+        let mut view = c.clone();
+        view.addr = addr.clone();
+        // The forwards and visitors, and in failover mode the tunnels, start
+        // out on the first server
+        if i > 0 {
+            view.forwards = Vec::new();
+            view.visitors = Vec::new();
+            if c.server_mode == ServerMode::Failover {
+                view.tunnels = Default::default();
+            }
+        }
+        let status = Arc::new(nat_tunnel::control::Status::new(&view));
+        let (view_tx, view_rx) = watch::channel(view);
+        supervisors.spawn(
+            supervise(
+                view_rx,
+                token.clone(),
+                drain.clone(),
+                crypto_cfg.clone(),
+                status.clone(),
+                persist,
+            )
+            .instrument(info_span!("Server", addr = addr)),
+        );
+        views.push((view_tx, status));
+    }
+
+    if let Some(ref path) = c.control_socket {
+        let path = path.clone();
+        let statuses = views.iter().map(|(_, s)| s.clone()).collect();
+        let drain = drain.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = nat_tunnel::control::serve(path, statuses, drain, token).await {
+                error!(cause = ?e, "control socket failed");
+            }
+        });
+    }
+
+    // Spawn a separate task to reload tunnels on SIGHUP. Only `tunnels`,
+    // `forwards` and `visitors` are taken from the new config, everything
+    // else requires a restart
+    let (reload_tx, reload_rx) = tokio::sync::watch::channel(c.clone());
+    let config_path = args.config.clone();
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sighup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+        while sighup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading tunnels");
+            match config::load_config(&config_path) {
+                Err(e) => error!(cause = ?e, "failed to reload config. Keeping the current one"),
+                Ok(new) => reload_tx.send_modify(|c| {
+                    c.tunnels = new.tunnels;
+                    c.forwards = new.forwards;
+                    c.visitors = new.visitors;
+                }),
+            }
+        }
+    });
+    tokio::spawn(coordinate(c.server_mode, reload_rx, views, token.clone()));
+
+    // Drain on SIGTERM, or when asked to on the control socket, and shut
+    // down for good once drain_timeout passes
+    let drain_timeout = c.drain_timeout;
+    let shutdown_token = token.clone();
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM, draining...");
+                drain.cancel();
+            }
+            _ = drain.cancelled() => info!("Draining..."),
+        }
+        tokio::time::sleep(drain_timeout).await;
+        warn!("drain_timeout passed, shutting down");
+        shutdown_token.cancel();
+    });
 
     // Spawn a separate task to handle SIGINT
     let shutdown_token = token.clone();
@@ -60,28 +233,103 @@ async fn main() -> color_eyre::Result<()> {
         exit(1);
     });
 
+    // Carry on for as long as any server is still being connected to
+    let mut ret = Ok(());
+    while let Some(r) = supervisors.join_next().await {
+        match r {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => ret = Err(e),
+            // Leave the sessions to the other servers be
+            Err(e) => {
+                error!(cause = ?e, "supervisor panicked");
+                ret = Err(e.into());
+            }
+        }
+    }
+    ret
+}
+
+/// Keep a Client connected to the server at the `addr` of `view` until
+/// `token` is cancelled, or until the session ends once `drain` is, backing
+/// off between failed attempts as the retry policy says. With `persist`, any
+/// error but the server turning us away is retried rather than only those a
+/// server can recover from, since the other servers carry on meanwhile
+async fn supervise(
+    mut view: watch::Receiver<config::Config>,
+    token: CancellationToken,
+    drain: CancellationToken,
+    crypto_cfg: Option<Arc<rustls::ClientConfig>>,
+    status: Arc<nat_tunnel::control::Status>,
+    persist: bool,
+) -> color_eyre::Result<()> {
+    use nat_tunnel::config::Transport;
+
+    let policy = view.borrow().retry.clone();
+    let max_attempts = policy.max_attempts();
+    let mut backoff = nat_tunnel::retry::Backoff::new(policy, max_attempts);
+    // Kept across attempts while its session can be resumed
+    let mut client = None;
     loop {
-        use nat_tunnel::config::Transport;
+        let c = view.borrow_and_update().clone();
+        status.set_connecting();
+        let reload = view.clone();
         let ft = match c.transport {
-            Transport::Quic => run_quic(c.clone(), token.clone()).await,
-            Transport::Tcp => run(c.clone(), token.clone(), &crypto_cfg).await,
+            Transport::Quic => {
+                run_quic(
+                    &mut client,
+                    c,
+                    token.clone(),
+                    drain.clone(),
+                    status.clone(),
+                    reload,
+                )
+                .await
+            }
+            Transport::Tcp => {
+                run(
+                    &mut client,
+                    c,
+                    token.clone(),
+                    drain.clone(),
+                    &crypto_cfg,
+                    status.clone(),
+                    reload,
+                )
+                .await
+            }
         };
+        // A session that got as far as connecting starts the backoff over
+        if status.state() == ConnectionState::Connected {
+            backoff.reset();
+        }
+        status.set_disconnected();
+        if drain.is_cancelled() && client.is_none() {
+            info!("drained");
+            return Ok(());
+        }
         match ft {
-            Ok(_) => exit(0),
-            Err(e) if e.reconnectable_err() => {
+            Ok(_) => return Ok(()),
+            // A wrong psk, or a server ending the session as we connect,
+            // won't be any different the next time
+            Err(e)
+                if e.reconnectable_err() || (persist && !matches!(e, Error::ConnectionRefused)) =>
+            {
                 if token.is_cancelled() {
                     return Ok(());
                 }
-                if last_failure.elapsed() >= std::time::Duration::from_secs(5) {
-                    failures = 0;
-                }
-                last_failure = std::time::Instant::now();
-                failures += 1;
-                if failures >= 5 {
-                    error!("client has failed 5 times in 5 seconds. Exiting");
+                let Some(delay) = backoff.next_delay() else {
+                    error!(cause = ?e, attempts = backoff.attempts(), "client has failed too many times in a row. Giving up");
                     return Err(Report::from(e));
+                };
+                error!(cause = ?e, attempt = backoff.attempts(), delay = ?delay, "client has failed. Retrying after a delay");
+                status.set_retry(backoff.attempts(), delay);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => (),
+                    _ = token.cancelled() => return Ok(()),
+                    // Nothing left to drain
+                    _ = drain.cancelled(), if client.is_none() => return Ok(()),
                 }
-                error!(cause = ?e, "client has failed. attempting recovery");
+                status.record_reconnect();
             }
             Err(e) => {
                 error!(cause = ?e, "client has failed with unrecoverable error");
@@ -91,6 +339,90 @@ async fn main() -> color_eyre::Result<()> {
     }
 }
 
+/// Hand the tunnels of the latest config to the servers that should hold
+/// them: every server in active-active mode, the one in use in failover mode.
+/// The forwards and visitors go to the one in use in either mode, since they
+/// can only be listened for once
+async fn coordinate(
+    mode: ServerMode,
+    mut reload: watch::Receiver<config::Config>,
+    views: Vec<(
+        watch::Sender<config::Config>,
+        Arc<nat_tunnel::control::Status>,
+    )>,
+    token: CancellationToken,
+) {
+    let mut active = 0;
+    // When the server in use was last seen not connected
+    let mut down_since = None;
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            maybe_reload = reload.changed() => {
+                if maybe_reload.is_err() {
+                    return;
+                }
+            }
+            _ = token.cancelled() => return,
+        }
+        // Give the server in use a moment to (re)connect before moving off
+        // it, so that a slow handshake doesn't cause a failover
+        if views[active].1.state() == ConnectionState::Connected {
+            down_since = None;
+        } else if down_since.get_or_insert_with(Instant::now).elapsed() >= FAILOVER_GRACE {
+            if let Some(next) = failover(&views) {
+                info!(
+                    from = views[active].1.server_addr(),
+                    to = views[next].1.server_addr(),
+                    "moving to another server"
+                );
+                active = next;
+                down_since = None;
+            }
+        }
+        let (tunnels, forwards, visitors) = {
+            let c = reload.borrow_and_update();
+            (c.tunnels.clone(), c.forwards.clone(), c.visitors.clone())
+        };
+        for (i, (view, _)) in views.iter().enumerate() {
+            let wanted = match mode {
+                ServerMode::ActiveActive => tunnels.clone(),
+                ServerMode::Failover if i == active => tunnels.clone(),
+                ServerMode::Failover => Default::default(),
+            };
+            let (wanted_forwards, wanted_visitors) = match i == active {
+                true => (forwards.clone(), visitors.clone()),
+                false => (Vec::new(), Vec::new()),
+            };
+            view.send_if_modified(|c| {
+                let changed = c.tunnels != wanted
+                    || c.forwards != wanted_forwards
+                    || c.visitors != wanted_visitors;
+                c.tunnels = wanted;
+                c.forwards = wanted_forwards;
+                c.visitors = wanted_visitors;
+                changed
+            });
+        }
+    }
+}
+
+// The connected server with the lowest heartbeat RTT, earlier servers first
+fn failover(
+    views: &[(
+        watch::Sender<config::Config>,
+        Arc<nat_tunnel::control::Status>,
+    )],
+) -> Option<usize> {
+    views
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, s))| s.state() == ConnectionState::Connected)
+        .min_by_key(|(i, (_, s))| (s.rtt().unwrap_or(Duration::MAX), *i))
+        .map(|(i, _)| i)
+}
+
 // addr Should(tm) be either:
 // 1. ipv6 address [:port]
 // 2. ipv4 address [:port]
@@ -110,7 +442,43 @@ fn why_do_i_have_to_impl_this(addr: &str) -> &str {
     }
 }
 
-async fn run_quic(c: config::Config, token: CancellationToken) -> nat_tunnel::net::Result<()> {
+/// Run the Client of `slot` over `stream`, resuming its session, or a new
+/// Client if there is no session to resume
+#[allow(clippy::too_many_arguments)]
+async fn run_client(
+    slot: &mut Option<client::Client<stnet::BoxedStream>>,
+    c: config::Config,
+    token: CancellationToken,
+    drain: CancellationToken,
+    peer_addr: stnet::StreamId,
+    stream: stnet::BoxedStream,
+    status: Arc<nat_tunnel::control::Status>,
+    reload: watch::Receiver<config::Config>,
+) -> nat_tunnel::net::Result<()> {
+    match slot.as_mut() {
+        Some(client) => client.reconnect(peer_addr, stream),
+        None => {
+            *slot = Some(client::Client::new(
+                c, token, drain, peer_addr, stream, status, reload,
+            ))
+        }
+    }
+    let client = slot.as_mut().expect("client was just set");
+    let ret = client.run().await;
+    if !client.resumable() {
+        *slot = None;
+    }
+    ret
+}
+
+async fn run_quic(
+    client: &mut Option<client::Client<stnet::BoxedStream>>,
+    c: config::Config,
+    token: CancellationToken,
+    drain: CancellationToken,
+    status: Arc<nat_tunnel::control::Status>,
+    reload: watch::Receiver<config::Config>,
+) -> nat_tunnel::net::Result<()> {
     use quinn_proto::crypto::rustls::QuicClientConfig;
     use std::net::ToSocketAddrs;
 
@@ -134,8 +502,8 @@ async fn run_quic(c: config::Config, token: CancellationToken) -> nat_tunnel::ne
     let addrs: Vec<_> = c
         .addr
         .to_socket_addrs()
-        .with_context(|_| IoSnafu {
-            message: format!("dns resolution failed for {}", c.addr),
+        .with_context(|_| ResolveSnafu {
+            addr: c.addr.clone(),
         })?
         .collect();
     let expected_host = why_do_i_have_to_impl_this(&c.addr);
@@ -147,7 +515,7 @@ async fn run_quic(c: config::Config, token: CancellationToken) -> nat_tunnel::ne
     }
     let conn = conn.unwrap();
 
-    let (send, recv) = conn.open_bi().await.expect("failed to open stream");
+    let (send, recv) = conn.open_bi().await?;
 
     let id = stnet::StreamId::Quic(
         quinn_proto::ConnectionId::new(&[0x00]),
@@ -156,22 +524,35 @@ async fn run_quic(c: config::Config, token: CancellationToken) -> nat_tunnel::ne
     );
     let b = nat_tunnel::server::QuicBox::new(send, recv);
     info!("TLS enabled. All connections to the Server will be encrypted.");
-    let mut client = client::Client::new(c, token, id, b);
-    client.run().await
+    run_client(client, c, token, drain, id, Box::new(b), status, reload).await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run(
+    client: &mut Option<client::Client<stnet::BoxedStream>>,
     c: config::Config,
     token: CancellationToken,
+    drain: CancellationToken,
     crypto_cfg: &Option<Arc<rustls::ClientConfig>>,
+    status: Arc<nat_tunnel::control::Status>,
+    reload: watch::Receiver<config::Config>,
 ) -> nat_tunnel::net::Result<()> {
     info!("Handshaking with {}", &c.addr);
-    let client_stream = tokio::select! {
-        result = tnet::TcpStream::connect(&c.addr) => {
-            result.with_context(|_| IoSnafu {
-                message: format!("failed to connect to server {addr:?}", addr=c.addr)
+    let connect = async {
+        let addrs: Vec<_> = tnet::lookup_host(&c.addr)
+            .await
+            .with_context(|_| ResolveSnafu {
+                addr: c.addr.clone(),
             })?
-        }
+            .collect();
+        tnet::TcpStream::connect(&addrs[..])
+            .await
+            .with_context(|_| IoSnafu {
+                message: format!("failed to connect to server {addr:?}", addr = c.addr),
+            })
+    };
+    let client_stream = tokio::select! {
+        result = connect => result?,
         _ = token.cancelled() => {
             return Err(nat_tunnel::net::IoTimeoutSnafu {
                 context: "connection attempt cancelled",
@@ -203,13 +584,35 @@ async fn run(
         let client_stream = conn
             .connect(domain, client_stream)
             .await
-            .expect("TLS initialization failed");
+            .with_context(|_| IoSnafu {
+                message: "TLS handshake with the server failed",
+            })?;
 
         info!("TLS enabled. All connections to the Server will be encrypted.");
-        let mut client = client::Client::new(c, token.clone(), peer_addr.into(), client_stream);
-        client.run().await
+        let stream = Box::new(client_stream);
+        run_client(
+            client,
+            c,
+            token,
+            drain,
+            peer_addr.into(),
+            stream,
+            status,
+            reload,
+        )
+        .await
     } else {
-        let mut client = client::Client::new(c, token.clone(), peer_addr.into(), client_stream);
-        client.run().await
+        let stream = Box::new(client_stream);
+        run_client(
+            client,
+            c,
+            token,
+            drain,
+            peer_addr.into(),
+            stream,
+            status,
+            reload,
+        )
+        .await
     }
 }
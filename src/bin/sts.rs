@@ -1,11 +1,12 @@
 use clap::Parser;
 use color_eyre::eyre::{Report, Result as CEResult};
-use nat_tunnel::{config::server as config, net::IoSnafu, server};
+use nat_tunnel::{config::server as config, net::IoSnafu, net::VhostKind, server};
 use snafu::ResultExt;
 use std::process::exit;
+use std::sync::Arc;
 use tokio::net as tnet;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{error, info, warn};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -18,7 +19,6 @@ struct Args {
 
 #[tokio::main]
 async fn main() -> CEResult<()> {
-    tracing_subscriber::fmt::init();
     color_eyre::install()?;
     let provider = rustls::crypto::aws_lc_rs::default_provider();
     provider
@@ -26,7 +26,18 @@ async fn main() -> CEResult<()> {
         .expect("failed to install crypto provider");
     let args = Args::parse();
 
-    let c = config::load_config(&args.config)?;
+    let c = nat_tunnel::logging::with_default(|| config::load_config(&args.config))?;
+    let telemetry = nat_tunnel::logging::with_default(|| {
+        c.otel
+            .as_ref()
+            .map(|o| nat_tunnel::otel::init(o, "sts"))
+            .transpose()
+    })?;
+    let access_log = nat_tunnel::logging::AccessLog {
+        target: nat_tunnel::server::access::TARGET,
+        file: c.access_log.as_deref(),
+    };
+    nat_tunnel::logging::init(&c.logging, Some(access_log), telemetry.as_ref())?;
     if c.crypto.is_none() && !args.allow_insecure_transport {
         panic!("Insecure transport in use without --allow-insecure-transport");
     }
@@ -36,6 +47,15 @@ async fn main() -> CEResult<()> {
 
     let token = CancellationToken::new();
 
+    if let Some(addr) = c.metrics_addr {
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = nat_tunnel::metrics::serve(addr, token).await {
+                error!(cause = ?e, "metrics endpoint failed");
+            }
+        });
+    }
+
     let shutdown_token = token.clone();
     tokio::spawn(async move {
         tokio::signal::ctrl_c()
@@ -50,22 +70,159 @@ async fn main() -> CEResult<()> {
         exit(1);
     });
 
+    let admin_addr = c.admin_addr;
+    let drain_timeout = c.drain_timeout;
+    let http_addr = c.http_addr;
+    let sni_addr = c.sni_addr;
+
     // TODO wow lazy
     use nat_tunnel::config::Transport;
     match c.transport {
         Transport::Tcp => {
-            let listener = tnet::TcpListener::bind(c.addr)
-                .await
+            let activated = nat_tunnel::systemd::listen_socket(socket2::Type::STREAM)
                 .with_context(|_| IoSnafu {
-                    message: format!("failed to bind {}", c.addr),
+                    message: "failed to use the socket passed in by systemd",
                 })?;
+            let listener = match activated {
+                Some(socket) => {
+                    info!("using the listening socket passed in by systemd");
+                    tnet::TcpListener::from_std(socket.into()).with_context(|_| IoSnafu {
+                        message: "failed to use the socket passed in by systemd",
+                    })?
+                }
+                None => tnet::TcpListener::bind(c.addr)
+                    .await
+                    .with_context(|_| IoSnafu {
+                        message: format!("failed to bind {}", c.addr),
+                    })?,
+            };
             let mut transport = server::TcpServer::new(c, token.clone(), listener).unwrap();
+            spawn_admin(admin_addr, transport.registry(), token.clone());
+            spawn_drain(transport.registry(), drain_timeout, token.clone());
+            spawn_reload(transport.terminator(), transport.auth());
+            spawn_vhost(
+                http_addr,
+                VhostKind::Http,
+                transport.vhosts(),
+                token.clone(),
+            );
+            spawn_vhost(sni_addr, VhostKind::Sni, transport.vhosts(), token.clone());
 
             transport.run().await.map_err(Report::from)
         }
         Transport::Quic => {
-            let mut transport = server::QuicServer::new(c, token.clone()).unwrap();
+            let socket =
+                nat_tunnel::systemd::listen_socket(socket2::Type::DGRAM).with_context(|_| {
+                    IoSnafu {
+                        message: "failed to use the socket passed in by systemd",
+                    }
+                })?;
+            if socket.is_some() {
+                info!("using the listening socket passed in by systemd");
+            }
+            let mut transport =
+                server::QuicServer::new(c, token.clone(), socket.map(Into::into)).unwrap();
+            spawn_admin(admin_addr, transport.registry(), token.clone());
+            spawn_drain(transport.registry(), drain_timeout, token.clone());
+            spawn_reload(transport.terminator(), transport.auth());
+            spawn_vhost(
+                http_addr,
+                VhostKind::Http,
+                transport.vhosts(),
+                token.clone(),
+            );
+            spawn_vhost(sni_addr, VhostKind::Sni, transport.vhosts(), token.clone());
             transport.run().await.map_err(Report::from)
         }
     }
 }
+
+// Reload the TLS termination certificates and htpasswd files from disk on
+// SIGHUP
+fn spawn_reload(
+    terminator: Option<Arc<server::terminate::Terminator>>,
+    auth: Option<Arc<server::auth::Authenticator>>,
+) {
+    if terminator.is_none() && auth.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sighup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+        while sighup.recv().await.is_some() {
+            if let Some(ref terminator) = terminator {
+                info!("Received SIGHUP, reloading TLS termination certificates");
+                if let Err(e) = terminator.reload() {
+                    error!(cause = ?e, "failed to reload certificates. Keeping the current ones");
+                }
+            }
+            if let Some(ref auth) = auth {
+                info!("Received SIGHUP, reloading http auth");
+                if let Err(e) = auth.reload() {
+                    error!(cause = ?e, "failed to reload http auth. Keeping the current one");
+                }
+            }
+        }
+    });
+}
+
+// Drain on SIGTERM, or when asked to on the admin API, and shut down once
+// every client is gone or drain_timeout passes
+fn spawn_drain(
+    registry: Arc<server::Registry>,
+    drain_timeout: std::time::Duration,
+    token: CancellationToken,
+) {
+    let drain = registry.drain_token();
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM. Draining connections...");
+                registry.drain();
+            }
+            _ = drain.cancelled() => info!("Draining connections..."),
+            _ = token.cancelled() => return,
+        }
+        tokio::select! {
+            _ = registry.idle() => info!("All clients drained. Shutting down..."),
+            _ = tokio::time::sleep(drain_timeout) => {
+                warn!("drain_timeout passed. Terminating all connections and shutting down...");
+            }
+            _ = token.cancelled() => (),
+        }
+        token.cancel();
+    });
+}
+
+fn spawn_admin(
+    addr: Option<std::net::SocketAddr>,
+    registry: Arc<server::Registry>,
+    token: CancellationToken,
+) {
+    let Some(addr) = addr else {
+        return;
+    };
+    tokio::spawn(async move {
+        if let Err(e) = server::admin::serve(addr, registry, token).await {
+            error!(cause = ?e, "admin API failed");
+        }
+    });
+}
+
+fn spawn_vhost(
+    addr: Option<std::net::SocketAddr>,
+    kind: VhostKind,
+    vhosts: Arc<std::sync::Mutex<server::vhost::VhostChannels>>,
+    token: CancellationToken,
+) {
+    let Some(addr) = addr else {
+        return;
+    };
+    tokio::spawn(async move {
+        if let Err(e) = server::vhost::serve(addr, kind, vhosts, token).await {
+            error!(cause = ?e, kind = ?kind, "vhost listener failed");
+        }
+    });
+}
@@ -1,27 +1,79 @@
-use crate::{config::client as config, net as stnet, net::Frame, redirector::Redirector};
+use crate::{
+    backends, config::client as config, forward, http_tunnel, net as stnet, net::Frame,
+    redirector::Redirector,
+};
 use rustls_pki_types::ServerName;
-use snafu::ResultExt;
-use std::collections::HashMap;
+use snafu::{IntoError, ResultExt};
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use stnet::Result;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio::task::{JoinError, JoinSet};
 use tokio_rustls::TlsConnector;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn, Instrument};
+
+// Buffered between the Redirector and the proxy of HTTP tunnels
+const HTTP_BUFFER: usize = 64 * 1024;
 
 pub struct Client<T> {
     peer_addr: stnet::StreamId,
     config: config::Config,
     token: CancellationToken,
+    // Cancelled to stop taking new connections and to end the session once
+    // the live ones are done
+    drain: CancellationToken,
     transport: stnet::Transport<T>,
+    status: Arc<crate::control::Status>,
+    reload: watch::Receiver<config::Config>,
+    // remote_ports and hostnames currently held for us by the server
+    acked: BTreeSet<u16>,
+    acked_hosts: BTreeSet<stnet::Vhost>,
+    // What this client adds to the active_tunnels gauge. With several
+    // servers, each one's client adds its own
+    counted_tunnels: i64,
+    // Ports the server picked for ephemeral tunnels
+    assigned: HashMap<u16, config::Tunnel>,
+    // Ephemeral tunnels waiting on the server to pick a port, in request
+    // order
+    pending: VecDeque<config::Tunnel>,
+    // The Internals of every tunnel, and their health
+    backends: backends::Backends,
+    // The health last reported to the server for each tunnel. The server
+    // takes tunnels to be up until told otherwise
+    reported: HashMap<u16, bool>,
+    reported_hosts: HashMap<stnet::Vhost, bool>,
+    // When the Ping that is still waiting on its Pong was sent
+    ping_sent: Option<std::time::Instant>,
+    // The listeners of the local forwards and visitors, and what they
+    // accept
+    forwards: forward::Forwards<config::Forward>,
+    accepted: mpsc::Receiver<forward::Accepted<config::Forward>>,
+    visitors: forward::Forwards<config::Visitor>,
+    visiting: mpsc::Receiver<forward::Accepted<config::Visitor>>,
 
     to_server: mpsc::Sender<stnet::RedirectorFrame>,
     from_internal: mpsc::Receiver<stnet::RedirectorFrame>,
     to_internal: HashMap<SocketAddr, mpsc::Sender<stnet::RedirectorFrame>>,
 
     handlers: JoinSet<SocketAddr>,
+
+    // The token to resume the session with, if the server holds on to it
+    // when the connection drops
+    session: Option<stnet::SessionToken>,
+    replay: stnet::Replay,
+}
+
+// A client given up on takes its tunnels out of the gauge with it
+impl<T> Drop for Client<T> {
+    fn drop(&mut self) {
+        crate::metrics::metrics()
+            .active_tunnels
+            .sub(self.counted_tunnels);
+    }
 }
 
 impl<T> Client<T>
@@ -31,37 +83,262 @@ where
     pub fn new(
         config: config::Config,
         token: CancellationToken,
+        drain: CancellationToken,
         peer_addr: stnet::StreamId,
         stream: T,
+        status: Arc<crate::control::Status>,
+        reload: watch::Receiver<config::Config>,
     ) -> Client<T> {
         let (tx, rx) = mpsc::channel(config.channel_limits.core);
-        Client {
+        let mut backends = backends::Backends::new(token.clone());
+        backends.sync(config.tunnels.iter());
+        // Forwards and visitors only start listening once we are connected
+        let (accepted_tx, accepted) = mpsc::channel(config.channel_limits.core);
+        let forwards = forward::Forwards::new(token.clone(), accepted_tx);
+        let (visiting_tx, visiting) = mpsc::channel(config.channel_limits.core);
+        let visitors = forward::Forwards::new(token.clone(), visiting_tx);
+        let client = Client {
+            backends,
+            status,
+            reload,
+            acked: BTreeSet::new(),
+            acked_hosts: BTreeSet::new(),
+            counted_tunnels: 0,
+            assigned: HashMap::new(),
+            pending: VecDeque::new(),
+            reported: HashMap::new(),
+            reported_hosts: HashMap::new(),
+            ping_sent: None,
+            forwards,
+            accepted,
+            visitors,
+            visiting,
             transport: stnet::Transport::new(config.timeouts.clone(), stream),
             peer_addr,
             config,
             token,
+            drain,
             handlers: JoinSet::new(),
             to_server: tx,
             from_internal: rx,
             to_internal: HashMap::new(),
-        }
+            session: None,
+            replay: stnet::Replay::default(),
+        };
+        client.status.set_tunnels(client.tunnel_list());
+        client
     }
 
-    async fn push_tunnel_config(&mut self) -> Result<()> {
+    /// Carry on over a new connection to the server. The session is resumed
+    /// on the next run, if it can be
+    pub fn reconnect(&mut self, peer_addr: stnet::StreamId, stream: T) {
+        self.transport = stnet::Transport::new(self.config.timeouts.clone(), stream);
+        self.peer_addr = peer_addr;
+    }
+
+    /// Whether the session can be resumed over a new connection, in which
+    /// case the connections to the Internals are still open
+    pub fn resumable(&self) -> bool {
+        self.session.is_some() && self.replay.resumable() && !self.token.is_cancelled()
+    }
+
+    async fn read_auth(&mut self) -> Result<()> {
         let frame = self.transport.read_frame().await?;
         let stnet::Frame::Auth(_) = frame else {
             return Err(stnet::Error::ConnectionRefused);
         };
+        Ok(())
+    }
+
+    /// Pick the session up where it was left off, sending the server what it
+    /// missed. A new session is started if the server no longer has it
+    async fn resume(&mut self, token: stnet::SessionToken) -> Result<()> {
+        self.read_auth().await?;
+        let received = self.replay.received();
+        self.transport
+            .write_frame(Frame::Resume(token, received))
+            .await?;
+        let frame = self.transport.read_frame().await?;
+        let Frame::Resumed(received) = frame else {
+            return Err(stnet::Error::ConnectionRefused);
+        };
+        let Some(received) = received else {
+            warn!("server no longer has our session. Starting a new one");
+            self.forget_session().await;
+            return self.push_tunnels().await;
+        };
+        let Some(missed) = self.replay.since(received) else {
+            error!(
+                received = received,
+                "what the server missed is gone. Can't resume the session"
+            );
+            // Ends the session on the server too
+            let reason = "session can't be resumed".to_string();
+            self.transport.write_frame(Frame::Kthxbai(reason)).await?;
+            self.forget_session().await;
+            return Err(stnet::Error::ConnectionDead);
+        };
+        let replayed = missed.len();
+        for frame in missed {
+            self.transport.write_frame(frame.into()).await?;
+        }
+        info!(replayed = replayed, "resumed the session");
+        self.session = Some(token);
+        self.status
+            .set_connected(self.acked.iter().copied().collect());
+        self.update_acked();
+        Ok(())
+    }
+
+    /// Drop the connections of a session the server no longer has
+    async fn forget_session(&mut self) {
+        self.session = None;
+        self.replay = stnet::Replay::default();
+        self.handlers.shutdown().await;
+        self.to_internal.clear();
+        // Whatever the Redirectors had queued for the server is moot now
+        while self.from_internal.try_recv().is_ok() {}
+        self.acked.clear();
+        self.acked_hosts.clear();
+        self.assigned.clear();
+        self.pending.clear();
+        self.reported.clear();
+        self.reported_hosts.clear();
+        self.ping_sent = None;
+    }
 
-        let tunnels = self.config.tunnels.keys().copied().collect();
-        self.transport.write_frame(Frame::Tunnels(tunnels)).await?;
+    async fn push_tunnel_config(&mut self) -> Result<()> {
+        self.read_auth().await?;
+        self.push_tunnels().await
+    }
+
+    #[tracing::instrument(name = "register_tunnels", level = "info", skip_all)]
+    async fn push_tunnels(&mut self) -> Result<()> {
+        // Pooled ports are joined once we are in
+        let ports: Vec<_> = self.config.tunnels.fixed.keys().copied().collect();
+        let (tunnels, pooled) = self.config.tunnels.split_pooled(&ports);
+        self.transport
+            .write_frame(Frame::Tunnels(tunnels.clone()))
+            .await?;
 
         let frame = self.transport.read_frame().await?;
-        let stnet::Frame::Tunnels(_) = frame else {
+        let stnet::Frame::Tunnels(acked) = frame else {
             return Err(stnet::Error::ConnectionRefused);
         };
         trace!("Pushed tunnel config to remote");
-        Ok(())
+        let rejected: Vec<_> = tunnels
+            .iter()
+            .filter(|p| !acked.contains(p))
+            .copied()
+            .collect();
+        if !rejected.is_empty() {
+            error!(rejected = ?rejected, "server rejected tunnels");
+        }
+        self.acked = acked.iter().copied().collect();
+        self.status.set_connected(acked);
+
+        // Ask the server to pick the ports of ephemeral tunnels, and to
+        // route our hostnames to us
+        let ephemeral = self.config.tunnels.ephemeral.clone();
+        let pooled = pooled.into_iter().map(|(port, _)| port).collect();
+        self.request_ports(pooled, ephemeral).await?;
+        let hosts = self.config.tunnels.hosts.keys().cloned().collect();
+        self.request_hosts(hosts).await
+    }
+
+    /// Ask the server to route `hosts` to us. Secret tunnels are sent along
+    /// with their secret
+    async fn request_hosts(&mut self, hosts: Vec<stnet::Vhost>) -> Result<()> {
+        let (secrets, hosts): (Vec<_>, Vec<_>) = hosts
+            .into_iter()
+            .partition(|h| h.kind == stnet::VhostKind::Secret);
+        if !hosts.is_empty() {
+            self.transport.write_frame(Frame::AddHosts(hosts)).await?;
+        }
+        let secrets: Vec<_> = secrets
+            .into_iter()
+            .filter_map(|h| {
+                let secret = self.config.tunnels.hosts.get(&h)?.secret.clone()?;
+                Some((h.hostname, secret.into()))
+            })
+            .collect();
+        if secrets.is_empty() {
+            return Ok(());
+        }
+        self.transport.write_frame(Frame::AddSecrets(secrets)).await
+    }
+
+    /// Ask the server for the fixed `ports`, joining the pool of those that
+    /// have one, and a port of its choosing for each tunnel in `ephemeral`
+    async fn request_ports(
+        &mut self,
+        ports: Vec<u16>,
+        ephemeral: Vec<config::Tunnel>,
+    ) -> Result<()> {
+        let (mut ports, pooled) = self.config.tunnels.split_pooled(&ports);
+        if !pooled.is_empty() {
+            self.transport.write_frame(Frame::JoinPools(pooled)).await?;
+        }
+        ports.extend(ephemeral.iter().map(|_| 0));
+        if ports.is_empty() {
+            return Ok(());
+        }
+        self.pending.extend(ephemeral);
+        self.transport.write_frame(Frame::AddTunnels(ports)).await
+    }
+
+    /// Every configured tunnel along with the remote_port it is served on.
+    /// Ephemeral tunnels without an assigned port get 0
+    fn tunnel_list(&self) -> Vec<(u16, config::Tunnel)> {
+        let mut assigned: Vec<_> = self.assigned.iter().collect();
+        let mut tunnels: Vec<_> = self
+            .config
+            .tunnels
+            .fixed
+            .values()
+            .map(|t| (t.remote_port, t.clone()))
+            .collect();
+        for t in self.config.tunnels.ephemeral.iter() {
+            let port = match assigned.iter().position(|(_, a)| *a == t) {
+                None => 0,
+                Some(i) => *assigned.swap_remove(i).0,
+            };
+            tunnels.push((port, t.clone()));
+        }
+        tunnels.extend(self.config.tunnels.hosts.values().map(|t| (0, t.clone())));
+        tunnels
+    }
+
+    /// Whether the config still has an ephemeral tunnel like `t` that
+    /// hasn't been assigned a port
+    fn wants_ephemeral(&self, t: &config::Tunnel) -> bool {
+        let wanted = self
+            .config
+            .tunnels
+            .ephemeral
+            .iter()
+            .filter(|e| *e == t)
+            .count();
+        let held = self.assigned.values().filter(|a| *a == t).count();
+        wanted > held
+    }
+
+    // Make this client's share of the active_tunnels gauge `count`
+    fn count_tunnels(&mut self, count: usize) {
+        let count = count as i64;
+        crate::metrics::metrics()
+            .active_tunnels
+            .add(count - self.counted_tunnels);
+        self.counted_tunnels = count;
+    }
+
+    fn update_acked(&mut self) {
+        self.count_tunnels(self.acked.len());
+        self.status
+            .set_acknowledged(self.acked.iter().copied().collect());
+        self.status
+            .set_acknowledged_hosts(self.acked_hosts.iter().cloned().collect());
+        self.status.set_tunnels(self.tunnel_list());
     }
 
     pub fn redirector_join(
@@ -94,19 +371,119 @@ where
         match frame {
             Frame::Heartbeat => {
                 trace!("heartbeat received from server");
+                crate::metrics::metrics()
+                    .channel_occupancy
+                    .with_label_values(&["from_internal", &self.peer_addr.to_string()])
+                    .set(self.from_internal.len() as i64);
                 if let Err(e) = self.transport.write_frame(Frame::Heartbeat).await {
                     error!(e=?e, "failed to send heartbeat to server");
                     return Err(e);
                 }
+                if let Some(count) = self.replay.ack() {
+                    self.transport.write_frame(Frame::Ack(count)).await?;
+                }
+                // Measure our side of the round trip as well, at the pace
+                // the server sets
+                if self.ping_sent.is_none() {
+                    self.transport.write_frame(Frame::Ping).await?;
+                    self.ping_sent = Some(std::time::Instant::now());
+                }
+            }
+            Frame::Pong => {
+                if let Some(sent) = self.ping_sent.take() {
+                    trace!(rtt = ?sent.elapsed(), "pong received from server");
+                    self.status.set_rtt(sent.elapsed());
+                }
             }
-            Frame::Kthxbai => {
-                info!("Server is shutting down");
+            Frame::Kthxbai(reason) => {
+                info!(reason = reason, "server ended the session");
+                // and has let go of our session
+                self.session = None;
             }
+            Frame::Session(token) => {
+                info!("server holds on to the session if the connection drops");
+                self.session = Some(token);
+                self.replay.keep();
+            }
+            Frame::Ack(count) => self.replay.acknowledged(count),
             Frame::Redirector(r) => {
+                if let Some(count) = self.replay.record_received() {
+                    self.transport.write_frame(Frame::Ack(count)).await?;
+                }
                 if let Err(e) = self.redirector_frame(r).await {
                     error!(cause = ?e, "redirector failed");
                 }
             }
+            Frame::TunnelResults(results) => {
+                let mut unwanted = Vec::new();
+                for r in results {
+                    match r {
+                        stnet::TunnelResult::Added(port) => {
+                            info!(port = port, "server added tunnel");
+                            self.acked.insert(port);
+                            self.reported.remove(&port);
+                        }
+                        stnet::TunnelResult::Assigned(port) => {
+                            let Some(t) = self.pending.pop_front() else {
+                                error!(port = port, "server assigned a port we didn't ask for");
+                                unwanted.push(port);
+                                continue;
+                            };
+                            if !self.wants_ephemeral(&t) {
+                                // Removed by a reload while we were waiting
+                                unwanted.push(port);
+                                continue;
+                            }
+                            info!(
+                                port = port,
+                                backends = ?t.backends(),
+                                "server assigned port"
+                            );
+                            self.acked.insert(port);
+                            self.reported.remove(&port);
+                            self.assigned.insert(port, t);
+                        }
+                        stnet::TunnelResult::Removed(port) => {
+                            info!(port = port, "server removed tunnel");
+                            self.acked.remove(&port);
+                        }
+                        stnet::TunnelResult::Rejected(0, reason) => {
+                            let t = self.pending.pop_front();
+                            error!(tunnel = ?t, reason = reason, "server rejected ephemeral tunnel");
+                        }
+                        stnet::TunnelResult::Rejected(port, reason) => {
+                            error!(port = port, reason = reason, "server rejected tunnel");
+                            self.acked.remove(&port);
+                        }
+                        stnet::TunnelResult::HostAdded(host) => {
+                            info!(host = %host, "server added host");
+                            self.reported_hosts.remove(&host);
+                            self.acked_hosts.insert(host);
+                        }
+                        stnet::TunnelResult::HostRemoved(host) => {
+                            info!(host = %host, "server removed host");
+                            self.acked_hosts.remove(&host);
+                        }
+                        stnet::TunnelResult::HostRejected(host, reason) => {
+                            error!(host = %host, reason = reason, "server rejected host");
+                            self.acked_hosts.remove(&host);
+                        }
+                    }
+                }
+                self.update_acked();
+                if !unwanted.is_empty() {
+                    self.transport
+                        .write_frame(Frame::RemoveTunnels(unwanted))
+                        .await?;
+                }
+                self.report_health().await?;
+            }
+            Frame::TunnelClosed(port, reason) => {
+                error!(port = port, reason = reason, "server closed tunnel");
+                self.acked.remove(&port);
+                self.assigned.remove(&port);
+                self.update_acked();
+            }
             f => {
                 trace!(frame = ?f, addr = ?self.peer_addr, "received unexpected frame");
             }
@@ -114,14 +491,192 @@ where
         Ok(())
     }
 
+    /// Apply the `tunnels` of a reloaded config, registering added tunnels
+    /// with the server and unregistering removed ones. Connections on
+    /// unchanged tunnels are left alone.
+    async fn reload_tunnels(&mut self, config: config::Config) -> Result<()> {
+        if config.forwards != self.config.forwards {
+            info!(forwards = ?config.forwards, "reloading forwards");
+            self.config.forwards = config.forwards;
+            self.forwards.sync(self.config.forwards.iter());
+        }
+        if config.visitors != self.config.visitors {
+            let names: Vec<_> = config.visitors.iter().map(|v| &v.secret_name).collect();
+            info!(visitors = ?names, "reloading visitors");
+            self.config.visitors = config.visitors;
+            self.visitors.sync(self.config.visitors.iter());
+        }
+        let diff = config::TunnelDiff::new(&self.config.tunnels, &config.tunnels);
+        if diff.is_empty() {
+            info!("tunnel config unchanged");
+            return Ok(());
+        }
+        info!(
+            added = ?diff.added,
+            removed = ?diff.removed,
+            changed = ?diff.changed,
+            added_ephemeral = diff.added_ephemeral.len(),
+            removed_ephemeral = diff.removed_ephemeral.len(),
+            added_hosts = ?diff.added_hosts,
+            removed_hosts = ?diff.removed_hosts,
+            changed_hosts = ?diff.changed_hosts,
+            "reloading tunnels"
+        );
+        self.config.tunnels = config.tunnels;
+        self.backends.sync(self.config.tunnels.iter());
+
+        // Ephemeral tunnels that are still pending are released once the
+        // server assigns their port
+        let mut removed = diff.removed;
+        for t in diff.removed_ephemeral.iter() {
+            let port = self
+                .assigned
+                .iter()
+                .find(|(_, a)| *a == t)
+                .map(|(port, _)| *port);
+            if let Some(port) = port {
+                self.assigned.remove(&port);
+                removed.push(port);
+            }
+        }
+        self.status.set_tunnels(self.tunnel_list());
+
+        if !removed.is_empty() {
+            self.transport
+                .write_frame(Frame::RemoveTunnels(removed))
+                .await?;
+        }
+        if !diff.removed_hosts.is_empty() {
+            self.transport
+                .write_frame(Frame::RemoveHosts(diff.removed_hosts))
+                .await?;
+        }
+        self.request_ports(diff.added, diff.added_ephemeral).await?;
+        self.request_hosts(diff.added_hosts).await?;
+        // Health checks may have been added to or removed from tunnels
+        self.report_health().await
+    }
+
+    /// Tell the server about the tunnels whose Internals went up or down
+    /// since the last report. Tunnels without health checks count as up
+    async fn report_health(&mut self) -> Result<()> {
+        let mut ports = Vec::new();
+        for port in self.acked.iter() {
+            let Some(t) = self
+                .config
+                .tunnels
+                .fixed
+                .get(port)
+                .or_else(|| self.assigned.get(port))
+            else {
+                continue;
+            };
+            let healthy = self.backends.healthy(t).unwrap_or(true);
+            if self.reported.get(port).copied().unwrap_or(true) != healthy {
+                ports.push((*port, healthy));
+            }
+        }
+        let mut hosts = Vec::new();
+        for host in self.acked_hosts.iter() {
+            let Some(t) = self.config.tunnels.hosts.get(host) else {
+                continue;
+            };
+            let healthy = self.backends.healthy(t).unwrap_or(true);
+            if self.reported_hosts.get(host).copied().unwrap_or(true) != healthy {
+                hosts.push((host.clone(), healthy));
+            }
+        }
+
+        let (acked, acked_hosts) = (&self.acked, &self.acked_hosts);
+        self.reported.retain(|port, _| acked.contains(port));
+        self.reported_hosts
+            .retain(|host, _| acked_hosts.contains(host));
+        self.reported.extend(ports.iter().cloned());
+        self.reported_hosts.extend(hosts.iter().cloned());
+        if !ports.is_empty() {
+            info!(tunnels = ?ports, "reporting Internal health");
+            self.transport
+                .write_frame(Frame::TunnelHealth(ports))
+                .await?;
+        }
+        if !hosts.is_empty() {
+            info!(hosts = ?hosts, "reporting Internal health");
+            self.transport.write_frame(Frame::HostHealth(hosts)).await?;
+        }
+        Ok(())
+    }
+
+    // Authenticate, then register the tunnels or pick up where the last
+    // session left off
+    #[tracing::instrument(name = "handshake", level = "info", skip_all)]
+    async fn handshake(&mut self) -> Result<()> {
+        self.transport.send_helo(self.config.psk.as_bytes()).await?;
+        match self.session {
+            None => self.push_tunnel_config().await,
+            Some(token) => self.resume(token).await,
+        }
+    }
+
     #[tracing::instrument(name = "Client", level = "debug", skip_all)]
     pub async fn run(&mut self) -> stnet::Result<()> {
-        self.transport.send_helo(self.config.psk.as_bytes()).await?;
-        self.push_tunnel_config().await?;
+        let metrics = crate::metrics::metrics();
+        if let Err(e) = self.handshake().await {
+            let reason = match e {
+                stnet::Error::ConnectionRefused => "refused",
+                _ => "transport",
+            };
+            metrics
+                .handshake_failures
+                .with_label_values(&[reason])
+                .inc();
+            return Err(e);
+        }
+        self.count_tunnels(self.acked.len());
+        self.report_health().await?;
+        self.forwards.sync(self.config.forwards.iter());
+        self.visitors.sync(self.config.visitors.iter());
+        let health_changed = self.backends.changed();
+        let mut reload_closed = false;
+        let mut draining = false;
         let ret = loop {
             tokio::select! {
-                // A tunnel has completed it's redirection
-                maybe_join = self.handlers.join_next() => self.redirector_join(maybe_join),
+                // The config was reloaded. A draining client keeps what it
+                // has
+                maybe_reload = self.reload.changed(), if !reload_closed && !draining => {
+                    if maybe_reload.is_err() {
+                        reload_closed = true;
+                        continue;
+                    }
+                    let config = self.reload.borrow_and_update().clone();
+                    if let Err(e) = self.reload_tunnels(config).await {
+                        break Err(e)
+                    }
+                }
+
+                // A connection was accepted on a local forward
+                Some((stream, id, forward)) = self.accepted.recv() => {
+                    if let Err(e) = self.start_forward(stream, id, &forward).await {
+                        break Err(e)
+                    }
+                }
+
+                // Or on a visitor
+                Some((stream, id, visitor)) = self.visiting.recv() => {
+                    if let Err(e) = self.start_visit(stream, id, &visitor).await {
+                        break Err(e)
+                    }
+                }
+
+                // An Internal went up or down
+                _ = health_changed.notified() => {
+                    if let Err(e) = self.report_health().await {
+                        break Err(e)
+                    }
+                }
+
+                // A tunnel has completed it's redirection. join_next is ready
+                // right away while there are none, which would spin this loop
+                maybe_join = self.handlers.join_next(), if !self.handlers.is_empty() => self.redirector_join(maybe_join),
 
                 // Client receives a frame from Server
                 maybe_frame = self.transport.read_frame() => {
@@ -137,7 +692,20 @@ where
                         Some(d) => d,
                     };
 
-                    if let Err(e) = self.transport.write_frame(data.into()).await {
+                    if let Err(e) = self.write_redirector(data).await {
+                        break Err(e)
+                    }
+                }
+
+                // Stop accepting on the local forwards and visitors, and
+                // have the server stop sending new Externals. It ends the
+                // session once the live connections are done
+                _ = self.drain.cancelled(), if !draining => {
+                    info!(connections = self.handlers.len(), "draining");
+                    draining = true;
+                    self.forwards.sync(std::iter::empty());
+                    self.visitors.sync(std::iter::empty());
+                    if let Err(e) = self.transport.write_frame(Frame::Drain).await {
                         break Err(e)
                     }
                 }
@@ -147,68 +715,248 @@ where
                 }
             }
         };
+        // The server holds on to the session and its Externals, so hold on
+        // to the Internals as well until we are back
+        if ret.is_err() && self.resumable() {
+            info!("connection to the server lost. The session can be resumed");
+            return ret;
+        }
         self.handlers.abort_all();
-        if let Err(e) = self.transport.write_frame(Frame::Kthxbai).await {
+        let reason = "client is shutting down".to_string();
+        if let Err(e) = self.transport.write_frame(Frame::Kthxbai(reason)).await {
             error!(e=?e, "failed to inform server of shutdown");
         }
         while self.handlers.join_next().await.is_some() {}
+        self.count_tunnels(0);
+        let _ = metrics
+            .channel_occupancy
+            .remove_label_values(&["from_internal", &self.peer_addr.to_string()]);
         ret
     }
 
+    /// Send a Redirector frame to the server, holding on to it until the
+    /// server acknowledges it
+    async fn write_redirector(&mut self, frame: stnet::RedirectorFrame) -> Result<()> {
+        self.replay.record_sent(&frame);
+        self.transport.write_frame(frame.into()).await
+    }
+
     async fn new_redirector<U: stnet::Stream + 'static>(
         &mut self,
         id: SocketAddr,
         port: u16,
+        conn_id: stnet::ConnId,
         internal_stream: U,
+        tunnel_cfg: &config::Tunnel,
+        lease: backends::Lease,
     ) -> Result<()> {
         let to_server = self.to_server.clone();
         let token = self.token.clone();
         let mtu = self.config.mtu;
         let (to_internal, from_internal) = mpsc::channel(self.config.channel_limits.core);
         self.to_internal.insert(id, to_internal);
-        self.handlers.spawn(async move {
-            let mut r = Redirector::with_stream(
-                id,
-                port,
-                mtu,
-                token,
-                internal_stream,
-                to_server,
-                from_internal,
+        if !tunnel_cfg.http {
+            self.handlers.spawn(
+                async move {
+                    let mut r = Redirector::with_stream(
+                        id,
+                        port,
+                        mtu,
+                        token,
+                        internal_stream,
+                        to_server,
+                        from_internal,
+                    )
+                    .with_conn_id(conn_id);
+                    r.run().await;
+                    drop(lease);
+                    id
+                }
+                .in_current_span(),
             );
-            r.run().await;
-            id
-        });
+            return Ok(());
+        }
+
+        // The Redirector talks to the HTTP proxy, which talks to the Internal
+        let peer = http_tunnel::Peer {
+            external_addr: id,
+            remote_port: port,
+            proto: tunnel_cfg.forwarded_proto.clone(),
+        };
+        let (ours, theirs) = tokio::io::duplex(HTTP_BUFFER);
+        self.handlers.spawn(
+            async move {
+                let mut r =
+                    Redirector::with_stream(id, port, mtu, token, ours, to_server, from_internal)
+                        .with_conn_id(conn_id);
+                // Dropping the Redirector when it is done lets the proxy see EOF
+                let redirect = async move { r.run().await };
+                let proxy =
+                    http_tunnel::proxy(theirs, internal_stream, http_tunnel::Mode::Forward(peer));
+                let (_, ret) = tokio::join!(redirect, proxy);
+                if let Err(e) = ret {
+                    info!(cause = ?e, external_addr = ?id, "http connection ended");
+                }
+                drop(lease);
+                id
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+
+    /// Have the server open a connection to the target of `forward` for
+    /// `stream`, accepted on the forward's local_addr from `id`
+    async fn start_forward(
+        &mut self,
+        stream: TcpStream,
+        id: SocketAddr,
+        forward: &config::Forward,
+    ) -> Result<()> {
+        info!(target = forward.target, for_ = ?id, "forwarding connection");
+        let start = stnet::RedirectorFrame::StartForward(id, forward.target.clone());
+        self.start_local(stream, id, forward.local_addr.port(), start)
+            .await
+    }
+
+    /// Have the server splice `stream`, accepted on the local_addr of
+    /// `visitor` from `id`, to the secret tunnel the visitor is for
+    async fn start_visit(
+        &mut self,
+        stream: TcpStream,
+        id: SocketAddr,
+        visitor: &config::Visitor,
+    ) -> Result<()> {
+        info!(secret_name = visitor.secret_name, for_ = ?id, "visiting secret tunnel");
+        let secret = visitor.secret.clone().into();
+        let start = stnet::RedirectorFrame::StartVisit(id, visitor.secret_name.clone(), secret);
+        self.start_local(stream, id, visitor.local_addr.port(), start)
+            .await
+    }
+
+    // Send `start` to the server and shuffle data between it and `stream`,
+    // accepted on a local `port`
+    async fn start_local(
+        &mut self,
+        stream: TcpStream,
+        id: SocketAddr,
+        port: u16,
+        start: stnet::RedirectorFrame,
+    ) -> Result<()> {
+        if self.to_internal.contains_key(&id) {
+            error!(id = ?id, "connection already redirected");
+            return Ok(());
+        }
+        self.write_redirector(start).await?;
+
+        let to_server = self.to_server.clone();
+        let token = self.token.clone();
+        let mtu = self.config.mtu;
+        let (to_internal, from_internal) = mpsc::channel(self.config.channel_limits.core);
+        self.to_internal.insert(id, to_internal);
+        self.handlers.spawn(
+            async move {
+                let mut r =
+                    Redirector::with_stream(id, port, mtu, token, stream, to_server, from_internal);
+                r.run().await;
+                id
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+
+    /// Open a connection to the Internal of `tunnel_cfg` for the External
+    /// `id`, unless one is already open. The server is told to drop the
+    /// External if that fails
+    async fn start_conn(
+        &mut self,
+        id: SocketAddr,
+        server_addr: SocketAddr,
+        port: u16,
+        conn_id: stnet::ConnId,
+        trace: stnet::TraceContext,
+        tunnel_cfg: Option<config::Tunnel>,
+    ) -> Result<()> {
+        if self.to_internal.contains_key(&id) {
+            return Ok(());
+        }
+        // Part of the trace the server started for the connection
+        let span = tracing::info_span!("new_conn", conn_id = %conn_id);
+        trace.set_parent_of(&span);
+        let ret = match tunnel_cfg {
+            // The tunnel was removed by a reload, but the server hadn't
+            // caught up yet
+            None => Err(stnet::Error::ConnectionRefused),
+            Some(ref t) => {
+                self.new_conn(id, server_addr, port, conn_id, t)
+                    .instrument(span)
+                    .await
+            }
+        };
+        if let Err(e) = ret {
+            // make sure the Server kills off the connection on its side
+            let d = stnet::RedirectorFrame::KillListener(id);
+            self.write_redirector(d).await?;
+            return Err(e);
+        }
         Ok(())
     }
 
-    async fn new_conn(&mut self, id: SocketAddr, port: u16) -> Result<()> {
-        let tunnel_cfg = match self.config.tunnels.get(&port) {
-            None => unreachable!(),
-            Some(p) => p,
+    async fn new_conn(
+        &mut self,
+        id: SocketAddr,
+        server_addr: SocketAddr,
+        port: u16,
+        conn_id: stnet::ConnId,
+        tunnel_cfg: &config::Tunnel,
+    ) -> Result<()> {
+        // Each healthy backend is tried in turn before giving up
+        let mut connected = None;
+        for (backend, lease) in self.backends.pick(tunnel_cfg) {
+            match TcpStream::connect((backend.local_hostname.clone(), backend.local_port)).await {
+                Ok(s) => {
+                    connected = Some((s, backend, lease));
+                    break;
+                }
+                Err(e) => {
+                    warn!(cause = ?e, backend = %backend, for_ = ?id, "failed to connect to backend")
+                }
+            }
+        }
+        let Some((mut internal_stream, backend, lease)) = connected else {
+            return Err(stnet::IoSnafu {
+                message: "no backend of the tunnel could be connected to",
+            }
+            .into_error(std::io::ErrorKind::ConnectionRefused.into()));
         };
-        let internal_stream =
-            TcpStream::connect((tunnel_cfg.local_hostname.clone(), tunnel_cfg.local_port))
+        let internal_addr = internal_stream.peer_addr().unwrap();
+        // The header goes ahead of everything, TLS included
+        if let Some(version) = tunnel_cfg.proxy_protocol {
+            internal_stream
+                .write_all(&version.header(id, server_addr))
                 .await
                 .with_context(|_| crate::net::IoSnafu {
-                    message: "tunnel connection failed",
+                    message: "failed to write PROXY protocol header",
                 })?;
-        let internal_addr = internal_stream.peer_addr().unwrap();
+        }
         if let Some(ref crypto_cfg) = tunnel_cfg.crypto {
             info!(internal_addr = ?internal_addr, for_ = ?id, "connecting to Internal (TLS)");
             let cc = crate::tls_self_signed::crypto_client_init(crypto_cfg)?;
             let connector = TlsConnector::from(cc);
-            let dnsname = ServerName::try_from(tunnel_cfg.local_hostname.clone())?;
+            let dnsname = ServerName::try_from(backend.local_hostname.clone())?;
             let tls_stream = connector
                 .connect(dnsname, internal_stream)
                 .await
                 .with_context(|_| crate::net::IoSnafu {
                     message: "connect via tls failed",
                 })?;
-            self.new_redirector(id, port, tls_stream).await?;
+            self.new_redirector(id, port, conn_id, tls_stream, tunnel_cfg, lease)
+                .await?;
         } else {
             info!(internal_addr = ?internal_addr, for_ = ?id, "connecting to Internal");
-            self.new_redirector(id, port, internal_stream).await?;
+            self.new_redirector(id, port, conn_id, internal_stream, tunnel_cfg, lease)
+                .await?;
         };
 
         Ok(())
@@ -233,20 +981,37 @@ where
                     }
                 }
             }
-            stnet::RedirectorFrame::StartListener(id, port) => {
-                // Open a tunnel to the internal if needed
-                if !self.to_internal.contains_key(&id) {
-                    if let Err(e) = self.new_conn(id, port).await {
-                        // make sure the Server kills off the connection on its side
-                        let d = stnet::RedirectorFrame::KillListener(id);
-                        self.transport.write_frame(d.into()).await?;
-                        return Err(e);
-                    }
-                }
+            stnet::RedirectorFrame::StartListener(id, server_addr, port, conn_id, trace) => {
+                let tunnel_cfg = self
+                    .config
+                    .tunnels
+                    .fixed
+                    .get(&port)
+                    .or_else(|| self.assigned.get(&port))
+                    .cloned();
+                self.start_conn(id, server_addr, port, conn_id, trace, tunnel_cfg)
+                    .await?;
+            }
+            stnet::RedirectorFrame::StartVhostListener(
+                id,
+                server_addr,
+                port,
+                vhost,
+                conn_id,
+                trace,
+            ) => {
+                let tunnel_cfg = self.config.tunnels.hosts.get(&vhost).cloned();
+                self.start_conn(id, server_addr, port, conn_id, trace, tunnel_cfg)
+                    .await?;
             }
             stnet::RedirectorFrame::KillListener(ref id) => {
                 self.to_internal.remove(id);
             }
+            // Only ever sent to the server
+            f @ (stnet::RedirectorFrame::StartForward(..)
+            | stnet::RedirectorFrame::StartVisit(..)) => {
+                trace!(frame = ?f, addr = ?self.peer_addr, "received unexpected frame");
+            }
         }
 
         Ok(())
@@ -1,9 +1,11 @@
 use crate::config::Result;
+use crate::net::{Vhost, VhostKind};
 use rustls::pki_types::CertificateDer;
 use serde::{Deserialize, Serialize};
 use snafu::prelude::*;
 use std::collections::HashMap;
 use std::fs::read_to_string;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::vec::Vec;
 
@@ -27,27 +29,229 @@ impl Default for ChannelLimits {
 pub struct Config {
     #[serde(deserialize_with = "super::common::de_psk")]
     pub psk: String,
+    // The server to connect to. Required unless servers is set
+    #[serde(default)]
     pub addr: String,
+    // Several servers to connect to instead of addr, used as server_mode
+    // says
+    #[serde(default)]
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub server_mode: ServerMode,
     #[serde(default)]
     pub transport: super::common::Transport,
     #[serde(default = "default_mtu", deserialize_with = "warn_mtu")]
     pub mtu: u16,
-    #[serde(deserialize_with = "de_tunnels")]
-    pub tunnels: HashMap<u16, Tunnel>,
+    pub tunnels: Tunnels,
     pub crypto: Option<CryptoConfig>,
     #[serde(default)]
     pub channel_limits: ChannelLimits,
     #[serde(default)]
     pub timeouts: super::common::Timeout,
+    // Serve Prometheus metrics on this address, if set
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+    // Path of the unix socket used by `stc status`, if set
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+    // The health_check of tunnels that don't have their own
+    #[serde(default, deserialize_with = "de_health_check")]
+    pub health_check: Option<HealthCheck>,
+    // Local ports whose connections are carried to the server, which opens
+    // a connection to the forward's target for each
+    #[serde(default, deserialize_with = "de_forwards")]
+    pub forwards: Vec<Forward>,
+    // Local ports whose connections are carried to the secret tunnel of
+    // another client, through the server
+    #[serde(default, deserialize_with = "de_visitors")]
+    pub visitors: Vec<Visitor>,
+    // How a server that can't be reached, or was lost, is retried
+    #[serde(default, deserialize_with = "de_retry")]
+    pub retry: Retry,
+    // How long a drain may take, in seconds, before the connections that
+    // are still live are dropped
+    #[serde(
+        default = "super::common::default_drain_timeout",
+        deserialize_with = "super::common::de_drain_timeout"
+    )]
+    pub drain_timeout: std::time::Duration,
+    #[serde(default)]
+    pub logging: super::common::Logging,
+    // Export traces and metrics over OTLP, if set
+    #[serde(default)]
+    pub otel: Option<super::common::Otel>,
+}
+
+/// Exponential backoff between attempts to connect to a server
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Retry {
+    // Seconds before the first retry
+    #[serde(default = "default_initial_delay")]
+    pub initial_delay: f64,
+    // Seconds the delay grows up to
+    #[serde(default = "default_max_delay")]
+    pub max_delay: f64,
+    // What the delay is multiplied by after each failed attempt
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    // Each delay is made up to this fraction shorter or longer at random,
+    // so that clients don't all come back at once
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+    // Failed attempts in a row before giving up. Unset, or 0, never gives
+    // up
+    #[serde(default)]
+    pub max_attempts: Option<u64>,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            initial_delay: default_initial_delay(),
+            max_delay: default_max_delay(),
+            multiplier: default_multiplier(),
+            jitter: default_jitter(),
+            max_attempts: None,
+        }
+    }
 }
 
-fn de_tunnels<'de, D>(deserializer: D) -> std::result::Result<HashMap<u16, Tunnel>, D::Error>
+impl Retry {
+    /// Failed attempts in a row before giving up on a server, None for
+    /// never
+    pub fn max_attempts(&self) -> Option<u64> {
+        match self.max_attempts {
+            None | Some(0) => None,
+            Some(n) => Some(n),
+        }
+    }
+}
+
+fn default_initial_delay() -> f64 {
+    0.5
+}
+
+fn default_max_delay() -> f64 {
+    30.0
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+fn default_jitter() -> f64 {
+    0.2
+}
+
+fn de_retry<'de, D>(deserializer: D) -> std::result::Result<Retry, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
-    let tunnels: Vec<Tunnel> = Vec::<Tunnel>::deserialize(deserializer)?;
-    let tunnel_map: HashMap<_, _> = tunnels.into_iter().map(|c| (c.remote_port, c)).collect();
-    Ok(tunnel_map)
+    let retry = Retry::deserialize(deserializer)?;
+    let message = if !(retry.initial_delay.is_finite() && retry.initial_delay > 0.0) {
+        Some("retry initial_delay must be more than 0 seconds")
+    } else if !(retry.max_delay.is_finite() && retry.max_delay >= retry.initial_delay) {
+        Some("retry max_delay must not be shorter than initial_delay")
+    } else if !(retry.multiplier.is_finite() && retry.multiplier >= 1.0) {
+        Some("retry multiplier must be at least 1")
+    } else if !(0.0..=1.0).contains(&retry.jitter) {
+        Some("retry jitter must be between 0 and 1")
+    } else {
+        None
+    };
+    match message {
+        Some(message) => Err(serde::de::Error::custom(message)),
+        None => Ok(retry),
+    }
+}
+
+/// A local port forwarded through the server, like `ssh -L`
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct Forward {
+    pub local_addr: SocketAddr,
+    // "host:port", resolved and connected to by the server. Must be in the
+    // server's forward_targets
+    #[serde(deserialize_with = "super::common::de_target")]
+    pub target: String,
+}
+
+fn de_forwards<'de, D>(deserializer: D) -> std::result::Result<Vec<Forward>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let forwards = Vec::<Forward>::deserialize(deserializer)?;
+    for (i, f) in forwards.iter().enumerate() {
+        if forwards[..i].iter().any(|o| o.local_addr == f.local_addr) {
+            return Err(serde::de::Error::custom(format!(
+                "forward local_addr {} is used more than once",
+                f.local_addr
+            )));
+        }
+    }
+    Ok(forwards)
+}
+
+/// A local port for the secret tunnel of another client, like frp's stcp
+/// visitors
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct Visitor {
+    pub local_addr: SocketAddr,
+    // The secret_name of the tunnel
+    #[serde(deserialize_with = "de_secret")]
+    pub secret_name: String,
+    #[serde(deserialize_with = "de_secret", skip_serializing)]
+    pub secret: String,
+}
+
+fn de_visitors<'de, D>(deserializer: D) -> std::result::Result<Vec<Visitor>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let visitors = Vec::<Visitor>::deserialize(deserializer)?;
+    for (i, v) in visitors.iter().enumerate() {
+        if visitors[..i].iter().any(|o| o.local_addr == v.local_addr) {
+            return Err(serde::de::Error::custom(format!(
+                "visitor local_addr {} is used more than once",
+                v.local_addr
+            )));
+        }
+    }
+    Ok(visitors)
+}
+
+fn de_secret<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let secret = String::deserialize(deserializer)?;
+    if secret.is_empty() {
+        return Err(serde::de::Error::custom(
+            "secret_name and secret must not be empty",
+        ));
+    }
+    Ok(secret)
+}
+
+/// How the tunnels are spread over several servers
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerMode {
+    // The tunnels are held by one server at a time, the first one that is
+    // up. If it goes down they move to the one with the lowest heartbeat RTT
+    #[default]
+    Failover,
+    // Every server holds every tunnel
+    ActiveActive,
+}
+
+impl Config {
+    /// The servers to connect to
+    pub fn servers(&self) -> Vec<String> {
+        match self.servers.is_empty() {
+            true => vec![self.addr.clone()],
+            false => self.servers.clone(),
+        }
+    }
 }
 
 fn default_mtu() -> u16 {
@@ -63,7 +267,7 @@ where
     Ok(value)
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct CryptoConfig {
     #[serde(default = "localhost_ipv4", deserialize_with = "de_sni_name")]
     pub sni_name: String,
@@ -110,14 +314,406 @@ fn localhost_ipv4() -> String {
     "127.0.0.1".to_string()
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Tunnel {
+    // 0, or omitted, asks the server to pick a port from its ephemeral pool
+    #[serde(default)]
     pub remote_port: u16,
+    // Serve this tunnel on the server's shared HTTP listener for requests
+    // with this Host, instead of on its own port
+    #[serde(default, deserialize_with = "de_hostname")]
+    pub hostname: Option<String>,
+    // Serve this tunnel on the server's shared SNI listener for TLS
+    // connections asking for this server name. TLS is passed through to the
+    // Internal untouched
+    #[serde(default, deserialize_with = "de_hostname")]
+    pub sni_hostname: Option<String>,
     #[serde(default = "localhost_ipv4")]
     pub local_hostname: String,
+    // Required unless backends is set
+    #[serde(default)]
     pub local_port: u16,
+    // Several Internals to spread connections over, instead of
+    // local_hostname/local_port
+    #[serde(default)]
+    pub backends: Vec<Backend>,
+    #[serde(default)]
+    pub balance: super::common::Balance,
+    // Only connect to the backends that pass this check
+    #[serde(default, deserialize_with = "de_health_check")]
+    pub health_check: Option<HealthCheck>,
     #[serde(default = "Option::default", skip_serializing)]
     pub crypto: Option<CryptoConfig>,
+    // Send a PROXY protocol header with the External's address to the
+    // Internal before any data
+    #[serde(default)]
+    pub proxy_protocol: Option<crate::proxy_protocol::ProxyProtocol>,
+    // Parse the HTTP/1.x going through this tunnel to add X-Forwarded-For
+    // and friends, and to write an access log
+    #[serde(default)]
+    pub http: bool,
+    // The scheme to put in X-Forwarded-Proto. "https" if the server
+    // terminates TLS for this tunnel
+    #[serde(
+        default = "default_forwarded_proto",
+        deserialize_with = "de_forwarded_proto"
+    )]
+    pub forwarded_proto: String,
+    // Share remote_port with the other clients in the pool of this name,
+    // instead of holding it alone
+    #[serde(default)]
+    pub pool: Option<String>,
+    // Don't serve this tunnel on the server at all, but only to the
+    // visitors of other clients that ask for it by this name and know
+    // secret
+    #[serde(default, deserialize_with = "de_secret_name")]
+    pub secret_name: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub secret: Option<String>,
+}
+
+fn de_secret_name<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Some(de_secret(deserializer)?))
+}
+
+fn default_forwarded_proto() -> String {
+    "http".to_string()
+}
+
+fn de_forwarded_proto<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let proto = String::deserialize(deserializer)?.to_ascii_lowercase();
+    if proto != "http" && proto != "https" {
+        return Err(serde::de::Error::custom(format!(
+            "forwarded_proto invalid. expected \"http\" or \"https\", got {proto:?}"
+        )));
+    }
+    Ok(proto)
+}
+
+/// One of the Internals of a tunnel
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct Backend {
+    #[serde(default = "localhost_ipv4")]
+    pub local_hostname: String,
+    pub local_port: u16,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.local_hostname.contains(':') {
+            true => write!(f, "[{}]:{}", self.local_hostname, self.local_port),
+            false => write!(f, "{}:{}", self.local_hostname, self.local_port),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct HealthCheck {
+    // Seconds between checks
+    #[serde(default = "default_health_interval")]
+    pub interval: u64,
+    // Seconds before a check counts as failed
+    #[serde(default = "default_health_timeout")]
+    pub timeout: u64,
+    // GET this path and expect a 2xx or 3xx, instead of only connecting
+    #[serde(default)]
+    pub http_path: Option<String>,
+}
+
+fn default_health_interval() -> u64 {
+    10
+}
+
+fn default_health_timeout() -> u64 {
+    2
+}
+
+fn de_health_check<'de, D>(deserializer: D) -> std::result::Result<Option<HealthCheck>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let check = Option::<HealthCheck>::deserialize(deserializer)?;
+    let Some(check) = check else {
+        return Ok(None);
+    };
+    if check.interval == 0 || check.timeout == 0 {
+        return Err(serde::de::Error::custom(
+            "health_check interval and timeout must be at least 1 second",
+        ));
+    }
+    if check.timeout > check.interval {
+        return Err(serde::de::Error::custom(
+            "health_check timeout must not be longer than its interval",
+        ));
+    }
+    if let Some(ref path) = check.http_path {
+        if !path.starts_with('/') || path.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(serde::de::Error::custom(format!(
+                "health_check http_path must be a path starting with /, got {path:?}"
+            )));
+        }
+    }
+    Ok(Some(check))
+}
+
+impl Tunnel {
+    /// The hostname this tunnel is served under on one of the server's
+    /// shared listeners, or the name of a secret tunnel, if any
+    pub fn vhost(&self) -> Option<Vhost> {
+        let (kind, hostname) = match (&self.hostname, &self.sni_hostname, &self.secret_name) {
+            (Some(h), _, _) => (VhostKind::Http, h),
+            (None, Some(h), _) => (VhostKind::Sni, h),
+            (None, None, Some(name)) => (VhostKind::Secret, name),
+            (None, None, None) => return None,
+        };
+        Some(Vhost {
+            kind,
+            hostname: hostname.clone(),
+        })
+    }
+
+    /// The Internals of this tunnel, in config order
+    pub fn backends(&self) -> Vec<Backend> {
+        if !self.backends.is_empty() {
+            return self.backends.clone();
+        }
+        vec![Backend {
+            local_hostname: self.local_hostname.clone(),
+            local_port: self.local_port,
+        }]
+    }
+}
+
+fn de_hostname<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let hostname = Option::<String>::deserialize(deserializer)?;
+    let Some(hostname) = hostname else {
+        return Ok(None);
+    };
+    // Host headers and server names are matched case insensitively, and
+    // without a trailing dot
+    let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+    if hostname.contains(':') || rustls::pki_types::ServerName::try_from(hostname.clone()).is_err()
+    {
+        return Err(serde::de::Error::custom(format!(
+            "hostname invalid. expected hostname without a port, got {hostname:?}"
+        )));
+    }
+    Ok(Some(hostname))
+}
+
+/// The `[[tunnels]]` of a config, split by how the server exposes them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tunnels {
+    // By remote_port
+    pub fixed: HashMap<u16, Tunnel>,
+    // Tunnels with remote_port = 0, in config order
+    pub ephemeral: Vec<Tunnel>,
+    // By hostname, served on one of the server's shared listeners
+    pub hosts: HashMap<Vhost, Tunnel>,
+}
+
+impl Tunnels {
+    pub fn iter(&self) -> impl Iterator<Item = &Tunnel> {
+        self.fixed
+            .values()
+            .chain(self.ephemeral.iter())
+            .chain(self.hosts.values())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Tunnel> {
+        self.fixed
+            .values_mut()
+            .chain(self.ephemeral.iter_mut())
+            .chain(self.hosts.values_mut())
+    }
+
+    pub fn len(&self) -> usize {
+        self.fixed.len() + self.ephemeral.len() + self.hosts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The remote_ports held alone, and those shared by a pool along with
+    /// the pool's name
+    pub fn split_pooled(&self, ports: &[u16]) -> (Vec<u16>, Vec<(u16, String)>) {
+        let mut alone = Vec::new();
+        let mut pooled = Vec::new();
+        for port in ports.iter().copied() {
+            match self.fixed.get(&port).and_then(|t| t.pool.clone()) {
+                None => alone.push(port),
+                Some(pool) => pooled.push((port, pool)),
+            }
+        }
+        (alone, pooled)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tunnels {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let mut tunnels = Tunnels::default();
+        for t in Vec::<Tunnel>::deserialize(deserializer)? {
+            let names = [&t.hostname, &t.sni_hostname, &t.secret_name];
+            if names.iter().filter(|n| n.is_some()).count() > 1 {
+                return Err(serde::de::Error::custom(
+                    "tunnel must set only one of hostname, sni_hostname and secret_name",
+                ));
+            }
+            if t.secret_name.is_some() != t.secret.is_some() {
+                return Err(serde::de::Error::custom(
+                    "tunnel must set secret_name and secret together",
+                ));
+            }
+            match (t.local_port, t.backends.is_empty()) {
+                (0, true) => {
+                    return Err(serde::de::Error::custom(
+                        "tunnel must set local_port or backends",
+                    ));
+                }
+                (port, false) if port != 0 => {
+                    return Err(serde::de::Error::custom(
+                        "tunnel must not set both local_port and backends",
+                    ));
+                }
+                _ => (),
+            }
+            if t.backends.iter().any(|b| b.local_port == 0) {
+                return Err(serde::de::Error::custom("backend local_port must not be 0"));
+            }
+            if t.pool.is_some() && (t.vhost().is_some() || t.remote_port == 0) {
+                return Err(serde::de::Error::custom(
+                    "tunnel with a pool must set remote_port, and not hostname, sni_hostname or secret_name",
+                ));
+            }
+            match (t.vhost(), t.remote_port) {
+                (Some(_), port) if port != 0 => {
+                    return Err(serde::de::Error::custom(format!(
+                        "tunnel with hostname, sni_hostname or secret_name must not set remote_port, got {port}"
+                    )));
+                }
+                (Some(vhost), _) => {
+                    tunnels.hosts.insert(vhost, t);
+                }
+                (None, 0) => tunnels.ephemeral.push(t),
+                (None, port) => {
+                    tunnels.fixed.insert(port, t);
+                }
+            }
+        }
+        Ok(tunnels)
+    }
+}
+
+impl Serialize for Tunnels {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+/// Difference between the `tunnels` of two configs. Fixed tunnels are
+/// compared by remote_port, ephemeral ones by their whole config
+#[derive(Debug, Default, PartialEq)]
+pub struct TunnelDiff {
+    pub added: Vec<u16>,
+    pub removed: Vec<u16>,
+    // Same remote_port, but the Internal changed
+    pub changed: Vec<u16>,
+    pub added_ephemeral: Vec<Tunnel>,
+    pub removed_ephemeral: Vec<Tunnel>,
+    pub added_hosts: Vec<Vhost>,
+    pub removed_hosts: Vec<Vhost>,
+    // Same hostname, but the Internal changed
+    pub changed_hosts: Vec<Vhost>,
+}
+
+impl TunnelDiff {
+    pub fn new(old: &Tunnels, new: &Tunnels) -> TunnelDiff {
+        let mut diff = TunnelDiff::default();
+        for (port, t) in new.fixed.iter() {
+            match old.fixed.get(port) {
+                None => diff.added.push(*port),
+                // The server has to let go of the port before it can be
+                // held another way
+                Some(o) if o.pool != t.pool => {
+                    diff.removed.push(*port);
+                    diff.added.push(*port);
+                }
+                Some(o) if o != t => diff.changed.push(*port),
+                Some(_) => (),
+            }
+        }
+        diff.removed.extend(
+            old.fixed
+                .keys()
+                .filter(|p| !new.fixed.contains_key(p))
+                .copied(),
+        );
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+
+        let mut unmatched = old.ephemeral.clone();
+        for t in new.ephemeral.iter() {
+            match unmatched.iter().position(|o| o == t) {
+                None => diff.added_ephemeral.push(t.clone()),
+                Some(i) => {
+                    unmatched.remove(i);
+                }
+            }
+        }
+        diff.removed_ephemeral = unmatched;
+
+        for (host, t) in new.hosts.iter() {
+            match old.hosts.get(host) {
+                None => diff.added_hosts.push(host.clone()),
+                // Visitors are checked against the secret the server got
+                // along with the name
+                Some(o) if o.secret != t.secret => {
+                    diff.removed_hosts.push(host.clone());
+                    diff.added_hosts.push(host.clone());
+                }
+                Some(o) if o != t => diff.changed_hosts.push(host.clone()),
+                Some(_) => (),
+            }
+        }
+        diff.removed_hosts.extend(
+            old.hosts
+                .keys()
+                .filter(|h| !new.hosts.contains_key(*h))
+                .cloned(),
+        );
+        diff.added_hosts.sort();
+        diff.removed_hosts.sort();
+        diff.changed_hosts.sort();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.added_ephemeral.is_empty()
+            && self.removed_ephemeral.is_empty()
+            && self.added_hosts.is_empty()
+            && self.removed_hosts.is_empty()
+            && self.changed_hosts.is_empty()
+    }
 }
 
 #[derive(Debug)]
@@ -149,7 +745,36 @@ pub fn load_config(config: &Path) -> crate::config::Result<Config> {
         message: format!("failed to read config file '{:?}'", config),
     })?;
 
-    let c: Config =
+    let mut c: Config =
         toml::from_str(&config_contents).with_context(|_| crate::config::DecodeSnafu {})?;
+    let message = match (c.addr.is_empty(), c.servers.is_empty()) {
+        (true, true) => Some("addr or servers must be set"),
+        (false, false) => Some("addr and servers must not both be set"),
+        _ if (1..c.servers.len()).any(|i| c.servers[..i].contains(&c.servers[i])) => {
+            Some("servers must not be listed twice")
+        }
+        _ => None,
+    };
+    if let Some(message) = message {
+        return crate::config::InvalidSnafu { message }.fail();
+    }
+    let shared = c
+        .visitors
+        .iter()
+        .find(|v| c.forwards.iter().any(|f| f.local_addr == v.local_addr));
+    if let Some(v) = shared {
+        return crate::config::InvalidSnafu {
+            message: format!(
+                "local_addr {} is used by both a forward and a visitor",
+                v.local_addr
+            ),
+        }
+        .fail();
+    }
+    if let Some(ref check) = c.health_check {
+        for t in c.tunnels.iter_mut() {
+            t.health_check.get_or_insert_with(|| check.clone());
+        }
+    }
     Ok(c)
 }
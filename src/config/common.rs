@@ -50,6 +50,219 @@ fn default_auth_timeout() -> Duration {
     std::time::Duration::from_millis(300)
 }
 
+pub fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+pub fn de_drain_timeout<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let secs = u64::deserialize(deserializer)?;
+    if secs > 3600 {
+        return Err(serde::de::Error::custom(format!(
+            "drain_timeout should be at most 3600 seconds, got {secs}"
+        )));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Where logs go, and how much of them
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Logging {
+    #[serde(default)]
+    pub format: LogFormat,
+    // The level of everything that isn't in modules
+    #[serde(default = "default_log_level", deserialize_with = "de_log_level")]
+    pub level: String,
+    // Levels of single modules, e.g. "nat_tunnel::redirector" = "trace"
+    #[serde(default, deserialize_with = "de_log_modules")]
+    pub modules: std::collections::BTreeMap<String, String>,
+    // Append to this file instead of writing to stdout, if set
+    #[serde(default)]
+    pub file: Option<std::path::PathBuf>,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            level: default_log_level(),
+            modules: Default::default(),
+            file: None,
+        }
+    }
+}
+
+impl Logging {
+    /// The filter directives for `level` and `modules`, RUST_LOG style
+    pub fn directives(&self) -> String {
+        let mut directives = vec![self.level.clone()];
+        directives.extend(self.modules.iter().map(|(m, l)| format!("{m}={l}")));
+        directives.join(",")
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One JSON object per line, with the fields of the spans it's in
+    Json,
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn check_log_level<E: serde::de::Error>(level: &str) -> Result<(), E> {
+    match level.parse::<tracing::level_filters::LevelFilter>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(E::custom(format!(
+            "log level must be one of off, error, warn, info, debug or trace, got {level:?}"
+        ))),
+    }
+}
+
+fn de_log_level<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let level = String::deserialize(deserializer)?;
+    check_log_level(&level)?;
+    Ok(level)
+}
+
+fn de_log_modules<'de, D>(
+    deserializer: D,
+) -> std::result::Result<std::collections::BTreeMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let modules = std::collections::BTreeMap::<String, String>::deserialize(deserializer)?;
+    for (module, level) in modules.iter() {
+        if module.is_empty() || module.contains([',', '=', '[', ']', '{', '}', ' ']) {
+            return Err(serde::de::Error::custom(format!(
+                "invalid module name for logging: {module:?}"
+            )));
+        }
+        check_log_level(level)?;
+    }
+    Ok(modules)
+}
+
+/// Where traces and metrics are exported to over OTLP/HTTP. Only used when
+/// built with the `otel` feature
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Otel {
+    // The collector's OTLP/HTTP receiver. /v1/traces and /v1/metrics are
+    // appended to it
+    #[serde(deserialize_with = "de_otel_endpoint")]
+    pub endpoint: String,
+    #[serde(default)]
+    pub protocol: OtelProtocol,
+    // Reported as service.name. The binary's name if unset
+    #[serde(default)]
+    pub service_name: Option<String>,
+    // How often metrics are exported, in seconds
+    #[serde(
+        default = "default_otel_metrics_interval",
+        deserialize_with = "de_otel_metrics_interval"
+    )]
+    pub metrics_interval: Duration,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OtelProtocol {
+    #[default]
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+    #[serde(rename = "http/json")]
+    HttpJson,
+}
+
+fn default_otel_metrics_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn de_otel_endpoint<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let endpoint = String::deserialize(deserializer)?;
+    if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+        return Err(serde::de::Error::custom(format!(
+            "otel endpoint must be an http:// or https:// URL, got {endpoint:?}"
+        )));
+    }
+    Ok(endpoint.trim_end_matches('/').to_string())
+}
+
+fn de_otel_metrics_interval<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let secs = u64::deserialize(deserializer)?;
+    if secs == 0 || secs > 3600 {
+        return Err(serde::de::Error::custom(format!(
+            "otel metrics_interval should be in range (0, 3600] seconds, got {secs}"
+        )));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// How connections are spread over the places that can take them
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    // Whichever has the fewest live connections
+    LeastConnections,
+}
+
+/// The port of a "host:port" destination, or None if `target` isn't one.
+/// IPv6 addresses go in brackets
+pub fn target_port(target: &str) -> Option<u16> {
+    let (host, port) = target.rsplit_once(':')?;
+    let port = port.parse().ok().filter(|p| *p != 0)?;
+    let bracketed = host.starts_with('[') && host.ends_with(']');
+    match host.is_empty() || (host.contains(':') && !bracketed) {
+        true => None,
+        false => Some(port),
+    }
+}
+
+fn check_target<E: serde::de::Error>(target: &str) -> Result<(), E> {
+    match target_port(target) {
+        Some(_) => Ok(()),
+        None => Err(E::custom(format!(
+            "forward target must be host:port, got {target:?}"
+        ))),
+    }
+}
+
+pub fn de_target<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let target = String::deserialize(deserializer)?;
+    check_target(&target)?;
+    Ok(target)
+}
+
+pub fn de_targets<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let targets = Vec::<String>::deserialize(deserializer)?;
+    for t in targets.iter() {
+        check_target(t)?;
+    }
+    Ok(targets)
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum Transport {
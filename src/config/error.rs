@@ -28,6 +28,8 @@ pub enum Error {
     },
     #[snafu(display("unsafe transport enabled without --allow-unsafe-transport"))]
     UnsafeTransport,
+    #[snafu(display("invalid config: {message}"))]
+    Invalid { message: String },
 }
 
 impl From<toml::de::Error> for Error {
@@ -47,6 +47,201 @@ pub struct Config {
     pub channel_limits: ChannelLimits,
     #[serde(default)]
     pub timeouts: super::common::Timeout,
+    // Serve Prometheus metrics on this address, if set
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+    // Serve the admin API on this address, if set. Must be loopback
+    #[serde(default, deserialize_with = "de_admin_addr")]
+    pub admin_addr: Option<SocketAddr>,
+    // Ports handed out to tunnels that ask for remote_port = 0, if set
+    #[serde(default, deserialize_with = "de_ephemeral_ports")]
+    pub ephemeral_ports: Option<PortRange>,
+    // Shared listener that routes HTTP requests to clients by their Host
+    // header, if set
+    #[serde(default)]
+    pub http_addr: Option<SocketAddr>,
+    // Shared listener that routes TLS connections to clients by the server
+    // name in their ClientHello, without terminating TLS, if set
+    #[serde(default)]
+    pub sni_addr: Option<SocketAddr>,
+    // Terminate TLS for Externals on some remote_ports, if set
+    #[serde(default)]
+    pub tls_termination: Option<TlsTermination>,
+    // Require credentials on the requests to some HTTP tunnels
+    #[serde(default, deserialize_with = "de_http_auth")]
+    pub http_auth: Vec<HttpAuth>,
+    // How Externals on a port shared by a pool of clients are spread over
+    // its members
+    #[serde(default)]
+    pub pool_balance: super::common::Balance,
+    // The "host:port" destinations clients may open local forwards to.
+    // Local forwarding is off while this is empty
+    #[serde(default, deserialize_with = "super::common::de_targets")]
+    pub forward_targets: Vec<String>,
+    // How long a client whose connection dropped may take to come back and
+    // resume its session, in seconds. Sessions aren't resumed if unset
+    #[serde(default, deserialize_with = "de_session_grace")]
+    pub session_grace: Option<std::time::Duration>,
+    // How long a drain may take, in seconds, before the connections that
+    // are still live are dropped
+    #[serde(
+        default = "super::common::default_drain_timeout",
+        deserialize_with = "super::common::de_drain_timeout"
+    )]
+    pub drain_timeout: std::time::Duration,
+    #[serde(default)]
+    pub logging: super::common::Logging,
+    // Export traces and metrics over OTLP, if set
+    #[serde(default)]
+    pub otel: Option<super::common::Otel>,
+    // Write the access log, one line per External connection, to this file
+    // instead of along with the rest of the logs, if set
+    #[serde(default)]
+    pub access_log: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HttpAuth {
+    // The remote_ports and hostnames of the tunnels this applies to
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    // Users allowed in with basic auth. Only bcrypt passwords are supported
+    #[serde(default)]
+    pub htpasswd: Option<PathBuf>,
+    // Allowed in as `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    #[serde(default = "default_realm")]
+    pub realm: String,
+}
+
+fn default_realm() -> String {
+    "nat-tunnel".to_string()
+}
+
+fn de_http_auth<'de, D>(deserializer: D) -> std::result::Result<Vec<HttpAuth>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let policies = Vec::<HttpAuth>::deserialize(deserializer)?;
+    let mut ports = std::collections::HashSet::new();
+    let mut hosts = std::collections::HashSet::new();
+    for p in policies.iter() {
+        if p.htpasswd.is_none() && p.tokens.is_empty() {
+            return Err(serde::de::Error::custom(
+                "http_auth needs an htpasswd file or tokens",
+            ));
+        }
+        if p.tokens.iter().any(String::is_empty) {
+            return Err(serde::de::Error::custom("http_auth tokens can't be empty"));
+        }
+        if p.realm.contains(['"', '\\']) {
+            return Err(serde::de::Error::custom(format!(
+                "http_auth realm can't contain quotes or backslashes, got {:?}",
+                p.realm
+            )));
+        }
+        if let Some(port) = p.ports.iter().find(|port| !ports.insert(**port)) {
+            return Err(serde::de::Error::custom(format!(
+                "port {port} is in more than one http_auth"
+            )));
+        }
+        if let Some(host) = p
+            .hosts
+            .iter()
+            .find(|host| !hosts.insert(host.to_ascii_lowercase()))
+        {
+            return Err(serde::de::Error::custom(format!(
+                "host {host} is in more than one http_auth"
+            )));
+        }
+    }
+    Ok(policies)
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TlsTermination {
+    // remote_ports whose Externals speak TLS to the server, which forwards
+    // plaintext to the Internal
+    pub ports: Vec<u16>,
+    // Picked by the server name an External asks for. The first one is used
+    // for Externals no certificate covers
+    #[serde(deserialize_with = "de_termination_certs")]
+    pub certs: Vec<CryptoConfig>,
+}
+
+fn de_termination_certs<'de, D>(deserializer: D) -> std::result::Result<Vec<CryptoConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let certs = Vec::<CryptoConfig>::deserialize(deserializer)?;
+    if certs.is_empty() {
+        return Err(serde::de::Error::custom(
+            "tls_termination needs at least one certificate",
+        ));
+    }
+    Ok(certs)
+}
+
+/// An inclusive range of ports
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn iter(&self) -> std::ops::RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+fn de_ephemeral_ports<'de, D>(deserializer: D) -> std::result::Result<Option<PortRange>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let range = Option::<PortRange>::deserialize(deserializer)?;
+    if let Some(r) = range {
+        if r.start == 0 || r.start > r.end {
+            return Err(serde::de::Error::custom(format!(
+                "ephemeral_ports must satisfy 0 < start <= end, got {}-{}",
+                r.start, r.end
+            )));
+        }
+    }
+    Ok(range)
+}
+
+fn de_admin_addr<'de, D>(deserializer: D) -> std::result::Result<Option<SocketAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let addr = Option::<SocketAddr>::deserialize(deserializer)?;
+    if let Some(a) = addr {
+        if !a.ip().is_loopback() {
+            return Err(serde::de::Error::custom(format!(
+                "admin_addr must be a loopback address, got {a}"
+            )));
+        }
+    }
+    Ok(addr)
+}
+
+fn de_session_grace<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<std::time::Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let secs = u64::deserialize(deserializer)?;
+    if secs > 3600 {
+        return Err(serde::de::Error::custom(format!(
+            "session_grace should be at most 3600 seconds, got {secs}"
+        )));
+    }
+    Ok((secs > 0).then(|| std::time::Duration::from_secs(secs)))
 }
 
 fn default_mtu() -> u16 {
@@ -72,7 +267,7 @@ pub fn load_config(config: &Path) -> Result<Config> {
 
 // Note: we defer parsing the file because keys/certs can't
 // /shouldn't be moved around in memory
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct CryptoConfig {
     #[serde(deserialize_with = "de_key_file")]
     pub key: PathBuf,
@@ -0,0 +1,469 @@
+//! stc's local control socket. A running client keeps a [`Status`] up to date
+//! and writes a JSON [`StatusReport`] to anybody that connects to the socket
+//! and asks for it with a `status` line. A `drain` line drains the client
+//! first.
+use crate::{config::client as config, net as stnet};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, trace};
+
+// Ordered from worst to best
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Connecting => write!(f, "connecting"),
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelStatus {
+    // 0 for an ephemeral tunnel the server hasn't assigned a port to yet
+    pub remote_port: u16,
+    pub ephemeral: bool,
+    // Served on one of the server's shared listeners instead of remote_port
+    pub hostname: Option<String>,
+    // The shared listener is the SNI one, rather than the HTTP one
+    #[serde(default)]
+    pub sni: bool,
+    // Not served on the server, only to visitors. hostname is its
+    // secret_name
+    #[serde(default)]
+    pub secret: bool,
+    // Shared with the other clients in this pool
+    #[serde(default)]
+    pub pool: Option<String>,
+    pub local_hostname: String,
+    pub local_port: u16,
+    // host:port of each Internal, for tunnels with several
+    #[serde(default)]
+    pub backends: Vec<String>,
+    pub acknowledged: bool,
+    pub active_connections: i64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub state: ConnectionState,
+    pub server_addr: String,
+    pub reconnect_attempts: u64,
+    pub connected_secs: Option<u64>,
+    // Round trip time of the last Ping to the server
+    #[serde(default)]
+    pub heartbeat_rtt_ms: Option<u64>,
+    pub tunnels: Vec<TunnelStatus>,
+    // The backoff before the last retry, until connected again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryStatus>,
+    // The report of each server, when connected to several. The rest of
+    // the report then sums them up
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<StatusReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryStatus {
+    // Failed attempts in a row
+    pub attempt: u64,
+    pub delay_ms: u64,
+}
+
+struct StatusInner {
+    // Each tunnel along with the remote_port it is served on
+    tunnels: Vec<(u16, config::Tunnel)>,
+    state: ConnectionState,
+    reconnect_attempts: u64,
+    connected_at: Option<Instant>,
+    rtt: Option<std::time::Duration>,
+    acknowledged: Vec<u16>,
+    acknowledged_hosts: Vec<stnet::Vhost>,
+    retry: Option<RetryStatus>,
+}
+
+/// Live state of a client's connection to a server
+pub struct Status {
+    server_addr: String,
+    inner: Mutex<StatusInner>,
+}
+
+impl Status {
+    pub fn new(config: &config::Config) -> Self {
+        Status {
+            server_addr: config.addr.clone(),
+            inner: Mutex::new(StatusInner {
+                tunnels: sorted_tunnels(
+                    config
+                        .tunnels
+                        .iter()
+                        .map(|t| (t.remote_port, t.clone()))
+                        .collect(),
+                ),
+                state: ConnectionState::Connecting,
+                reconnect_attempts: 0,
+                connected_at: None,
+                rtt: None,
+                acknowledged: Vec::new(),
+                acknowledged_hosts: Vec::new(),
+                retry: None,
+            }),
+        }
+    }
+
+    pub fn set_connecting(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = ConnectionState::Connecting;
+    }
+
+    /// The server acknowledged `tunnels`
+    pub fn set_connected(&self, tunnels: Vec<u16>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = ConnectionState::Connected;
+        inner.connected_at = Some(Instant::now());
+        inner.acknowledged = tunnels;
+        inner.retry = None;
+    }
+
+    /// The server acknowledged a change of tunnels
+    pub fn set_acknowledged(&self, tunnels: Vec<u16>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.acknowledged = tunnels;
+    }
+
+    /// The server acknowledged a change of hostnames
+    pub fn set_acknowledged_hosts(&self, hosts: Vec<stnet::Vhost>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.acknowledged_hosts = hosts;
+    }
+
+    /// The tunnels changed, either by a reload or by the server assigning
+    /// ephemeral ports. Each tunnel comes with the remote_port it is served
+    /// on, which is 0 until an ephemeral tunnel is assigned one
+    pub fn set_tunnels(&self, tunnels: Vec<(u16, config::Tunnel)>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tunnels = sorted_tunnels(tunnels);
+    }
+
+    /// The round trip time of the last Ping to the server
+    pub fn set_rtt(&self, rtt: std::time::Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.rtt = Some(rtt);
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.inner.lock().unwrap().state
+    }
+
+    pub fn rtt(&self) -> Option<std::time::Duration> {
+        self.inner.lock().unwrap().rtt
+    }
+
+    pub fn server_addr(&self) -> &str {
+        &self.server_addr
+    }
+
+    pub fn set_disconnected(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = ConnectionState::Disconnected;
+        inner.connected_at = None;
+        inner.rtt = None;
+        inner.acknowledged.clear();
+        inner.acknowledged_hosts.clear();
+    }
+
+    /// The client is waiting `delay` before trying again, after `attempt`
+    /// failed attempts in a row
+    pub fn set_retry(&self, attempt: u64, delay: std::time::Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.retry = Some(RetryStatus {
+            attempt,
+            delay_ms: delay.as_millis() as u64,
+        });
+    }
+
+    pub fn record_reconnect(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.reconnect_attempts += 1;
+    }
+
+    pub fn report(&self) -> StatusReport {
+        let metrics = crate::metrics::metrics();
+        let inner = self.inner.lock().unwrap();
+        let tunnels = inner
+            .tunnels
+            .iter()
+            .map(|(remote_port, t)| {
+                // Don't create series for tunnels that aren't served yet
+                let (active_connections, bytes_in, bytes_out) = match remote_port {
+                    0 => (0, 0, 0),
+                    port => {
+                        let port = port.to_string();
+                        (
+                            metrics.active_connections.with_label_values(&[&port]).get(),
+                            metrics.tunnel_bytes.with_label_values(&[&port, "in"]).get(),
+                            metrics
+                                .tunnel_bytes
+                                .with_label_values(&[&port, "out"])
+                                .get(),
+                        )
+                    }
+                };
+                let vhost = t.vhost();
+                TunnelStatus {
+                    remote_port: *remote_port,
+                    ephemeral: t.remote_port == 0 && vhost.is_none(),
+                    hostname: vhost.as_ref().map(|v| v.hostname.clone()),
+                    sni: t.sni_hostname.is_some(),
+                    secret: t.secret_name.is_some(),
+                    pool: t.pool.clone(),
+                    local_hostname: t.local_hostname.clone(),
+                    local_port: t.local_port,
+                    backends: t.backends.iter().map(|b| b.to_string()).collect(),
+                    acknowledged: match vhost {
+                        Some(ref v) => inner.acknowledged_hosts.contains(v),
+                        None => inner.acknowledged.contains(remote_port),
+                    },
+                    active_connections,
+                    bytes_in,
+                    bytes_out,
+                }
+            })
+            .collect();
+        StatusReport {
+            state: inner.state,
+            server_addr: self.server_addr.clone(),
+            reconnect_attempts: inner.reconnect_attempts,
+            connected_secs: inner.connected_at.map(|t| t.elapsed().as_secs()),
+            heartbeat_rtt_ms: inner.rtt.map(|r| r.as_millis() as u64),
+            tunnels,
+            retry: inner.retry.clone(),
+            servers: Vec::new(),
+        }
+    }
+}
+
+/// The report of a client connected to each server of `statuses`. With a
+/// single server it is that server's report
+pub fn report(statuses: &[Arc<Status>]) -> StatusReport {
+    if let [status] = statuses {
+        return status.report();
+    }
+    let servers: Vec<_> = statuses.iter().map(|s| s.report()).collect();
+    StatusReport {
+        state: servers
+            .iter()
+            .map(|r| r.state)
+            .max()
+            .unwrap_or(ConnectionState::Disconnected),
+        server_addr: servers
+            .iter()
+            .map(|r| r.server_addr.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        reconnect_attempts: servers.iter().map(|r| r.reconnect_attempts).sum(),
+        connected_secs: servers.iter().filter_map(|r| r.connected_secs).max(),
+        heartbeat_rtt_ms: servers.iter().filter_map(|r| r.heartbeat_rtt_ms).min(),
+        tunnels: Vec::new(),
+        retry: None,
+        servers,
+    }
+}
+
+fn sorted_tunnels(mut tunnels: Vec<(u16, config::Tunnel)>) -> Vec<(u16, config::Tunnel)> {
+    tunnels.sort_by_key(|(port, _)| *port);
+    tunnels
+}
+
+impl std::fmt::Display for StatusReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.connected_secs {
+            Some(secs) => writeln!(f, "state: {} ({secs}s)", self.state)?,
+            None => writeln!(f, "state: {}", self.state)?,
+        }
+        if !self.servers.is_empty() {
+            writeln!(f, "servers: {}", self.server_addr)?;
+            writeln!(f, "reconnect attempts: {}", self.reconnect_attempts)?;
+            for server in self.servers.iter() {
+                writeln!(f)?;
+                write!(f, "{server}")?;
+            }
+            return Ok(());
+        }
+        writeln!(f, "server: {}", self.server_addr)?;
+        writeln!(f, "reconnect attempts: {}", self.reconnect_attempts)?;
+        if let Some(ref retry) = self.retry {
+            writeln!(
+                f,
+                "retry: attempt {} after {}ms",
+                retry.attempt, retry.delay_ms
+            )?;
+        }
+        if let Some(rtt) = self.heartbeat_rtt_ms {
+            writeln!(f, "heartbeat rtt: {rtt}ms")?;
+        }
+        writeln!(f, "tunnels:")?;
+        for t in self.tunnels.iter() {
+            let remote_port = match (&t.hostname, t.ephemeral, t.remote_port) {
+                (Some(h), _, _) if t.sni => format!("{h} (sni)"),
+                (Some(h), _, _) if t.secret => format!("{h} (secret)"),
+                (Some(h), _, _) => h.clone(),
+                (None, true, 0) => "(unassigned)".to_string(),
+                (None, true, port) => format!("{port} (assigned)"),
+                (None, false, port) => match t.pool {
+                    Some(ref pool) => format!("{port} (pool {pool})"),
+                    None => port.to_string(),
+                },
+            };
+            let internal = match t.backends.is_empty() {
+                true => format!("{}:{}", t.local_hostname, t.local_port),
+                false => t.backends.join(", "),
+            };
+            writeln!(
+                f,
+                "  {} -> {} ({}) connections: {} bytes in: {} bytes out: {}",
+                remote_port,
+                internal,
+                if t.acknowledged {
+                    "acknowledged"
+                } else {
+                    "not acknowledged"
+                },
+                t.active_connections,
+                t.bytes_in,
+                t.bytes_out,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Serve status reports on the unix socket at `path` until `token` is
+/// cancelled, and cancel `drain` when asked to. A stale socket file at
+/// `path` is replaced.
+#[tracing::instrument(name = "Control", level = "info", skip_all)]
+pub async fn serve(
+    path: PathBuf,
+    statuses: Vec<Arc<Status>>,
+    drain: CancellationToken,
+    token: CancellationToken,
+) -> stnet::Result<()> {
+    if path.exists() {
+        std::fs::remove_file(&path).with_context(|_| stnet::IoSnafu {
+            message: format!("failed to remove stale control socket {path:?}"),
+        })?;
+    }
+    let listener = UnixListener::bind(&path).with_context(|_| stnet::IoSnafu {
+        message: format!("failed to bind control socket {path:?}"),
+    })?;
+    info!(path = ?path, "control socket listening");
+
+    let ret = loop {
+        let mut stream = tokio::select! {
+            maybe_accept = listener.accept() => match maybe_accept {
+                Err(e) => {
+                    error!(cause = ?e, "failed to accept control connection");
+                    continue;
+                }
+                Ok((s, _)) => s,
+            },
+            _ = token.cancelled() => break Ok(()),
+        };
+
+        let statuses = statuses.clone();
+        let drain = drain.clone();
+        tokio::spawn(async move {
+            let mut command = String::new();
+            let mut reader = tokio::io::BufReader::new((&mut stream).take(64));
+            let read = reader.read_line(&mut command);
+            if !matches!(
+                tokio::time::timeout(std::time::Duration::from_secs(5), read).await,
+                Ok(Ok(_))
+            ) {
+                trace!("bad control request");
+                return;
+            }
+            match command.trim() {
+                "status" => (),
+                "drain" => {
+                    if !drain.is_cancelled() {
+                        info!("drain requested on the control socket");
+                    }
+                    drain.cancel();
+                }
+                c => {
+                    trace!(command = c, "unknown control command");
+                    return;
+                }
+            }
+            drop(reader);
+            let report = report(&statuses);
+            let body = match serde_json::to_vec(&report) {
+                Err(e) => {
+                    error!(cause = ?e, "failed to encode status");
+                    return;
+                }
+                Ok(b) => b,
+            };
+            if let Err(e) = stream.write_all(&body).await {
+                trace!(cause = ?e, "failed to write status");
+            }
+            let _ = stream.shutdown().await;
+        });
+    };
+    let _ = std::fs::remove_file(&path);
+    ret
+}
+
+/// Fetch the status of the stc listening on the control socket at `path`
+pub async fn query(path: &Path) -> stnet::Result<StatusReport> {
+    request(path, "status").await
+}
+
+/// Drain the stc listening on the control socket at `path`. Returns its
+/// status as of the start of the drain
+pub async fn drain(path: &Path) -> stnet::Result<StatusReport> {
+    request(path, "drain").await
+}
+
+async fn request(path: &Path, command: &str) -> stnet::Result<StatusReport> {
+    let mut stream = UnixStream::connect(path)
+        .await
+        .with_context(|_| stnet::IoSnafu {
+            message: format!("failed to connect to control socket {path:?}"),
+        })?;
+    stream
+        .write_all(format!("{command}\n").as_bytes())
+        .await
+        .with_context(|_| stnet::IoSnafu {
+            message: "failed to write control request",
+        })?;
+    let mut buf = Vec::new();
+    stream
+        .read_to_end(&mut buf)
+        .await
+        .with_context(|_| stnet::IoSnafu {
+            message: "failed to read status",
+        })?;
+    serde_json::from_slice(&buf).map_err(|e| stnet::Error::Io {
+        message: "invalid status".to_string(),
+        source: e.into(),
+        backtrace: snafu::Backtrace::capture(),
+    })
+}
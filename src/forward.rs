@@ -0,0 +1,133 @@
+//! The client's local forwards and visitors. Each one listens on a local
+//! address, and the connections accepted there are handed to the Client,
+//! which has the server open a connection to the forward's target, or splice
+//! them to the visitor's secret tunnel
+use crate::config::client as config;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+const BIND_RETRY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Something the client listens for locally
+pub trait Local: Clone + Eq + std::hash::Hash + Send + Sync + 'static {
+    fn local_addr(&self) -> SocketAddr;
+    // Where its connections go, for the logs
+    fn target(&self) -> &str;
+}
+
+impl Local for config::Forward {
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn target(&self) -> &str {
+        &self.target
+    }
+}
+
+impl Local for config::Visitor {
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn target(&self) -> &str {
+        &self.secret_name
+    }
+}
+
+/// A connection accepted on a local forward or visitor
+pub type Accepted<F> = (TcpStream, SocketAddr, F);
+
+pub struct Forwards<F: Local> {
+    token: CancellationToken,
+    // Stops the listener of each forward
+    listeners: HashMap<F, CancellationToken>,
+    tx: mpsc::Sender<Accepted<F>>,
+}
+
+impl<F: Local> Forwards<F> {
+    /// Connections accepted on any forward are sent to `tx`. Listeners run
+    /// until `token` is cancelled or this is dropped
+    pub fn new(token: CancellationToken, tx: mpsc::Sender<Accepted<F>>) -> Self {
+        Forwards {
+            token: token.child_token(),
+            listeners: HashMap::new(),
+            tx,
+        }
+    }
+
+    /// Start listening for the forwards that aren't listened for yet, and
+    /// stop listening for the ones that are gone
+    pub fn sync<'a>(&mut self, forwards: impl Iterator<Item = &'a F>) {
+        let forwards: Vec<_> = forwards.collect();
+        self.listeners.retain(|f, token| {
+            let keep = forwards.contains(&f);
+            if !keep {
+                token.cancel();
+            }
+            keep
+        });
+        for f in forwards {
+            if self.listeners.contains_key(f) {
+                continue;
+            }
+            let token = self.token.child_token();
+            tokio::spawn(listen(f.clone(), self.tx.clone(), token.clone()));
+            self.listeners.insert(f.clone(), token);
+        }
+    }
+}
+
+impl<F: Local> Drop for Forwards<F> {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+#[tracing::instrument(name = "Forward", level = "info", skip_all, fields(local_addr = %forward.local_addr(), target = forward.target()))]
+async fn listen<F: Local>(forward: F, tx: mpsc::Sender<Accepted<F>>, token: CancellationToken) {
+    // The address may still be held by the listener of the Client that had
+    // this forward before us, so keep trying
+    let mut logged = false;
+    let listener = loop {
+        match TcpListener::bind(forward.local_addr()).await {
+            Ok(l) => break l,
+            Err(e) if !logged => {
+                error!(cause = ?e, "failed to listen for forward. Retrying");
+                logged = true;
+            }
+            Err(_) => (),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(BIND_RETRY) => (),
+            _ = token.cancelled() => return,
+        }
+    };
+    info!("listening for forward");
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = token.cancelled() => return,
+        };
+        match accepted {
+            Err(e) => {
+                error!(cause = ?e, "failed to accept connection");
+                // This typically means an exhaustion of ports, so give it a
+                // few seconds
+                tokio::select! {
+                    _ = tokio::time::sleep(std::time::Duration::from_secs(10)) => (),
+                    _ = token.cancelled() => return,
+                }
+            }
+            Ok((s, addr)) => {
+                if tx.send((s, addr, forward.clone())).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
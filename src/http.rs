@@ -0,0 +1,159 @@
+//! Just enough HTTP/1.1 to serve the small local endpoints (metrics, etc)
+//! without pulling in a full web framework.
+use crate::net as stnet;
+use snafu::ResultExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Request heads larger than this are rejected outright
+pub const MAX_HEAD_LEN: usize = 8192;
+/// Requests with more headers than this are rejected, here and by the HTTP
+/// tunnels alike
+pub const MAX_HEADERS: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The `Host` header, lowercased and without a port or trailing dot
+    pub fn host(&self) -> Option<String> {
+        let host = self.header("host")?.trim();
+        // ipv6 addresses are bracketed, so any other colon starts the port
+        let host = match host.strip_prefix('[') {
+            Some(rest) => &rest[..rest.find(']')?],
+            None => host.split(':').next().unwrap_or(host),
+        };
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if host.is_empty() {
+            return None;
+        }
+        Some(host)
+    }
+}
+
+/// Parse a complete request head. Returns `Ok(None)` if `buf` does not yet
+/// contain the full head, and the number of bytes consumed otherwise.
+pub fn parse_request(buf: &[u8]) -> stnet::Result<Option<(Request, usize)>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let len = match req.parse(buf) {
+        Err(_) => return Err(stnet::Error::UnexpectedFrame),
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Ok(httparse::Status::Complete(len)) => len,
+    };
+    let headers = req
+        .headers
+        .iter()
+        .map(|h| {
+            (
+                h.name.to_string(),
+                String::from_utf8_lossy(h.value).into_owned(),
+            )
+        })
+        .collect();
+    Ok(Some((
+        Request {
+            method: req.method.unwrap_or_default().to_string(),
+            path: req.path.unwrap_or_default().to_string(),
+            headers,
+        },
+        len,
+    )))
+}
+
+/// Read a request head from `stream`. Any body is ignored.
+pub async fn read_request<T>(stream: &mut T) -> stnet::Result<Request>
+where
+    T: tokio::io::AsyncRead + Unpin,
+{
+    read_head(stream).await.map(|(req, _)| req)
+}
+
+/// Read a request head from `stream`, along with every byte read off of it
+/// so far, so that the request can be passed on as is.
+pub async fn read_head<T>(stream: &mut T) -> stnet::Result<(Request, Vec<u8>)>
+where
+    T: tokio::io::AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream
+            .read(&mut chunk)
+            .await
+            .with_context(|_| stnet::IoSnafu {
+                message: "failed to read http request",
+            })?;
+        if n == 0 {
+            return Err(stnet::Error::ConnectionDead);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some((req, _)) = parse_request(&buf)? {
+            return Ok((req, buf));
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(stnet::Error::UnexpectedFrame);
+        }
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Write a complete response and close the connection
+pub async fn write_response<T>(
+    stream: &mut T,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> stnet::Result<()>
+where
+    T: tokio::io::AsyncWrite + Unpin,
+{
+    // 204 responses must not carry a body or its headers
+    let head = if status == 204 {
+        format!(
+            "HTTP/1.1 {status} {}\r\nConnection: close\r\n\r\n",
+            reason(status)
+        )
+    } else {
+        format!(
+            "HTTP/1.1 {status} {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            reason(status),
+            body.len()
+        )
+    };
+    let mut out = head.into_bytes();
+    out.extend_from_slice(body);
+    stream
+        .write_all(&out)
+        .await
+        .with_context(|_| stnet::IoSnafu {
+            message: "failed to write http response",
+        })?;
+    stream.shutdown().await.with_context(|_| stnet::IoSnafu {
+        message: "failed to close http response",
+    })
+}
@@ -0,0 +1,744 @@
+//! HTTP-aware tunnels. Request heads from the External are rewritten to carry
+//! its address in `X-Forwarded-For`, `X-Forwarded-Proto` and `Forwarded`, and
+//! each request is written to the access log once its response is complete.
+//! Keep-alive connections are followed request by request, and upgraded
+//! connections (e.g. WebSocket) are passed through untouched once the
+//! Internal agrees to the upgrade.
+//! The server uses the same machinery to gate requests on their credentials.
+use crate::http::MAX_HEADERS;
+use crate::net as stnet;
+use futures::future::BoxFuture;
+use snafu::ResultExt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
+
+// Heads larger than this are rejected. Generous, since cookies add up
+const MAX_HEAD_LEN: usize = 64 * 1024;
+// Chunk size lines and trailers are short
+const MAX_LINE_LEN: usize = 4096;
+
+// The answer to a request whose body can't be told apart from what follows
+const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// The target requests are logged under
+pub const ACCESS_TARGET: &str = "nat_tunnel::access";
+
+// The External may be a proxy itself, so the External is added to the end of
+// whatever chain these already carry. Only the last entry is vouched for
+const CHAINED_HEADERS: [&str; 2] = ["x-forwarded-for", "forwarded"];
+// The scheme used on the hop to the server, which is ours to say
+const REPLACED_HEADERS: [&str; 1] = ["x-forwarded-proto"];
+
+/// The External an HTTP connection is for
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub external_addr: SocketAddr,
+    pub remote_port: u16,
+    // The scheme the External used to reach the server
+    pub proto: String,
+}
+
+/// Turns down requests that may not reach the Internal
+pub trait Gate: Send + Sync {
+    /// The response to send instead of passing on a request with these
+    /// headers, if it is turned down
+    fn refuse<'a>(&'a self, headers: &[httparse::Header]) -> BoxFuture<'a, Option<Vec<u8>>>;
+}
+
+/// What [`proxy`] does with each request on its way to the Internal
+#[derive(Clone)]
+pub enum Mode {
+    // Replace the forwarding headers and write the access log
+    Forward(Peer),
+    // Pass requests on untouched, unless the gate turns them down. The
+    // connection ends with the first one turned down
+    Gate(Arc<dyn Gate>),
+}
+
+// A request waiting for its response
+struct Pending {
+    method: String,
+    path: String,
+    started: Instant,
+    // Sent in place of a response, for a request that was turned down
+    refusal: Option<Vec<u8>>,
+    // For a request asking for an upgrade, told whether the Internal agreed
+    upgrade: Option<oneshot::Sender<bool>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Body {
+    None,
+    Length(u64),
+    Chunked,
+    // Delimited by the connection closing
+    UntilEof,
+}
+
+/// Pass HTTP/1.x between `external` and `internal` until either of them is
+/// done
+pub async fn proxy<E, I>(external: E, internal: I, mode: Mode) -> stnet::Result<()>
+where
+    E: AsyncRead + AsyncWrite + Unpin,
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    let (external_r, external_w) = tokio::io::split(external);
+    let (internal_r, internal_w) = tokio::io::split(internal);
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let requests = requests(Reader::new(external_r), internal_w, tx, &mode);
+    let responses = responses(Reader::new(internal_r), external_w, rx, &mode);
+    tokio::pin!(requests, responses);
+    tokio::select! {
+        // The External is done sending, but the Internal may still have
+        // answers for it
+        ret = &mut requests => {
+            ret?;
+            responses.await
+        }
+        ret = &mut responses => ret,
+    }
+}
+
+async fn requests<R, W>(
+    mut external: Reader<R>,
+    mut internal: W,
+    pending: mpsc::UnboundedSender<Pending>,
+    mode: &Mode,
+) -> stnet::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let Some(head) = external.head().await? else {
+            let _ = internal.shutdown().await;
+            return Ok(());
+        };
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        if !matches!(req.parse(&head), Ok(httparse::Status::Complete(_))) {
+            return Err(stnet::Error::UnexpectedFrame);
+        }
+        let method = req.method.unwrap_or_default().to_string();
+        let upgrade = method == "CONNECT" || header(req.headers, "upgrade").is_some();
+        // The Internal could find the body to end elsewhere than we do, and
+        // take what follows for a request of its own
+        let body = request_body(req.headers);
+
+        let refusal = match (body, mode) {
+            (None, _) => Some(BAD_REQUEST.to_vec()),
+            (Some(_), Mode::Forward(_)) => None,
+            (Some(_), Mode::Gate(gate)) => gate.refuse(req.headers).await,
+        };
+        let refused = refusal.is_some();
+        let (upgrade_tx, upgraded) = match upgrade {
+            true => {
+                let (tx, rx) = oneshot::channel();
+                (Some(tx), Some(rx))
+            }
+            false => (None, None),
+        };
+        // Queued first so that the response can't beat it
+        let _ = pending.send(Pending {
+            method,
+            path: req.path.unwrap_or_default().to_string(),
+            started: Instant::now(),
+            refusal,
+            upgrade: upgrade_tx,
+        });
+        if refused {
+            return Ok(());
+        }
+        match mode {
+            Mode::Forward(peer) => write(&mut internal, &rewrite(&req, peer)).await?,
+            Mode::Gate(_) => write(&mut internal, &head).await?,
+        }
+        // Only requests with a body we can find the end of get this far
+        external
+            .copy_body(body.unwrap_or(Body::None), &mut internal)
+            .await?;
+
+        // Nothing more may be sent until the Internal answered. Once it
+        // agrees, the connection is no longer HTTP and is passed through as
+        // is. Should it refuse, the next request is handled like any other
+        if let Some(upgraded) = upgraded {
+            match upgraded.await {
+                Ok(true) => {
+                    external.copy_to_end(&mut internal).await?;
+                    let _ = internal.shutdown().await;
+                    return Ok(());
+                }
+                Ok(false) => {}
+                // The Internal hung up without answering
+                Err(_) => {
+                    let _ = internal.shutdown().await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn responses<R, W>(
+    mut internal: Reader<R>,
+    mut external: W,
+    mut pending: mpsc::UnboundedReceiver<Pending>,
+    mode: &Mode,
+) -> stnet::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let req = tokio::select! {
+            biased;
+            req = pending.recv() => req,
+            // Anything from the Internal while no request is waiting is
+            // either it hanging up on an idle connection, or a response
+            // nobody asked for
+            n = internal.fill(), if internal.buf.is_empty() => match n? {
+                0 => {
+                    let _ = external.shutdown().await;
+                    return Ok(());
+                }
+                _ => None,
+            },
+        };
+        let Some(mut req) = req else {
+            // Nothing left to answer, or a response nobody asked for. Stop
+            // looking
+            internal.copy_to_end(&mut external).await?;
+            let _ = external.shutdown().await;
+            return Ok(());
+        };
+        if let Some(refusal) = req.refusal {
+            write(&mut external, &refusal).await?;
+            let _ = external.shutdown().await;
+            return Ok(());
+        }
+
+        // Interim responses come ahead of the real one
+        let (status, body, upgraded) = loop {
+            let Some(head) = internal.head().await? else {
+                let _ = external.shutdown().await;
+                return Ok(());
+            };
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut resp = httparse::Response::new(&mut headers);
+            if !matches!(resp.parse(&head), Ok(httparse::Status::Complete(_))) {
+                return Err(stnet::Error::UnexpectedFrame);
+            }
+            let status = resp.code.unwrap_or_default();
+            write(&mut external, &head).await?;
+            if (100..200).contains(&status) && status != 101 {
+                continue;
+            }
+
+            let upgraded =
+                status == 101 || (req.method == "CONNECT" && (200..300).contains(&status));
+            let body = if upgraded || req.method == "HEAD" || status == 204 || status == 304 {
+                Body::None
+            } else {
+                match (
+                    header(resp.headers, "transfer-encoding"),
+                    header(resp.headers, "content-length"),
+                ) {
+                    (Some(te), _) if is_chunked(te) => Body::Chunked,
+                    (Some(_), _) => Body::UntilEof,
+                    (None, Some(len)) => Body::Length(parse_length(len)?),
+                    (None, None) => Body::UntilEof,
+                }
+            };
+            break (status, body, upgraded);
+        };
+        if let Some(tx) = req.upgrade.take() {
+            let _ = tx.send(upgraded);
+        }
+        let bytes = internal.copy_body(body, &mut external).await?;
+        if let Mode::Forward(peer) = mode {
+            info!(
+                target: ACCESS_TARGET,
+                external_addr = %peer.external_addr,
+                remote_port = peer.remote_port,
+                method = req.method,
+                path = req.path,
+                status = status,
+                bytes = bytes,
+                latency_ms = req.started.elapsed().as_millis() as u64,
+                "request"
+            );
+        }
+
+        if upgraded {
+            internal.copy_to_end(&mut external).await?;
+        }
+        if upgraded || body == Body::UntilEof {
+            let _ = external.shutdown().await;
+            return Ok(());
+        }
+    }
+}
+
+// The request head with the External added to the forwarding headers
+fn rewrite(req: &httparse::Request, peer: &Peer) -> Vec<u8> {
+    let mut out = format!(
+        "{} {} HTTP/1.{}\r\n",
+        req.method.unwrap_or_default(),
+        req.path.unwrap_or_default(),
+        req.version.unwrap_or(1)
+    )
+    .into_bytes();
+    // The chains so far, in the order they were sent
+    let mut chains: [Vec<&[u8]>; 2] = Default::default();
+    for h in req.headers.iter() {
+        if let Some(i) = CHAINED_HEADERS
+            .iter()
+            .position(|f| h.name.eq_ignore_ascii_case(f))
+        {
+            let value = h.value.trim_ascii();
+            if !value.is_empty() {
+                chains[i].push(value);
+            }
+            continue;
+        }
+        if REPLACED_HEADERS
+            .iter()
+            .any(|f| h.name.eq_ignore_ascii_case(f))
+        {
+            continue;
+        }
+        out.extend_from_slice(h.name.as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(h.value);
+        out.extend_from_slice(b"\r\n");
+    }
+
+    let ip = peer.external_addr.ip().to_canonical();
+    // RFC 7239 wants ipv6 nodes bracketed and quoted
+    let node = match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    };
+    let proto = &peer.proto;
+    let [xff, forwarded] = chains;
+    chained(&mut out, "X-Forwarded-For", xff, &ip.to_string());
+    out.extend_from_slice(format!("X-Forwarded-Proto: {proto}\r\n").as_bytes());
+    chained(
+        &mut out,
+        "Forwarded",
+        forwarded,
+        &format!("for={node};proto={proto}"),
+    );
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+// A header line with `ours` at the end of the chain in `values`
+fn chained(out: &mut Vec<u8>, name: &str, values: Vec<&[u8]>, ours: &str) {
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(b": ");
+    for v in values {
+        out.extend_from_slice(v);
+        out.extend_from_slice(b", ");
+    }
+    out.extend_from_slice(ours.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+fn header<'a>(headers: &'a [httparse::Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .map(str::trim)
+}
+
+// Where the body of a request with these headers ends, if that is beyond
+// doubt (RFC 9112 6.3): there is no Content-Length next to a
+// Transfer-Encoding, and every Content-Length says the same
+fn request_body(headers: &[httparse::Header]) -> Option<Body> {
+    let values = |name: &str| -> Option<Vec<&str>> {
+        headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| std::str::from_utf8(h.value).ok())
+            .collect::<Option<Vec<_>>>()
+    };
+    let codings = values("transfer-encoding")?;
+    let lengths = values("content-length")?;
+    match (codings.is_empty(), lengths.is_empty()) {
+        (true, true) => Some(Body::None),
+        (false, true) if is_chunked(&codings.join(",")) => Some(Body::Chunked),
+        (false, _) => None,
+        (true, false) => {
+            let mut lengths = lengths.iter().flat_map(|l| l.split(','));
+            let len = parse_length(lengths.next()?.trim()).ok()?;
+            lengths
+                .all(|l| parse_length(l.trim()).ok() == Some(len))
+                .then_some(Body::Length(len))
+        }
+    }
+}
+
+// Chunked has to be the last coding if it is there at all
+fn is_chunked(transfer_encoding: &str) -> bool {
+    transfer_encoding
+        .rsplit(',')
+        .next()
+        .is_some_and(|c| c.trim().eq_ignore_ascii_case("chunked"))
+}
+
+fn parse_length(len: &str) -> stnet::Result<u64> {
+    len.parse().map_err(|_| stnet::Error::UnexpectedFrame)
+}
+
+async fn write<W: AsyncWrite + Unpin>(w: &mut W, buf: &[u8]) -> stnet::Result<()> {
+    w.write_all(buf).await.with_context(|_| stnet::IoSnafu {
+        message: "failed to write http",
+    })
+}
+
+// A read half with whatever was read past the last head or line
+struct Reader<R> {
+    inner: R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Reader<R> {
+    fn new(inner: R) -> Self {
+        Reader {
+            inner,
+            buf: Vec::with_capacity(4096),
+        }
+    }
+
+    // Read more into `buf`. Returns 0 at EOF
+    async fn fill(&mut self) -> stnet::Result<usize> {
+        let mut chunk = [0u8; 4096];
+        let n = self
+            .inner
+            .read(&mut chunk)
+            .await
+            .with_context(|_| stnet::IoSnafu {
+                message: "failed to read http",
+            })?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    // Everything up to and including `delim`, which must show up within
+    // `limit` bytes. Returns None if the stream ends cleanly before anything
+    async fn until(&mut self, delim: &[u8], limit: usize) -> stnet::Result<Option<Vec<u8>>> {
+        let mut searched = 0;
+        loop {
+            if let Some(i) = self.buf[searched..]
+                .windows(delim.len())
+                .position(|w| w == delim)
+            {
+                let end = searched + i + delim.len();
+                return Ok(Some(self.buf.drain(..end).collect()));
+            }
+            if self.buf.len() > limit {
+                return Err(stnet::Error::UnexpectedFrame);
+            }
+            searched = self.buf.len().saturating_sub(delim.len() - 1);
+            if self.fill().await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(stnet::Error::ConnectionDead);
+            }
+        }
+    }
+
+    async fn head(&mut self) -> stnet::Result<Option<Vec<u8>>> {
+        // Stray empty lines between messages are allowed
+        while self.buf.starts_with(b"\r\n") {
+            self.buf.drain(..2);
+        }
+        self.until(b"\r\n\r\n", MAX_HEAD_LEN).await
+    }
+
+    async fn line(&mut self) -> stnet::Result<Vec<u8>> {
+        self.until(b"\r\n", MAX_LINE_LEN)
+            .await?
+            .ok_or(stnet::Error::ConnectionDead)
+    }
+
+    // Pass a body on to `w`. Returns the size of the payload
+    async fn copy_body<W: AsyncWrite + Unpin>(
+        &mut self,
+        body: Body,
+        w: &mut W,
+    ) -> stnet::Result<u64> {
+        match body {
+            Body::None => Ok(0),
+            Body::Length(n) => {
+                self.copy_exact(n, w).await?;
+                Ok(n)
+            }
+            Body::Chunked => {
+                let mut total = 0;
+                loop {
+                    let line = self.line().await?;
+                    write(w, &line).await?;
+                    let size = std::str::from_utf8(&line)
+                        .ok()
+                        .and_then(|l| l.trim().split(';').next())
+                        .and_then(|s| u64::from_str_radix(s.trim(), 16).ok())
+                        .ok_or(stnet::Error::UnexpectedFrame)?;
+                    if size == 0 {
+                        break;
+                    }
+                    // The chunk and its CRLF
+                    self.copy_exact(size + 2, w).await?;
+                    total += size;
+                }
+                // Trailers, up to an empty line
+                loop {
+                    let line = self.line().await?;
+                    write(w, &line).await?;
+                    if line == b"\r\n" {
+                        return Ok(total);
+                    }
+                }
+            }
+            Body::UntilEof => self.copy_to_end(w).await,
+        }
+    }
+
+    async fn copy_exact<W: AsyncWrite + Unpin>(
+        &mut self,
+        mut n: u64,
+        w: &mut W,
+    ) -> stnet::Result<()> {
+        while n > 0 {
+            if self.buf.is_empty() && self.fill().await? == 0 {
+                return Err(stnet::Error::ConnectionDead);
+            }
+            let take = std::cmp::min(n, self.buf.len() as u64) as usize;
+            write(w, &self.buf[..take]).await?;
+            self.buf.drain(..take);
+            n -= take as u64;
+        }
+        Ok(())
+    }
+
+    async fn copy_to_end<W: AsyncWrite + Unpin>(&mut self, w: &mut W) -> stnet::Result<u64> {
+        let buffered = self.buf.len() as u64;
+        write(w, &self.buf).await?;
+        self.buf.clear();
+        let n = tokio::io::copy(&mut self.inner, w)
+            .await
+            .with_context(|_| stnet::IoSnafu {
+                message: "failed to pass on http",
+            })?;
+        Ok(buffered + n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(addr: &str) -> Peer {
+        Peer {
+            external_addr: addr.parse().unwrap(),
+            remote_port: 8080,
+            proto: "https".to_string(),
+        }
+    }
+
+    fn rewritten(head: &[u8], peer: &Peer) -> String {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        assert!(matches!(req.parse(head), Ok(httparse::Status::Complete(_))));
+        String::from_utf8(rewrite(&req, peer)).unwrap()
+    }
+
+    #[test]
+    fn adds_forwarding_headers() {
+        let head = rewritten(
+            b"GET / HTTP/1.1\r\nHost: x\r\n\r\n",
+            &peer("192.0.2.1:5000"),
+        );
+        assert_eq!(
+            head,
+            "GET / HTTP/1.1\r\nHost: x\r\nX-Forwarded-For: 192.0.2.1\r\nX-Forwarded-Proto: https\r\nForwarded: for=192.0.2.1;proto=https\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn appends_to_chains() {
+        let head = rewritten(
+            b"GET / HTTP/1.1\r\nx-forwarded-for: 6.6.6.6\r\nHost: x\r\nX-Forwarded-For:  7.7.7.7 \r\nForwarded: for=6.6.6.6\r\n\r\n",
+            &peer("192.0.2.1:5000"),
+        );
+        assert!(
+            head.contains("\r\nX-Forwarded-For: 6.6.6.6, 7.7.7.7, 192.0.2.1\r\n"),
+            "{head}"
+        );
+        assert!(
+            head.contains("\r\nForwarded: for=6.6.6.6, for=192.0.2.1;proto=https\r\n"),
+            "{head}"
+        );
+        assert_eq!(head.matches("X-Forwarded-For").count(), 1, "{head}");
+        assert!(!head.contains("x-forwarded-for"), "{head}");
+    }
+
+    #[test]
+    fn replaces_proto() {
+        let head = rewritten(
+            b"GET / HTTP/1.1\r\nX-Forwarded-Proto: ftp\r\nHost: x\r\n\r\n",
+            &peer("192.0.2.1:5000"),
+        );
+        assert!(!head.contains("ftp"), "{head}");
+        assert!(head.contains("\r\nX-Forwarded-Proto: https\r\n"), "{head}");
+    }
+
+    #[test]
+    fn quotes_ipv6_nodes() {
+        let head = rewritten(b"GET / HTTP/1.1\r\n\r\n", &peer("[2001:db8::1]:5000"));
+        assert!(head.contains("X-Forwarded-For: 2001:db8::1\r\n"), "{head}");
+        assert!(
+            head.contains("Forwarded: for=\"[2001:db8::1]\";proto=https\r\n"),
+            "{head}"
+        );
+        let head = rewritten(b"GET / HTTP/1.1\r\n\r\n", &peer("[::ffff:192.0.2.1]:5000"));
+        assert!(head.contains("Forwarded: for=192.0.2.1;"), "{head}");
+    }
+
+    // What the Internal got, after it answered every request with `answers`
+    async fn internal_sees(requests: &[u8], answers: &'static [u8]) -> String {
+        let (mut external, theirs) = tokio::io::duplex(64 * 1024);
+        let (ours, mut internal) = tokio::io::duplex(64 * 1024);
+        let proxy = tokio::spawn(proxy(theirs, ours, Mode::Forward(peer("192.0.2.1:5000"))));
+        external.write_all(requests).await.unwrap();
+        external.shutdown().await.unwrap();
+
+        let mut got = Vec::new();
+        let mut buf = [0u8; 4096];
+        let mut answered = 0;
+        loop {
+            let n = internal.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            got.extend_from_slice(&buf[..n]);
+            // One answer per head, once it's complete
+            while got.windows(4).filter(|w| w == b"\r\n\r\n").count() > answered {
+                internal.write_all(answers).await.unwrap();
+                answered += 1;
+            }
+        }
+        drop(internal);
+        proxy.await.unwrap().unwrap();
+        String::from_utf8(got).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rewrites_after_refused_upgrade() {
+        let got = internal_sees(
+            b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\nGET /next HTTP/1.1\r\nX-Forwarded-For: 6.6.6.6\r\n\r\n",
+            b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        let next = &got[got.find("GET /next").unwrap()..];
+        assert!(
+            next.contains("X-Forwarded-For: 6.6.6.6, 192.0.2.1\r\n"),
+            "{got}"
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_through_once_upgraded() {
+        let got = internal_sees(
+            b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\nGET /next HTTP/1.1\r\nX-Forwarded-For: 6.6.6.6\r\n\r\n",
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n",
+        )
+        .await;
+        assert!(
+            got.ends_with("\r\n\r\nGET /next HTTP/1.1\r\nX-Forwarded-For: 6.6.6.6\r\n\r\n"),
+            "{got}"
+        );
+    }
+
+    // Lets every request through
+    struct Open;
+
+    impl Gate for Open {
+        fn refuse<'a>(&'a self, _: &[httparse::Header]) -> BoxFuture<'a, Option<Vec<u8>>> {
+            Box::pin(async { None })
+        }
+    }
+
+    // What the External and the Internal got, after `request` went through
+    // an open gate and the Internal answered every head with a 200
+    async fn through_gate(request: &[u8]) -> (String, String) {
+        let (mut external, theirs) = tokio::io::duplex(64 * 1024);
+        let (ours, mut internal) = tokio::io::duplex(64 * 1024);
+        let proxy = tokio::spawn(proxy(theirs, ours, Mode::Gate(Arc::new(Open))));
+        external.write_all(request).await.unwrap();
+        external.shutdown().await.unwrap();
+
+        let mut got = Vec::new();
+        internal.read_to_end(&mut got).await.unwrap();
+        let heads = got.windows(4).filter(|w| w == b"\r\n\r\n").count();
+        for _ in 0..heads {
+            internal
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        }
+        drop(internal);
+        proxy.await.unwrap().unwrap();
+        let mut answer = Vec::new();
+        external.read_to_end(&mut answer).await.unwrap();
+        (
+            String::from_utf8(answer).unwrap(),
+            String::from_utf8(got).unwrap(),
+        )
+    }
+
+    const SMUGGLED: &str = "GET /admin HTTP/1.1\r\n\r\n";
+
+    async fn refuses_ambiguous(head: &str) {
+        let (answer, internal) = through_gate(format!("{head}{SMUGGLED}").as_bytes()).await;
+        assert!(answer.starts_with("HTTP/1.1 400 "), "{answer}");
+        assert!(internal.is_empty(), "{internal}");
+    }
+
+    #[tokio::test]
+    async fn refuses_chunked_with_length() {
+        refuses_ambiguous(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 25\r\n\r\n0\r\n\r\n",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn refuses_differing_lengths() {
+        refuses_ambiguous("POST / HTTP/1.1\r\nContent-Length: 0\r\nContent-Length: 25\r\n\r\n")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn refuses_differing_length_list() {
+        refuses_ambiguous("POST / HTTP/1.1\r\nContent-Length: 0, 25\r\n\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn accepts_repeated_length() {
+        let (answer, internal) =
+            through_gate(b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nhi")
+                .await;
+        assert!(answer.starts_with("HTTP/1.1 200 "), "{answer}");
+        assert!(internal.ends_with("\r\n\r\nhi"), "{internal}");
+    }
+}
@@ -1,10 +1,22 @@
+pub mod backends;
 pub mod client;
 pub mod config;
+pub mod control;
 mod error;
+pub mod forward;
+pub mod http;
+pub mod http_tunnel;
+pub mod logging;
+pub mod metrics;
 pub mod net;
+pub mod otel;
+pub mod proxy_protocol;
 pub mod race;
 pub mod redirector;
+pub mod retry;
 pub mod server;
+pub mod sni;
+pub mod systemd;
 pub mod tls_self_signed;
 
 pub use error::{Error, Result};
@@ -0,0 +1,119 @@
+//! Sets up where the logs of either binary go, according to their
+//! [`Logging`] config. RUST_LOG, when set, takes precedence over the levels
+//! in the config
+use crate::config::{self, LogFormat, Logging};
+use crate::otel::Telemetry;
+use snafu::ResultExt;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{
+    filter::Targets, fmt::writer::BoxMakeWriter, layer::SubscriberExt, registry::LookupSpan,
+    util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+/// The access log of a binary
+pub struct AccessLog<'a> {
+    /// The target its lines are logged under, at the info level
+    pub target: &'a str,
+    /// The file it goes to. Along with the rest of the logs if None
+    pub file: Option<&'a Path>,
+}
+
+/// Install the global subscriber. Must only be called once. Access log
+/// lines are written whatever the levels say. Spans are also exported
+/// through `telemetry` if given, at the same levels
+pub fn init(
+    cfg: &Logging,
+    access_log: Option<AccessLog>,
+    telemetry: Option<&Telemetry>,
+) -> crate::Result<()> {
+    let mut filter = env_filter(cfg)?;
+    let writer = match cfg.file {
+        None => None,
+        Some(ref path) => Some(open(path)?),
+    };
+    let access = match access_log {
+        None => None,
+        Some(AccessLog { target, file: None }) => {
+            filter = filter.add_directive(directive(target, "info")?);
+            None
+        }
+        Some(AccessLog {
+            target,
+            file: Some(path),
+        }) => {
+            filter = filter.add_directive(directive(target, "off")?);
+            let only_access = Targets::new().with_target(target, LevelFilter::INFO);
+            Some(layer(cfg.format, Some(open(path)?)).with_filter(only_access))
+        }
+    };
+    let exported = match telemetry {
+        None => None,
+        Some(t) => Some(t.layer().with_filter(env_filter(cfg)?)),
+    };
+    tracing_subscriber::registry()
+        .with(layer(cfg.format, writer).with_filter(filter))
+        .with(access)
+        .with(exported)
+        .init();
+    Ok(())
+}
+
+fn directive(target: &str, level: &str) -> config::Result<tracing_subscriber::filter::Directive> {
+    format!("{target}={level}")
+        .parse()
+        .map_err(|e| config::Error::Invalid {
+            message: format!("bad access log target {target:?}: {e}"),
+        })
+}
+
+fn env_filter(cfg: &Logging) -> config::Result<EnvFilter> {
+    match std::env::var("RUST_LOG") {
+        Ok(directives) if !directives.is_empty() => {
+            Ok(EnvFilter::builder().parse_lossy(directives))
+        }
+        _ => EnvFilter::builder()
+            .parse(cfg.directives())
+            .map_err(|e| config::Error::Invalid {
+                message: format!("bad logging levels: {e}"),
+            }),
+    }
+}
+
+/// Run `f` logging the default way, for the time before the config is
+/// known
+pub fn with_default<T>(f: impl FnOnce() -> T) -> T {
+    tracing::subscriber::with_default(tracing_subscriber::fmt().finish(), f)
+}
+
+// Formats lines as `format`, to stdout unless given a file
+fn layer<S>(
+    format: LogFormat,
+    file: Option<Mutex<std::fs::File>>,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    let ansi = file.is_none();
+    let writer = match file {
+        None => BoxMakeWriter::new(std::io::stdout),
+        Some(file) => BoxMakeWriter::new(file),
+    };
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+fn open(path: &Path) -> config::Result<Mutex<std::fs::File>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|_| config::IoSnafu {
+            message: format!("failed to open log file {}", path.display()),
+        })?;
+    Ok(Mutex::new(file))
+}
@@ -0,0 +1,218 @@
+//! Prometheus metrics shared by sts and stc. Each process only ever has one
+//! set of metrics, so they live in a global rather than being threaded
+//! through every constructor.
+use crate::net as stnet;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use snafu::ResultExt;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use tokio::net as tnet;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, trace};
+
+const NAMESPACE: &str = "nat_tunnel";
+
+pub struct Metrics {
+    registry: Registry,
+    /// Clients currently connected to the server (server only)
+    pub connected_clients: IntGauge,
+    /// Tunnels (remote_ports) currently held open
+    pub active_tunnels: IntGauge,
+    /// Live tunneled connections by remote_port
+    pub active_connections: IntGaugeVec,
+    /// Bytes moved through a tunnel by remote_port. `direction="in"` counts
+    /// bytes read from the local socket (the External on the server, the
+    /// Internal on the client); `direction="out"` counts bytes written to it
+    pub tunnel_bytes: IntCounterVec,
+    /// Failed client/server handshakes by reason
+    pub handshake_failures: IntCounterVec,
+    /// Time between sending a heartbeat and receiving the reply
+    pub heartbeat_rtt: Histogram,
+    /// Number of queued frames in the core channels, by channel name and peer
+    pub channel_occupancy: IntGaugeVec,
+    /// How long each redirector lived, by remote_port
+    pub redirector_lifetime: HistogramVec,
+    /// 1 if a health checked backend passes its checks, 0 if not (client only)
+    pub backend_healthy: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let connected_clients = IntGauge::with_opts(
+            Opts::new("connected_clients", "Number of connected clients").namespace(NAMESPACE),
+        )
+        .unwrap();
+        let active_tunnels = IntGauge::with_opts(
+            Opts::new("active_tunnels", "Number of open tunnels").namespace(NAMESPACE),
+        )
+        .unwrap();
+        let active_connections = IntGaugeVec::new(
+            Opts::new("active_connections", "Number of live tunneled connections")
+                .namespace(NAMESPACE),
+            &["remote_port"],
+        )
+        .unwrap();
+        let tunnel_bytes = IntCounterVec::new(
+            Opts::new("tunnel_bytes_total", "Bytes moved through a tunnel").namespace(NAMESPACE),
+            &["remote_port", "direction"],
+        )
+        .unwrap();
+        let handshake_failures = IntCounterVec::new(
+            Opts::new("handshake_failures_total", "Failed handshakes").namespace(NAMESPACE),
+            &["reason"],
+        )
+        .unwrap();
+        let heartbeat_rtt = Histogram::with_opts(
+            HistogramOpts::new("heartbeat_rtt_seconds", "Heartbeat round trip time")
+                .namespace(NAMESPACE)
+                .buckets(vec![
+                    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+                ]),
+        )
+        .unwrap();
+        let channel_occupancy = IntGaugeVec::new(
+            Opts::new("channel_occupancy", "Frames queued in a core channel").namespace(NAMESPACE),
+            &["channel", "peer"],
+        )
+        .unwrap();
+        let redirector_lifetime = HistogramVec::new(
+            HistogramOpts::new(
+                "redirector_lifetime_seconds",
+                "Lifetime of tunneled connections",
+            )
+            .namespace(NAMESPACE)
+            .buckets(vec![
+                0.01, 0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0, 14400.0,
+            ]),
+            &["remote_port"],
+        )
+        .unwrap();
+
+        let backend_healthy = IntGaugeVec::new(
+            Opts::new(
+                "backend_healthy",
+                "Whether a backend passes its health checks",
+            )
+            .namespace(NAMESPACE),
+            &["tunnel", "backend"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(connected_clients.clone()))
+            .unwrap();
+        registry.register(Box::new(active_tunnels.clone())).unwrap();
+        registry
+            .register(Box::new(active_connections.clone()))
+            .unwrap();
+        registry.register(Box::new(tunnel_bytes.clone())).unwrap();
+        registry
+            .register(Box::new(handshake_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(heartbeat_rtt.clone())).unwrap();
+        registry
+            .register(Box::new(channel_occupancy.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(redirector_lifetime.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(backend_healthy.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            connected_clients,
+            active_tunnels,
+            active_connections,
+            tunnel_bytes,
+            handshake_failures,
+            heartbeat_rtt,
+            channel_occupancy,
+            redirector_lifetime,
+            backend_healthy,
+        }
+    }
+
+    /// The current value of every metric
+    pub fn gather(&self) -> Vec<prometheus::proto::MetricFamily> {
+        self.registry.gather()
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let encoder = TextEncoder::new();
+        // Encoding into a Vec cannot fail
+        let _ = encoder.encode(&self.registry.gather(), &mut buf);
+        buf
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Serve `GET /metrics` on `addr` until `token` is cancelled
+#[tracing::instrument(name = "Metrics", level = "info", skip_all)]
+pub async fn serve(addr: SocketAddr, token: CancellationToken) -> stnet::Result<()> {
+    let listener = tnet::TcpListener::bind(addr)
+        .await
+        .with_context(|_| stnet::IoSnafu {
+            message: format!("failed to bind metrics listener {addr}"),
+        })?;
+    info!("serving metrics on http://{addr}/metrics");
+
+    loop {
+        let (mut stream, peer) = tokio::select! {
+            maybe_accept = listener.accept() => match maybe_accept {
+                Err(e) => {
+                    error!(cause = ?e, "failed to accept metrics connection");
+                    continue;
+                }
+                Ok(s) => s,
+            },
+            _ = token.cancelled() => return Ok(()),
+        };
+
+        tokio::spawn(async move {
+            let req = match tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                crate::http::read_request(&mut stream),
+            )
+            .await
+            {
+                Ok(Ok(req)) => req,
+                _ => {
+                    trace!(peer = ?peer, "bad metrics request");
+                    return;
+                }
+            };
+            let ret = match (req.method.as_str(), req.path.as_str()) {
+                ("GET", "/metrics") => {
+                    crate::http::write_response(
+                        &mut stream,
+                        200,
+                        "text/plain; version=0.0.4",
+                        &metrics().render(),
+                    )
+                    .await
+                }
+                _ => {
+                    crate::http::write_response(&mut stream, 404, "text/plain", b"not found\n")
+                        .await
+                }
+            };
+            if let Err(e) = ret {
+                trace!(cause = ?e, peer = ?peer, "failed to write metrics response");
+            }
+        });
+    }
+}
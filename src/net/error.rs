@@ -24,6 +24,12 @@ pub enum Error {
         source: std::io::Error,
         backtrace: snafu::Backtrace,
     },
+    #[snafu(display("failed to resolve {addr}"))]
+    Resolve {
+        addr: String,
+        source: std::io::Error,
+        backtrace: snafu::Backtrace,
+    },
     #[snafu(display("{context} timed out"))]
     IoTimeout {
         context: String,
@@ -80,12 +86,15 @@ impl Error {
                 #[allow(clippy::match_like_matches_macro)]
                 match source.kind() {
                     ConnectionReset | NetworkUnreachable | ConnectionAborted | NetworkDown
-                    | BrokenPipe => true,
+                    | BrokenPipe | ConnectionRefused | HostUnreachable | TimedOut => true,
                     Other => {
                         if let Some(e) = source.get_ref() {
                             if let Some(e) = e.downcast_ref::<quinn::ConnectionError>() {
                                 use quinn::ConnectionError::*;
-                                return matches!(e, Reset | TimedOut);
+                                return matches!(
+                                    e,
+                                    Reset | TimedOut | ConnectionClosed(_) | ApplicationClosed(_)
+                                );
                             }
                         }
                         false
@@ -97,10 +106,13 @@ impl Error {
                 use quinn::ConnectionError::*;
                 #[allow(clippy::match_like_matches_macro)]
                 match source {
-                    Reset | TimedOut => true,
+                    Reset | TimedOut | ConnectionClosed(_) | ApplicationClosed(_) => true,
                     _ => false,
                 }
             }
+            // The server may well be back in a moment, e.g. after a restart
+            // or a DNS blip
+            Resolve { .. } | ConnectionDead | IoTimeout { .. } => true,
             _ => false,
         }
     }
@@ -3,21 +3,56 @@ use std::net::SocketAddr;
 use std::ops::{Deref, DerefMut};
 use std::vec::Vec;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum RedirectorFrame {
-    StartListener(SocketAddr, u16),
+    // Sent by the server for a new External connected to the given address
+    // of the server on the given port, with the ID both ends log the
+    // connection under and the trace it is part of
+    StartListener(SocketAddr, SocketAddr, u16, ConnId, TraceContext),
     Datagram(Datagram),
     // Indicate that no further data will come from the sender.
     // i.e. HALF CLOSED
     KillListener(SocketAddr),
+    // Like StartListener, for an External that came in through one of the
+    // shared listeners on the given port, asking for the given Vhost
+    StartVhostListener(SocketAddr, SocketAddr, u16, Vhost, ConnId, TraceContext),
+    // Sent by the client for a connection accepted on one of its local
+    // forwards, asking the server to open one to the given "host:port"
+    StartForward(SocketAddr, String),
+    // Sent by the client for a connection accepted on one of its visitors,
+    // asking the server to splice it to the secret tunnel of the given name
+    // of another client, if the secret matches
+    StartVisit(SocketAddr, String, Secret),
 }
 
 impl RedirectorFrame {
     pub fn id(&self) -> &SocketAddr {
         match self {
-            RedirectorFrame::StartListener(id, _) => id,
+            RedirectorFrame::StartListener(id, ..) => id,
             RedirectorFrame::Datagram(d) => &d.id,
             RedirectorFrame::KillListener(id) => id,
+            RedirectorFrame::StartVhostListener(id, ..) => id,
+            RedirectorFrame::StartForward(id, _) => id,
+            RedirectorFrame::StartVisit(id, _, _) => id,
+        }
+    }
+
+    /// The ID of the External's connection, for the frames the server
+    /// starts one with
+    pub fn conn_id(&self) -> Option<ConnId> {
+        match self {
+            RedirectorFrame::StartListener(_, _, _, conn_id, _) => Some(*conn_id),
+            RedirectorFrame::StartVhostListener(_, _, _, _, conn_id, _) => Some(*conn_id),
+            _ => None,
+        }
+    }
+
+    /// Have the frames the server starts a connection with carry `trace`
+    pub fn set_trace(&mut self, trace: TraceContext) {
+        match self {
+            RedirectorFrame::StartListener(_, _, _, _, t) => *t = trace,
+            RedirectorFrame::StartVhostListener(_, _, _, _, _, t) => *t = trace,
+            _ => (),
         }
     }
 }
@@ -34,8 +69,130 @@ pub enum Frame {
     ListenerStart(SocketAddr),
     Redirector(RedirectorFrame),
     ListenerEnd(SocketAddr),
-    Kthxbai,
+    // Ends the session, with the reason why
+    Kthxbai(String),
     Heartbeat,
+    // Sent by the client to change its tunnels mid-session. The server
+    // responds with TunnelResults, with one result per requested port
+    AddTunnels(Vec<u16>),
+    RemoveTunnels(Vec<u16>),
+    TunnelResults(Vec<TunnelResult>),
+    // Sent by the server when it lost the External listener of a tunnel.
+    // The rest of the session is unaffected
+    TunnelClosed(u16, String),
+    // Like AddTunnels/RemoveTunnels, for hostnames served on the server's
+    // shared listeners
+    AddHosts(Vec<Vhost>),
+    RemoveHosts(Vec<Vhost>),
+    // Like AddTunnels, but joins the named pool of clients sharing each
+    // remote_port instead of holding it alone. Leaving is by RemoveTunnels
+    JoinPools(Vec<(u16, String)>),
+    // Sent by the client when the Internals of its tunnels go up or down,
+    // so that the server can turn Externals away while they are down
+    TunnelHealth(Vec<(u16, bool)>),
+    HostHealth(Vec<(Vhost, bool)>),
+    // Sent by the client to measure the round trip to the server, which
+    // answers each with a Pong right away
+    Ping,
+    Pong,
+    // Like AddHosts, for secret tunnels, each with the secret its visitors
+    // must present. Results come as HostAdded/HostRejected of a Secret
+    // Vhost, and leaving is by RemoveHosts
+    AddSecrets(Vec<(String, Secret)>),
+    // Sent by the server once the tunnels are set up, when it holds on to
+    // the session for a while after the connection drops
+    Session(super::SessionToken),
+    // Sent by the client instead of Tunnels to pick its session up over a
+    // new connection, with the number of Redirector frames it received over
+    // the session
+    Resume(super::SessionToken, u64),
+    // The server's answer to Resume, with the number of Redirector frames it
+    // received over the session. Each end then sends again what the other
+    // one is missing. None if the session is gone, after which the client
+    // starts a new one
+    Resumed(Option<u64>),
+    // Acknowledges receiving that many Redirector frames over the session
+    Ack(u64),
+    // Sent by the client to have the server stop sending it new Externals.
+    // The server ends the session with Kthxbai once the live connections
+    // are done
+    Drain,
+}
+
+/// Outcome of adding or removing a single tunnel
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TunnelResult {
+    Added(u16),
+    Removed(u16),
+    Rejected(u16, String),
+    // The server picked this port for a request of port 0
+    Assigned(u16),
+    HostAdded(Vhost),
+    HostRemoved(Vhost),
+    HostRejected(Vhost, String),
+}
+
+/// How a shared listener on the server picks the client for an External
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum VhostKind {
+    // By the Host header of an HTTP/1.x request
+    Http,
+    // By the server name of a TLS ClientHello, without terminating TLS
+    Sni,
+    // Not on a listener at all. Only reached by the visitors of other
+    // clients, by name
+    Secret,
+}
+
+impl VhostKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VhostKind::Http => "http",
+            VhostKind::Sni => "sni",
+            VhostKind::Secret => "secret",
+        }
+    }
+}
+
+/// Identifies an External connection in the logs of the server and of the
+/// client alike. Picked at random by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct ConnId(u64);
+
+impl ConnId {
+    pub fn random() -> Self {
+        use argon2::password_hash::rand_core::{OsRng, RngCore};
+        ConnId(OsRng.next_u64())
+    }
+}
+
+impl std::fmt::Display for ConnId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// The trace a connection is part of, as W3C Trace Context fields, so that
+/// the client can carry on with the trace the server started. Empty unless
+/// the server is built with the `otel` feature
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TraceContext(pub Vec<(String, String)>);
+
+/// A hostname served on one of the server's shared listeners
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub struct Vhost {
+    pub kind: VhostKind,
+    pub hostname: String,
+}
+
+impl std::fmt::Display for Vhost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            VhostKind::Http => write!(f, "http://{}", self.hostname),
+            VhostKind::Sni => write!(f, "sni://{}", self.hostname),
+            VhostKind::Secret => write!(f, "secret://{}", self.hostname),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -48,6 +205,31 @@ impl std::fmt::Debug for AuthKey {
     }
 }
 
+/// The secret of a secret tunnel
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Secret(String);
+
+// custom impl for the same reason as AuthKey
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Secret").finish()
+    }
+}
+
+impl Secret {
+    /// Whether `other` is the same secret, compared in constant time
+    pub fn matches(&self, other: &Secret) -> bool {
+        use subtle::ConstantTimeEq;
+        bool::from(self.0.as_bytes().ct_eq(other.0.as_bytes()))
+    }
+}
+
+impl From<String> for Secret {
+    fn from(item: String) -> Self {
+        Secret(item)
+    }
+}
+
 impl DerefMut for AuthKey {
     fn deref_mut(&mut self) -> &mut Vec<u8> {
         &mut self.0
@@ -81,7 +263,7 @@ impl std::convert::From<RedirectorFrame> for Frame {
 }
 
 /// Represents data that is being shuffled around from Client <-> Server
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Datagram {
     #[serde(rename = "i")]
     pub id: SocketAddr, // up to 16
@@ -111,6 +293,7 @@ fn set_tcp_user_timeout(_socket2: &socket2::Socket) -> std::io::Result<()> {
 }
 
 #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+#[allow(unused_variables)]
 fn set_tcp_user_timeout(socket2: &socket2::Socket) -> std::io::Result<()> {
     // TODO USER_TIMEOUT
     // https://blog.cloudflare.com/when-tcp-sockets-refuse-to-die/
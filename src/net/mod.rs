@@ -6,3 +6,9 @@ pub use error::*;
 
 mod transport;
 pub use transport::*;
+
+mod prefixed;
+pub use prefixed::*;
+
+mod session;
+pub use session::*;
@@ -0,0 +1,217 @@
+//! What it takes to resume a session over a new connection once the old one
+//! dropped. Both ends count the Redirector frames they send and receive over
+//! the session, acknowledge what they received every so often, and hold on
+//! to what they sent until it is acknowledged. When the client comes back,
+//! each end sends again whatever the other end is missing.
+use super::RedirectorFrame;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use tracing::warn;
+
+// How many Redirector frames may go unacknowledged before the session can't
+// be resumed anymore
+pub const REPLAY_LIMIT: usize = 4096;
+
+// Acknowledge every so many frames received, on top of every heartbeat
+const ACK_EVERY: u64 = 64;
+
+/// Handed to the client by the server, to resume its session with
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct SessionToken([u8; 16]);
+
+// custom impl for the same reason as AuthKey
+impl std::fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionToken").finish()
+    }
+}
+
+impl SessionToken {
+    pub fn generate() -> Self {
+        use argon2::password_hash::rand_core::{OsRng, RngCore};
+        let mut token = [0u8; 16];
+        OsRng.fill_bytes(&mut token);
+        SessionToken(token)
+    }
+}
+
+/// The Redirector frames of a session, counted both ways
+#[derive(Default)]
+pub struct Replay {
+    sent: u64,
+    received: u64,
+    // The count of received frames last acknowledged
+    acked: u64,
+    // The frames sent that weren't acknowledged yet, oldest first. None
+    // until the session is kept, and again once too many went unacknowledged
+    unacked: Option<VecDeque<RedirectorFrame>>,
+}
+
+impl Replay {
+    /// Hold on to the frames sent from now on, and acknowledge the ones
+    /// received
+    pub fn keep(&mut self) {
+        self.unacked = Some(VecDeque::new());
+    }
+
+    /// Whether the frames the other end may be missing are all still here
+    pub fn resumable(&self) -> bool {
+        self.unacked.is_some()
+    }
+
+    /// Frames received over the session so far
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Count a frame on its way to the other end
+    pub fn record_sent(&mut self, frame: &RedirectorFrame) {
+        self.sent += 1;
+        let Some(unacked) = self.unacked.as_mut() else {
+            return;
+        };
+        if unacked.len() >= REPLAY_LIMIT {
+            warn!(
+                limit = REPLAY_LIMIT,
+                "too many frames went unacknowledged. The session can't be resumed anymore"
+            );
+            self.unacked = None;
+            return;
+        }
+        unacked.push_back(frame.clone());
+    }
+
+    /// Count a frame from the other end. Returns the count to acknowledge,
+    /// if it is time to
+    pub fn record_received(&mut self) -> Option<u64> {
+        self.received += 1;
+        match self.received - self.acked >= ACK_EVERY {
+            true => self.ack(),
+            false => None,
+        }
+    }
+
+    /// The count to acknowledge, if any frames came in since the last time
+    pub fn ack(&mut self) -> Option<u64> {
+        if self.unacked.is_none() || self.received == self.acked {
+            return None;
+        }
+        self.acked = self.received;
+        Some(self.received)
+    }
+
+    /// Let go of the frames the other end acknowledged having received
+    /// `count` of
+    pub fn acknowledged(&mut self, count: u64) {
+        let Some(unacked) = self.unacked.as_mut() else {
+            return;
+        };
+        let first = self.sent - unacked.len() as u64;
+        let done = count.saturating_sub(first).min(unacked.len() as u64);
+        unacked.drain(..done as usize);
+    }
+
+    /// The frames to send again to the other end, which received `count`
+    /// of them, or None if some of those are gone
+    pub fn since(&self, count: u64) -> Option<Vec<RedirectorFrame>> {
+        let unacked = self.unacked.as_ref()?;
+        let first = self.sent - unacked.len() as u64;
+        if count < first || count > self.sent {
+            return None;
+        }
+        Some(
+            unacked
+                .iter()
+                .skip((count - first) as usize)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(n: u16) -> RedirectorFrame {
+        RedirectorFrame::KillListener(([127, 0, 0, 1], n).into())
+    }
+
+    fn port(frame: &RedirectorFrame) -> u16 {
+        match frame {
+            RedirectorFrame::KillListener(addr) => addr.port(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn resumable_up_to_the_limit() {
+        let mut replay = Replay::default();
+        replay.keep();
+        for n in 0..REPLAY_LIMIT {
+            replay.record_sent(&frame(n as u16));
+        }
+        assert!(replay.resumable());
+        let missed = replay.since(0).unwrap();
+        assert_eq!(missed.len(), REPLAY_LIMIT);
+        assert_eq!(port(&missed[0]), 0);
+
+        // One past it and what was held on to is gone
+        replay.record_sent(&frame(0));
+        assert!(!replay.resumable());
+        assert!(replay.since(0).is_none());
+        assert!(replay.since(REPLAY_LIMIT as u64 + 1).is_none());
+    }
+
+    #[test]
+    fn acknowledged_frames_make_room() {
+        let mut replay = Replay::default();
+        replay.keep();
+        for n in 0..REPLAY_LIMIT {
+            replay.record_sent(&frame(n as u16));
+        }
+        replay.acknowledged(1);
+        replay.record_sent(&frame(REPLAY_LIMIT as u16));
+        assert!(replay.resumable());
+
+        // The first frame is gone, the rest can still be sent again
+        assert!(replay.since(0).is_none());
+        let missed = replay.since(1).unwrap();
+        assert_eq!(missed.len(), REPLAY_LIMIT);
+        assert_eq!(port(&missed[0]), 1);
+        assert_eq!(port(missed.last().unwrap()), REPLAY_LIMIT as u16);
+        assert!(replay.since(REPLAY_LIMIT as u64 + 1).unwrap().is_empty());
+        assert!(replay.since(REPLAY_LIMIT as u64 + 2).is_none());
+    }
+
+    #[test]
+    fn acknowledging_too_much_is_harmless() {
+        let mut replay = Replay::default();
+        replay.keep();
+        for n in 0..3 {
+            replay.record_sent(&frame(n));
+        }
+        replay.acknowledged(10);
+        assert!(replay.since(3).unwrap().is_empty());
+        replay.acknowledged(1);
+        assert!(replay.since(3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn acks_every_so_often() {
+        let mut replay = Replay::default();
+        // Nothing is acknowledged until the session is kept
+        assert_eq!(replay.record_received(), None);
+        assert_eq!(replay.ack(), None);
+        replay.keep();
+        // The frame received before counts towards the first ack
+        for _ in 2..ACK_EVERY {
+            assert_eq!(replay.record_received(), None);
+        }
+        assert_eq!(replay.record_received(), Some(ACK_EVERY));
+        assert_eq!(replay.ack(), None);
+        replay.record_received();
+        assert_eq!(replay.ack(), Some(ACK_EVERY + 1));
+        assert_eq!(replay.received(), ACK_EVERY + 1);
+    }
+}
@@ -28,12 +28,27 @@ where
 pub trait Stream: tokio::io::AsyncWriteExt + tokio::io::AsyncReadExt + Sync + Send + Unpin {}
 impl<T: tokio::io::AsyncWriteExt + tokio::io::AsyncReadExt + Sync + Send + Unpin> Stream for T {}
 
+/// Any kind of Stream, for those that are held on to across connections
+/// that may not all be of the same kind
+pub type BoxedStream = Box<dyn Io>;
+pub trait Io: tokio::io::AsyncWrite + tokio::io::AsyncRead + Sync + Send + Unpin {}
+impl<T: tokio::io::AsyncWrite + tokio::io::AsyncRead + Sync + Send + Unpin> Io for T {}
+
 #[derive(Debug, Hash, Clone)]
 pub enum StreamId {
     Basic(SocketAddr),
     Quic(quinn::ConnectionId, quinn::StreamId, quinn::StreamId),
 }
 
+impl std::fmt::Display for StreamId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamId::Basic(addr) => write!(f, "{addr}"),
+            StreamId::Quic(cid, send, _) => write!(f, "{cid}:{}", send.index()),
+        }
+    }
+}
+
 impl From<SocketAddr> for StreamId {
     fn from(id: SocketAddr) -> Self {
         StreamId::Basic(id)
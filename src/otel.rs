@@ -0,0 +1,333 @@
+//! Exporting traces and metrics over OTLP/HTTP, per the `[otel]` config.
+//! Everything here is a no-op unless built with the `otel` feature
+use crate::config::Otel;
+use crate::net::TraceContext;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+#[cfg(feature = "otel")]
+use opentelemetry::trace::TracerProvider as _;
+#[cfg(feature = "otel")]
+use opentelemetry_otlp::WithExportConfig;
+#[cfg(feature = "otel")]
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The exporters. Dropping this flushes whatever hasn't been exported yet,
+/// so it should live until the process exits
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    tracer: opentelemetry_sdk::trace::SdkTracerProvider,
+    #[cfg(feature = "otel")]
+    meter: opentelemetry_sdk::metrics::SdkMeterProvider,
+    #[cfg(feature = "otel")]
+    bridge: tokio::task::JoinHandle<()>,
+}
+
+/// Start exporting according to `cfg`. `service` is the service.name used
+/// unless the config has one
+pub fn init(cfg: &Otel, service: &str) -> crate::Result<Telemetry> {
+    #[cfg(feature = "otel")]
+    {
+        use crate::config;
+        use opentelemetry_sdk::{metrics, trace, Resource};
+
+        let invalid = |e: opentelemetry_otlp::ExporterBuildError| config::Error::Invalid {
+            message: format!("bad otel config: {e}"),
+        };
+        let protocol = match cfg.protocol {
+            config::OtelProtocol::HttpProtobuf => opentelemetry_otlp::Protocol::HttpBinary,
+            config::OtelProtocol::HttpJson => opentelemetry_otlp::Protocol::HttpJson,
+        };
+        let resource = Resource::builder()
+            .with_service_name(cfg.service_name.clone().unwrap_or(service.to_string()))
+            .build();
+
+        let spans = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_protocol(protocol)
+            .with_endpoint(format!("{}/v1/traces", cfg.endpoint))
+            .build()
+            .map_err(invalid)?;
+        let tracer = trace::SdkTracerProvider::builder()
+            .with_batch_exporter(spans)
+            .with_resource(resource.clone())
+            .build();
+
+        let exporter = opentelemetry_otlp::MetricExporter::builder()
+            .with_http()
+            .with_protocol(protocol)
+            .with_endpoint(format!("{}/v1/metrics", cfg.endpoint))
+            .build()
+            .map_err(invalid)?;
+        let reader = metrics::PeriodicReader::builder(exporter)
+            .with_interval(cfg.metrics_interval)
+            .build();
+        let meter = metrics::SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+        let bridge = Bridge::start(
+            opentelemetry::metrics::MeterProvider::meter(&meter, "nat-tunnel"),
+            cfg.metrics_interval,
+        );
+
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry_sdk::propagation::TraceContextPropagator::new(),
+        );
+        tracing::info!(
+            endpoint = cfg.endpoint,
+            "exporting traces and metrics over OTLP"
+        );
+        Ok(Telemetry {
+            tracer,
+            meter,
+            bridge,
+        })
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = service;
+        tracing::warn!(
+            endpoint = cfg.endpoint,
+            "built without the otel feature, not exporting traces or metrics"
+        );
+        Ok(Telemetry {})
+    }
+}
+
+impl Telemetry {
+    /// The layer turning spans into traces
+    pub fn layer<S>(&self) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        #[cfg(feature = "otel")]
+        {
+            tracing_opentelemetry::layer()
+                .with_tracer(self.tracer.tracer("nat-tunnel"))
+                .boxed()
+        }
+        #[cfg(not(feature = "otel"))]
+        tracing_subscriber::layer::Identity::new().boxed()
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        {
+            self.bridge.abort();
+            if let Err(e) = self.tracer.shutdown() {
+                tracing::warn!(cause = %e, "failed to flush traces");
+            }
+            if let Err(e) = self.meter.shutdown() {
+                tracing::warn!(cause = %e, "failed to flush metrics");
+            }
+        }
+    }
+}
+
+// How long a gathered snapshot is reused for. Every callback of one export
+// runs well within this, so an export gathers the Prometheus metrics once
+#[cfg(feature = "otel")]
+const SNAPSHOT_TTL: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Exports the Prometheus metrics through observable instruments. A family
+// gets its instruments the first time it shows up in a gather, since labelled
+// families only do once they have a sample
+#[cfg(feature = "otel")]
+struct Bridge {
+    meter: opentelemetry::metrics::Meter,
+    snapshot: std::sync::Mutex<Option<(std::time::Instant, Families)>>,
+    bridged: std::sync::Mutex<std::collections::HashSet<String>>,
+}
+
+#[cfg(feature = "otel")]
+type Families = std::sync::Arc<Vec<prometheus::proto::MetricFamily>>;
+
+#[cfg(feature = "otel")]
+impl Bridge {
+    // Look for new families every `interval`, until the returned task is
+    // aborted
+    fn start(
+        meter: opentelemetry::metrics::Meter,
+        interval: std::time::Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let bridge = std::sync::Arc::new(Bridge {
+            meter,
+            snapshot: Default::default(),
+            bridged: Default::default(),
+        });
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                bridge.discover();
+            }
+        })
+    }
+
+    // The Prometheus metrics as of at most SNAPSHOT_TTL ago
+    fn families(&self) -> Families {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        match &*snapshot {
+            Some((at, families)) if at.elapsed() < SNAPSHOT_TTL => families.clone(),
+            _ => {
+                let families = Families::new(crate::metrics::metrics().gather());
+                *snapshot = Some((std::time::Instant::now(), families.clone()));
+                families
+            }
+        }
+    }
+
+    // Register the instruments of every family that doesn't have them yet
+    fn discover(self: &std::sync::Arc<Self>) {
+        use prometheus::proto::MetricType;
+
+        let families = self.families();
+        let mut bridged = self.bridged.lock().unwrap();
+        for family in families.iter() {
+            if !bridged.insert(family.get_name().to_string()) {
+                continue;
+            }
+            let name = family.get_name().to_string();
+            let help = family.get_help().to_string();
+            match family.get_field_type() {
+                MetricType::GAUGE => {
+                    let bridge = self.clone();
+                    self.meter
+                        .f64_observable_gauge(name.clone())
+                        .with_description(help)
+                        .with_callback(move |o| {
+                            bridge.observe(&name, |m, kv| o.observe(m.get_gauge().get_value(), kv))
+                        })
+                        .build();
+                }
+                MetricType::COUNTER => {
+                    let bridge = self.clone();
+                    self.meter
+                        .f64_observable_counter(name.clone())
+                        .with_description(help)
+                        .with_callback(move |o| {
+                            bridge
+                                .observe(&name, |m, kv| o.observe(m.get_counter().get_value(), kv))
+                        })
+                        .build();
+                }
+                MetricType::HISTOGRAM => self.histogram(name, help),
+                MetricType::SUMMARY => self.sum_and_count(name, help, |m| {
+                    let s = m.get_summary();
+                    (s.get_sample_sum(), s.get_sample_count())
+                }),
+                // None of prometheus' own metric types are untyped
+                MetricType::UNTYPED => {}
+            }
+        }
+    }
+
+    // A histogram is exported the way Prometheus exposes it: a cumulative
+    // _bucket counter per upper bound `le`, and a _sum and a _count counter
+    fn histogram(self: &std::sync::Arc<Self>, name: String, help: String) {
+        let bridge = self.clone();
+        let family = name.clone();
+        self.meter
+            .u64_observable_counter(format!("{name}_bucket"))
+            .with_description(help.clone())
+            .with_callback(move |o| {
+                bridge.observe(&family, |m, kv| {
+                    let h = m.get_histogram();
+                    let mut labels = kv.to_vec();
+                    labels.push(opentelemetry::KeyValue::new("le", "+Inf"));
+                    for b in h.get_bucket() {
+                        *labels.last_mut().unwrap() =
+                            opentelemetry::KeyValue::new("le", b.get_upper_bound().to_string());
+                        o.observe(b.get_cumulative_count(), &labels);
+                    }
+                    *labels.last_mut().unwrap() = opentelemetry::KeyValue::new("le", "+Inf");
+                    o.observe(h.get_sample_count(), &labels);
+                })
+            })
+            .build();
+        self.sum_and_count(name, help, |m| {
+            let h = m.get_histogram();
+            (h.get_sample_sum(), h.get_sample_count())
+        });
+    }
+
+    fn sum_and_count(
+        self: &std::sync::Arc<Self>,
+        name: String,
+        help: String,
+        get: fn(&prometheus::proto::Metric) -> (f64, u64),
+    ) {
+        let (bridge, family) = (self.clone(), name.clone());
+        self.meter
+            .f64_observable_counter(format!("{name}_sum"))
+            .with_description(help.clone())
+            .with_callback(move |o| bridge.observe(&family, |m, kv| o.observe(get(m).0, kv)))
+            .build();
+        let (bridge, family) = (self.clone(), name.clone());
+        self.meter
+            .u64_observable_counter(format!("{name}_count"))
+            .with_description(help)
+            .with_callback(move |o| bridge.observe(&family, |m, kv| o.observe(get(m).1, kv)))
+            .build();
+    }
+
+    // Call `f` with every sample of the family called `name`, and its labels
+    fn observe(
+        &self,
+        name: &str,
+        mut f: impl FnMut(&prometheus::proto::Metric, &[opentelemetry::KeyValue]),
+    ) {
+        let families = self.families();
+        let Some(family) = families.iter().find(|f| f.get_name() == name) else {
+            return;
+        };
+        for m in family.get_metric() {
+            let labels: Vec<_> = m
+                .get_label()
+                .iter()
+                .map(|l| {
+                    opentelemetry::KeyValue::new(
+                        l.get_name().to_string(),
+                        l.get_value().to_string(),
+                    )
+                })
+                .collect();
+            f(m, &labels);
+        }
+    }
+}
+
+impl TraceContext {
+    /// The trace the current span is part of, if it's being exported
+    pub fn current() -> Self {
+        #[cfg(feature = "otel")]
+        {
+            let cx = tracing::Span::current().context();
+            let mut carrier = std::collections::HashMap::new();
+            opentelemetry::global::get_text_map_propagator(|p| p.inject_context(&cx, &mut carrier));
+            TraceContext(carrier.into_iter().collect())
+        }
+        #[cfg(not(feature = "otel"))]
+        TraceContext::default()
+    }
+
+    /// Make `span` part of this trace. Has to be called before `span` is
+    /// first entered
+    pub fn set_parent_of(&self, span: &tracing::Span) {
+        #[cfg(feature = "otel")]
+        {
+            if self.0.is_empty() {
+                return;
+            }
+            let carrier: std::collections::HashMap<_, _> = self.0.iter().cloned().collect();
+            let cx = opentelemetry::global::get_text_map_propagator(|p| p.extract(&carrier));
+            if let Err(e) = span.set_parent(cx) {
+                tracing::debug!(cause = %e, "failed to carry on with the server's trace");
+            }
+        }
+        #[cfg(not(feature = "otel"))]
+        let _ = span;
+    }
+}
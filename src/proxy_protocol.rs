@@ -0,0 +1,153 @@
+//! Headers of the PROXY protocol, written to the Internal ahead of any payload
+//! so that it can see the External's address instead of the client's.
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    // Human readable
+    V1,
+    // Binary
+    V2,
+}
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+// Version 2, PROXY command
+const V2_PROXY: u8 = 0x21;
+// AF_INET or AF_INET6, over STREAM
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+impl ProxyProtocol {
+    /// The header for a connection from the External at `src` to the
+    /// server's `dst`
+    pub fn header(&self, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        // Externals on a dual stack listener can show up as v4-mapped
+        let src = SocketAddr::new(src.ip().to_canonical(), src.port());
+        let dst = SocketAddr::new(dst.ip().to_canonical(), dst.port());
+        // Both have to be of the same family. Should they not be, the
+        // destination address is left unspecified
+        let dst = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(_), IpAddr::V6(_)) => {
+                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), dst.port())
+            }
+            (IpAddr::V6(_), IpAddr::V4(_)) => {
+                SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), dst.port())
+            }
+            _ => dst,
+        };
+        match self {
+            ProxyProtocol::V1 => {
+                let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {family} {} {} {} {}\r\n",
+                    src.ip(),
+                    dst.ip(),
+                    src.port(),
+                    dst.port()
+                )
+                .into_bytes()
+            }
+            ProxyProtocol::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                header.push(V2_PROXY);
+                match (src.ip(), dst.ip()) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => {
+                        header.push(V2_TCP4);
+                        header.extend_from_slice(&12u16.to_be_bytes());
+                        header.extend_from_slice(&s.octets());
+                        header.extend_from_slice(&d.octets());
+                    }
+                    (IpAddr::V6(s), IpAddr::V6(d)) => {
+                        header.push(V2_TCP6);
+                        header.extend_from_slice(&36u16.to_be_bytes());
+                        header.extend_from_slice(&s.octets());
+                        header.extend_from_slice(&d.octets());
+                    }
+                    _ => unreachable!("source and destination are of the same family"),
+                }
+                header.extend_from_slice(&src.port().to_be_bytes());
+                header.extend_from_slice(&dst.port().to_be_bytes());
+                header
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let header = ProxyProtocol::V1.header(addr("192.0.2.1:5000"), addr("198.51.100.7:443"));
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.7 5000 443\r\n");
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let header =
+            ProxyProtocol::V1.header(addr("[2001:db8::1]:5000"), addr("[2001:db8::2]:443"));
+        assert_eq!(header, b"PROXY TCP6 2001:db8::1 2001:db8::2 5000 443\r\n");
+    }
+
+    #[test]
+    fn v1_v4_mapped_is_tcp4() {
+        let header = ProxyProtocol::V1.header(
+            addr("[::ffff:192.0.2.1]:5000"),
+            addr("[::ffff:198.51.100.7]:443"),
+        );
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.7 5000 443\r\n");
+    }
+
+    #[test]
+    fn v1_mixed_families_leave_destination_unspecified() {
+        let header = ProxyProtocol::V1.header(addr("192.0.2.1:5000"), addr("[2001:db8::2]:443"));
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 0.0.0.0 5000 443\r\n");
+        let header = ProxyProtocol::V1.header(addr("[2001:db8::1]:5000"), addr("198.51.100.7:443"));
+        assert_eq!(header, b"PROXY TCP6 2001:db8::1 :: 5000 443\r\n");
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let header = ProxyProtocol::V2.header(addr("192.0.2.1:5000"), addr("198.51.100.7:443"));
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        expected.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 7]);
+        expected.extend_from_slice(&[0x13, 0x88, 0x01, 0xbb]);
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn v2_tcp6() {
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let header = ProxyProtocol::V2.header(
+            SocketAddr::new(src.into(), 5000),
+            SocketAddr::new(dst.into(), 443),
+        );
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        expected.extend_from_slice(&src.octets());
+        expected.extend_from_slice(&dst.octets());
+        expected.extend_from_slice(&[0x13, 0x88, 0x01, 0xbb]);
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn v2_v4_mapped_is_tcp4() {
+        let header =
+            ProxyProtocol::V2.header(addr("[::ffff:192.0.2.1]:5000"), addr("198.51.100.7:443"));
+        assert_eq!(header[13], V2_TCP4);
+        assert_eq!(&header[16..24], &[192, 0, 2, 1, 198, 51, 100, 7]);
+        assert_eq!(header.len(), 16 + 12);
+    }
+}
@@ -15,16 +15,11 @@ pub async fn quinn(
     addrs: &[SocketAddr],
     expected_host: &str,
 ) -> stnet::Result<Option<quinn::Connection>> {
-    let ipv6_addrs = addrs
-        .iter()
-        .filter(|addr| matches!(addr, SocketAddr::V6(_)))
-        .peekable();
-    let ipv4_addrs = addrs
-        .iter()
-        .filter(|addr| matches!(addr, SocketAddr::V4(_)))
-        .peekable();
+    // Collected rather than filtered lazily, so that the future stays Send
+    let (ipv6_addrs, ipv4_addrs): (Vec<&SocketAddr>, Vec<&SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6());
 
-    for elem in ipv6_addrs.zip_longest(ipv4_addrs) {
+    for elem in ipv6_addrs.into_iter().zip_longest(ipv4_addrs) {
         let stream = match elem {
             // race between ipv6 and ipv4 connections with ipv4 having a delayed start
             Both(v6, v4) => {
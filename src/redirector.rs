@@ -8,16 +8,57 @@ use tracing::{error, trace};
 // See tests/mtu.rs for an explanation of this magic number
 pub const PROTOCOL_OVERHEAD: u16 = 53;
 
+/// Why a Redirector finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    // Both ends were done sending
+    Closed,
+    // Reading from or writing to the stream failed
+    Error,
+    // Nothing went either way for too long
+    Idle,
+    // The tunnel or session went away, or the connection was killed
+    Cancelled,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Closed => "closed",
+            CloseReason::Error => "error",
+            CloseReason::Idle => "idle",
+            CloseReason::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// What a Redirector did, once it is done
+#[derive(Debug, Clone)]
+pub struct Stats {
+    // Read from and written to the stream
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub duration: Duration,
+    pub reason: CloseReason,
+}
+
 /// Reads data from stream, and send it along the `tx` channel
 /// Reads data from rx channel, and send it along the stream
 pub struct Redirector<T: stnet::Stream> {
     id: SocketAddr,
     port: u16,
+    // Of the External, for the logs. None for connections the client started
+    conn_id: Option<stnet::ConnId>,
     token: CancellationToken,
     buffer_size: usize,
     stream: T,
     tx: mpsc::Sender<stnet::RedirectorFrame>,
     rx: mpsc::Receiver<stnet::RedirectorFrame>,
+    bytes_in: prometheus::IntCounter,
+    bytes_out: prometheus::IntCounter,
+    // Same as above, for this connection alone
+    read: u64,
+    written: u64,
 }
 
 impl<T> Redirector<T>
@@ -43,16 +84,29 @@ where
         //let buffer_size = mtu - PROTOCOL_OVERHEAD;
         let buffer_size = 1330 - PROTOCOL_OVERHEAD as usize;
 
+        let tunnel_bytes = &crate::metrics::metrics().tunnel_bytes;
+        let p = port.to_string();
         Redirector {
+            bytes_in: tunnel_bytes.with_label_values(&[&p, "in"]),
+            bytes_out: tunnel_bytes.with_label_values(&[&p, "out"]),
+            read: 0,
+            written: 0,
             stream,
             buffer_size,
             id,
             port,
+            conn_id: None,
             token,
             tx,
             rx,
         }
     }
+
+    /// Log under the ID of the External's connection
+    pub fn with_conn_id(mut self, conn_id: stnet::ConnId) -> Self {
+        self.conn_id = Some(conn_id);
+        self
+    }
     pub async fn read(
         &mut self,
         maybe_n: std::io::Result<usize>,
@@ -74,6 +128,8 @@ where
             trace!("read 0 bytes, ending redirector");
             return Some(true);
         }
+        self.bytes_in.inc_by(n as u64);
+        self.read += n as u64;
         let mut data = buf.clone();
         data.resize(n, 0);
         let d = stnet::Datagram {
@@ -93,11 +149,19 @@ where
         &mut ref mut last_activity: &mut std::time::Instant,
     ) -> Option<bool> {
         let data = match maybe_data {
-            None => return Some(true),
+            // The other side is done sending. Pass that on, so that whoever
+            // is on our stream isn't left waiting for more
+            None => {
+                let _ = self.stream.shutdown().await;
+                return Some(true);
+            }
             Some(stnet::RedirectorFrame::Datagram(d)) => d,
             // These packets should never reach a redirector
             Some(stnet::RedirectorFrame::KillListener(_)) => unreachable!(),
-            Some(stnet::RedirectorFrame::StartListener(_, _)) => unreachable!(),
+            Some(stnet::RedirectorFrame::StartListener(..)) => unreachable!(),
+            Some(stnet::RedirectorFrame::StartVhostListener(..)) => unreachable!(),
+            Some(stnet::RedirectorFrame::StartForward(..)) => unreachable!(),
+            Some(stnet::RedirectorFrame::StartVisit(..)) => unreachable!(),
         };
         if let Err(e) = self.stream.write_all(&data.data).await {
             error!(cause = ?e, "failed to write buffer");
@@ -107,18 +171,27 @@ where
             error!(cause = ?e, "failed to flush buffer");
             return Some(false);
         }
+        self.bytes_out.inc_by(data.data.len() as u64);
+        self.written += data.data.len() as u64;
         *last_activity = Instant::now();
         None
     }
 
-    #[tracing::instrument(name = "Redirector", level = "trace", skip_all)]
-    pub async fn run(&mut self) {
+    #[tracing::instrument(name = "Redirector", level = "info", skip_all, fields(conn_id = self.conn_id.map(tracing::field::display)))]
+    pub async fn run(&mut self) -> Stats {
+        let metrics = crate::metrics::metrics();
+        let port = self.port.to_string();
+        let connections = metrics.active_connections.with_label_values(&[&port]);
+        connections.inc();
+        let started = Instant::now();
+
         let mut last_activity = std::time::Instant::now();
         let keepalive = Duration::from_secs(300);
         let mut interval = tokio::time::interval(keepalive);
         let mut buf = vec![0; self.buffer_size];
         let mut write_done = false;
         let mut read_done = false;
+        let mut reason = CloseReason::Closed;
         // This is disgusting, but it lets half closed connections function correctly
         loop {
             tokio::select! {
@@ -129,6 +202,7 @@ where
                         } else {
                             write_done = true;
                             read_done = true;
+                            reason = CloseReason::Error;
                         }
                         break
                     }
@@ -141,6 +215,7 @@ where
                         } else {
                             write_done = true;
                             read_done = true;
+                            reason = CloseReason::Error;
                         }
                         break
                     }
@@ -149,11 +224,15 @@ where
                 _ = interval.tick() => {
                     if last_activity.elapsed() >= keepalive {
                         trace!("{} seconds passed without any activity. Closing.", keepalive.as_secs());
+                        reason = CloseReason::Idle;
                         break
                     }
                 }
 
-                _ = self.token.cancelled() => break,
+                _ = self.token.cancelled() => {
+                    reason = CloseReason::Cancelled;
+                    break
+                }
             }
         }
 
@@ -161,7 +240,10 @@ where
             loop {
                 tokio::select! {
                     maybe_data = self.rx.recv() => {
-                        if self.write(maybe_data, &mut last_activity).await.is_some() {
+                        if let Some(s) = self.write(maybe_data, &mut last_activity).await {
+                            if !s {
+                                reason = CloseReason::Error;
+                            }
                             break
                         }
                     }
@@ -169,11 +251,15 @@ where
                     _ = interval.tick() => {
                         if last_activity.elapsed() >= keepalive {
                             trace!("{} seconds passed without any activity. Closing.", keepalive.as_secs());
+                            reason = CloseReason::Idle;
                             break
                         }
                     }
 
-                    _ = self.token.cancelled() => break,
+                    _ = self.token.cancelled() => {
+                        reason = CloseReason::Cancelled;
+                        break
+                    }
                 }
             }
         }
@@ -182,7 +268,10 @@ where
             loop {
                 tokio::select! {
                     maybe_n = self.stream.read(&mut buf) => {
-                        if self.read(maybe_n, &mut buf, &mut last_activity).await.is_some() {
+                        if let Some(s) = self.read(maybe_n, &mut buf, &mut last_activity).await {
+                            if !s {
+                                reason = CloseReason::Error;
+                            }
                             break
                         }
                     }
@@ -190,15 +279,30 @@ where
                     _ = interval.tick() => {
                         if last_activity.elapsed() >= keepalive {
                             trace!("{} seconds passed without any activity. Closing.", keepalive.as_secs());
+                            reason = CloseReason::Idle;
                             break
                         }
                     }
 
-                    _ = self.token.cancelled() => break,
+                    _ = self.token.cancelled() => {
+                        reason = CloseReason::Cancelled;
+                        break
+                    }
                 }
             }
         }
         self.rx.close();
+        connections.dec();
+        metrics
+            .redirector_lifetime
+            .with_label_values(&[&port])
+            .observe(started.elapsed().as_secs_f64());
         trace!("Tunnel end");
+        Stats {
+            bytes_in: self.read,
+            bytes_out: self.written,
+            duration: started.elapsed(),
+            reason,
+        }
     }
 }
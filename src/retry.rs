@@ -0,0 +1,110 @@
+//! The backoff between a client's attempts to connect to a server
+use crate::config::client::Retry;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use std::time::Duration;
+
+/// Exponentially growing, capped and jittered delays between failed attempts
+pub struct Backoff {
+    policy: Retry,
+    max_attempts: Option<u64>,
+    // Failed attempts in a row
+    attempts: u64,
+}
+
+impl Backoff {
+    /// Give up after `max_attempts` failed attempts in a row, or never if
+    /// it is None
+    pub fn new(policy: Retry, max_attempts: Option<u64>) -> Self {
+        Backoff {
+            policy,
+            max_attempts,
+            attempts: 0,
+        }
+    }
+
+    /// Count a failed attempt, and return how long to wait before the next
+    /// one. None once out of attempts
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempts += 1;
+        if matches!(self.max_attempts, Some(max) if self.attempts >= max) {
+            return None;
+        }
+        let exp = (self.attempts - 1).min(i32::MAX as u64) as i32;
+        let delay = (self.policy.initial_delay * self.policy.multiplier.powi(exp))
+            .min(self.policy.max_delay);
+        // Anywhere from jitter shorter to jitter longer
+        let r = OsRng.next_u64() as f64 / u64::MAX as f64;
+        let delay = delay * (1.0 + self.policy.jitter * (2.0 * r - 1.0));
+        Some(Duration::from_secs_f64(delay.min(self.policy.max_delay)))
+    }
+
+    /// Failed attempts in a row so far
+    pub fn attempts(&self) -> u64 {
+        self.attempts
+    }
+
+    /// Start over from initial_delay, after a successful connection
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> Retry {
+        Retry {
+            initial_delay: 0.5,
+            max_delay: 30.0,
+            multiplier: 2.0,
+            jitter,
+            max_attempts: None,
+        }
+    }
+
+    #[test]
+    fn grows_up_to_max_delay() {
+        let mut backoff = Backoff::new(policy(0.0), None);
+        let delays: Vec<_> = (0..9)
+            .map(|_| backoff.next_delay().unwrap().as_secs_f64())
+            .collect();
+        assert_eq!(delays, [0.5, 1.0, 2.0, 4.0, 8.0, 16.0, 30.0, 30.0, 30.0]);
+    }
+
+    #[test]
+    fn stays_capped_after_many_attempts() {
+        let mut backoff = Backoff::new(policy(0.0), None);
+        backoff.attempts = u64::MAX - 1;
+        assert_eq!(backoff.next_delay(), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn jitter_never_exceeds_max_delay() {
+        let mut backoff = Backoff::new(policy(0.5), None);
+        for attempt in 1..=1000 {
+            let delay = backoff.next_delay().unwrap().as_secs_f64();
+            let base = (0.5 * 2f64.powi(attempt.min(100) - 1)).min(30.0);
+            assert!(delay >= base * 0.5 && delay <= 30.0, "{attempt}: {delay}");
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = Backoff::new(policy(0.0), Some(3));
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.attempts(), 3);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(policy(0.0), Some(3));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(500)));
+        assert!(backoff.next_delay().is_some());
+    }
+}
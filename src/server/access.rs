@@ -0,0 +1,34 @@
+//! The access log: one line per External connection, written once it is
+//! over. Lines are logged under their own target, so that they can be sent
+//! to a file of their own with access_log
+use crate::{net as stnet, redirector::Stats};
+use std::net::SocketAddr;
+
+pub const TARGET: &str = "nat_tunnel::access";
+
+/// Who used a tunnel, and how
+pub struct Entry {
+    pub remote_port: u16,
+    pub client: super::ClientId,
+    pub client_addr: Option<String>,
+    pub external_addr: SocketAddr,
+    pub conn_id: Option<stnet::ConnId>,
+}
+
+impl Entry {
+    pub fn log(&self, stats: &Stats) {
+        tracing::info!(
+            target: TARGET,
+            remote_port = self.remote_port,
+            client = self.client,
+            client_addr = self.client_addr.as_deref(),
+            external_addr = %self.external_addr,
+            conn_id = self.conn_id.map(tracing::field::display),
+            bytes_in = stats.bytes_in,
+            bytes_out = stats.bytes_out,
+            duration_ms = stats.duration.as_millis() as u64,
+            reason = stats.reason.as_str(),
+            "connection closed"
+        );
+    }
+}
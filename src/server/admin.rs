@@ -0,0 +1,116 @@
+//! Local admin API for sts. Speaks JSON over plain HTTP and must only ever be
+//! bound to a loopback address.
+//!
+//! * `GET /clients`
+//! * `GET /tunnels`
+//! * `GET /connections`
+//! * `DELETE /clients/<id>`
+//! * `DELETE /connections/<external_addr>`
+//! * `POST /drain`
+use super::Registry;
+use crate::{http, net as stnet};
+use snafu::ResultExt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net as tnet;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, trace};
+
+#[tracing::instrument(name = "Admin", level = "info", skip_all)]
+pub async fn serve(
+    addr: SocketAddr,
+    registry: Arc<Registry>,
+    token: CancellationToken,
+) -> stnet::Result<()> {
+    let listener = tnet::TcpListener::bind(addr)
+        .await
+        .with_context(|_| stnet::IoSnafu {
+            message: format!("failed to bind admin listener {addr}"),
+        })?;
+    info!("admin API listening on http://{addr}");
+
+    loop {
+        let (mut stream, peer) = tokio::select! {
+            maybe_accept = listener.accept() => match maybe_accept {
+                Err(e) => {
+                    error!(cause = ?e, "failed to accept admin connection");
+                    continue;
+                }
+                Ok(s) => s,
+            },
+            _ = token.cancelled() => return Ok(()),
+        };
+
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let req = match tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                http::read_request(&mut stream),
+            )
+            .await
+            {
+                Ok(Ok(req)) => req,
+                _ => {
+                    trace!(peer = ?peer, "bad admin request");
+                    return;
+                }
+            };
+            let (status, body) = handle(&registry, &req);
+            if let Err(e) =
+                http::write_response(&mut stream, status, "application/json", &body).await
+            {
+                trace!(cause = ?e, peer = ?peer, "failed to write admin response");
+            }
+        });
+    }
+}
+
+fn json<T: serde::Serialize>(t: &T) -> (u16, Vec<u8>) {
+    match serde_json::to_vec(t) {
+        Ok(body) => (200, body),
+        Err(_) => error_body(500, "failed to encode response"),
+    }
+}
+
+fn error_body(status: u16, message: &str) -> (u16, Vec<u8>) {
+    (
+        status,
+        serde_json::json!({ "error": message })
+            .to_string()
+            .into_bytes(),
+    )
+}
+
+fn handle(registry: &Registry, req: &http::Request) -> (u16, Vec<u8>) {
+    let path: Vec<_> = req.path.trim_matches('/').split('/').collect();
+    match (req.method.as_str(), path.as_slice()) {
+        ("GET", ["clients"]) => json(&registry.clients()),
+        ("GET", ["tunnels"]) => json(&registry.tunnels()),
+        ("GET", ["connections"]) => json(&registry.connections()),
+        ("DELETE", ["clients", id]) => match id.parse() {
+            Err(_) => error_body(400, "invalid client id"),
+            Ok(id) if registry.disconnect_client(id) => {
+                info!(client = id, "disconnecting client via admin API");
+                (204, Vec::new())
+            }
+            Ok(_) => error_body(404, "no such client"),
+        },
+        ("DELETE", ["connections", addr]) => match addr.parse::<SocketAddr>() {
+            Err(_) => error_body(400, "invalid connection address"),
+            Ok(addr) if registry.disconnect_connection(&addr) => {
+                info!(external_addr = ?addr, "disconnecting connection via admin API");
+                (204, Vec::new())
+            }
+            Ok(_) => error_body(404, "no such connection"),
+        },
+        ("POST", ["drain"]) => {
+            if registry.drain() {
+                info!("draining via admin API");
+            }
+            (204, Vec::new())
+        }
+        (_, ["clients" | "tunnels" | "connections", ..]) => error_body(405, "method not allowed"),
+        (_, ["drain"]) => error_body(405, "method not allowed"),
+        _ => error_body(404, "not found"),
+    }
+}
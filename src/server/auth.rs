@@ -0,0 +1,359 @@
+//! Credentials checks for HTTP tunnels, so that an Internal without any auth
+//! of its own can be exposed. An External's first request is checked before
+//! the client hears about it, and every request after it on a keep-alive
+//! connection is checked again. Turned down requests get a 401 from the
+//! server itself.
+use crate::config::server as config;
+use crate::{http, http_tunnel, net as stnet};
+use base64::Engine;
+use futures::future::BoxFuture;
+use snafu::ResultExt;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use subtle::ConstantTimeEq;
+use tokio::io::DuplexStream;
+use tracing::{info, trace};
+
+/// Externals that don't send a complete request head by then are dropped
+pub const HEAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Between an admitted External and its Redirector
+const BUFFER: usize = 64 * 1024;
+
+// Verified credentials remembered per policy. Forgotten all at once when full
+const MAX_VERIFIED: usize = 1024;
+
+/// The credentials allowed in on some tunnels
+pub struct Policy {
+    realm: String,
+    // bcrypt hashes by user
+    users: HashMap<String, String>,
+    tokens: Vec<String>,
+    // SHA-256 of the basic credentials verified already. bcrypt is slow on
+    // purpose, and browsers send the same header with every request
+    verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+impl Policy {
+    fn load(config: &config::HttpAuth) -> crate::Result<Self> {
+        let users = match config.htpasswd {
+            None => HashMap::new(),
+            Some(ref path) => {
+                let contents = std::fs::read_to_string(path).with_context(|_| stnet::IoSnafu {
+                    message: format!("failed to read htpasswd {path:?}"),
+                })?;
+                parse_htpasswd(&contents).map_err(|message| stnet::Error::Io {
+                    message: format!("{message} in {path:?}"),
+                    source: std::io::ErrorKind::InvalidData.into(),
+                    backtrace: snafu::Backtrace::capture(),
+                })?
+            }
+        };
+        Ok(Policy {
+            realm: config.realm.clone(),
+            users,
+            tokens: config.tokens.clone(),
+            verified: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Whether a request with this `Authorization` header is let through.
+    /// Can take a while for basic auth the first time around, so bcrypt is
+    /// run off the runtime
+    pub async fn allows(&self, authorization: Option<&str>) -> bool {
+        let Some(authorization) = authorization.map(str::trim) else {
+            return false;
+        };
+        let Some((scheme, credentials)) = authorization.split_once(' ') else {
+            return false;
+        };
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            // Every token is compared in full, so that timing gives nothing away
+            return self.tokens.iter().fold(false, |ok, token| {
+                ok | bool::from(token.as_bytes().ct_eq(credentials.as_bytes()))
+            });
+        }
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return false;
+        }
+        let key: [u8; 32] = ring::digest::digest(&ring::digest::SHA256, credentials.as_bytes())
+            .as_ref()
+            .try_into()
+            .unwrap();
+        if self.verified.lock().unwrap().contains(&key) {
+            return true;
+        }
+        let Some((user, password)) = base64::engine::general_purpose::STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|c| String::from_utf8(c).ok())
+            .and_then(|c| {
+                c.split_once(':')
+                    .map(|(u, p)| (u.to_string(), p.to_string()))
+            })
+        else {
+            return false;
+        };
+        let Some(hash) = self.users.get(&user).cloned() else {
+            return false;
+        };
+        let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .map(|r| r.unwrap_or(false))
+            .unwrap_or(false);
+        if !valid {
+            return false;
+        }
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= MAX_VERIFIED {
+            verified.clear();
+        }
+        verified.insert(key);
+        true
+    }
+
+    /// The 401 for Externals without valid credentials
+    pub fn refusal(&self) -> Vec<u8> {
+        let mut challenges = String::new();
+        if !self.users.is_empty() {
+            challenges.push_str(&format!(
+                "WWW-Authenticate: Basic realm=\"{}\", charset=\"UTF-8\"\r\n",
+                self.realm
+            ));
+        }
+        if !self.tokens.is_empty() {
+            challenges.push_str(&format!(
+                "WWW-Authenticate: Bearer realm=\"{}\"\r\n",
+                self.realm
+            ));
+        }
+        let body = format!("401 {}\n", http::reason(401));
+        format!(
+            "HTTP/1.1 401 {}\r\n{challenges}Content-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            http::reason(401),
+            body.len()
+        )
+        .into_bytes()
+    }
+}
+
+impl http_tunnel::Gate for Policy {
+    fn refuse<'a>(&'a self, headers: &[httparse::Header]) -> BoxFuture<'a, Option<Vec<u8>>> {
+        let authorization = headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("authorization"))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+            .map(str::to_string);
+        Box::pin(
+            async move { (!self.allows(authorization.as_deref()).await).then(|| self.refusal()) },
+        )
+    }
+}
+
+// `user:hash` lines, with blank lines and comments skipped
+fn parse_htpasswd(contents: &str) -> std::result::Result<HashMap<String, String>, String> {
+    let mut users = HashMap::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((user, hash)) = line.split_once(':') else {
+            return Err(format!("malformed line {}", n + 1));
+        };
+        if !hash.starts_with("$2") {
+            return Err(format!(
+                "only bcrypt passwords (htpasswd -B) are supported, but {user:?} has another kind"
+            ));
+        }
+        users.insert(user.to_string(), hash.to_string());
+    }
+    Ok(users)
+}
+
+#[derive(Default)]
+struct Policies {
+    by_port: HashMap<u16, Arc<Policy>>,
+    by_host: HashMap<String, Arc<Policy>>,
+}
+
+/// Picks the [`Policy`] for each tunnel from `http_auth`
+pub struct Authenticator {
+    config: Vec<config::HttpAuth>,
+    policies: Mutex<Arc<Policies>>,
+}
+
+impl Authenticator {
+    pub fn new(config: &[config::HttpAuth]) -> crate::Result<Self> {
+        let auth = Authenticator {
+            config: config.to_vec(),
+            policies: Mutex::new(Default::default()),
+        };
+        auth.reload()?;
+        Ok(auth)
+    }
+
+    /// The policy for the tunnel on `remote_port`, if it has one
+    pub fn for_port(&self, remote_port: u16) -> Option<Arc<Policy>> {
+        let policies = self.policies.lock().unwrap().clone();
+        policies.by_port.get(&remote_port).cloned()
+    }
+
+    /// The policy for the HTTP vhost `hostname`, if it has one
+    pub fn for_host(&self, hostname: &str) -> Option<Arc<Policy>> {
+        let policies = self.policies.lock().unwrap().clone();
+        policies.by_host.get(hostname).cloned()
+    }
+
+    /// Read the htpasswd files from disk again. Connections already let in
+    /// keep being checked against what they were let in with. On failure the
+    /// policies in use are kept
+    pub fn reload(&self) -> crate::Result<()> {
+        let mut policies = Policies::default();
+        for config in self.config.iter() {
+            let policy = Arc::new(Policy::load(config)?);
+            for port in config.ports.iter() {
+                policies.by_port.insert(*port, policy.clone());
+            }
+            for host in config.hosts.iter() {
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                policies.by_host.insert(host, policy.clone());
+            }
+        }
+        info!(
+            ports = ?policies.by_port.keys(),
+            hosts = ?policies.by_host.keys(),
+            "loaded http auth"
+        );
+        *self.policies.lock().unwrap() = Arc::new(policies);
+        Ok(())
+    }
+}
+
+/// Check the first request of an External against `policy`. Turned down
+/// Externals get a 401 and are hung up on. Otherwise returns the stream to
+/// hand to the Redirector, along with the task that checks the requests that
+/// follow, which has to be run alongside it
+pub async fn admit<T: stnet::Stream + 'static>(
+    mut stream: T,
+    policy: Arc<Policy>,
+) -> Option<(DuplexStream, BoxFuture<'static, ()>)> {
+    let (req, head) = match tokio::time::timeout(HEAD_TIMEOUT, http::read_head(&mut stream)).await {
+        Ok(Ok(r)) => r,
+        _ => {
+            trace!("bad http request on a gated tunnel");
+            let body = format!("400 {}\n", http::reason(400));
+            let _ = http::write_response(&mut stream, 400, "text/plain", body.as_bytes()).await;
+            return None;
+        }
+    };
+
+    if !policy.allows(req.header("authorization")).await {
+        trace!(
+            path = req.path,
+            "turned down request without valid credentials"
+        );
+        let _ = stream.write_all(&policy.refusal()).await;
+        let _ = stream.shutdown().await;
+        return None;
+    }
+
+    let (ours, theirs) = tokio::io::duplex(BUFFER);
+    let stream = stnet::PrefixedStream::new(head, stream);
+    let gate = http_tunnel::Mode::Gate(policy);
+    let proxy = async move {
+        if let Err(e) = http_tunnel::proxy(stream, theirs, gate).await {
+            trace!(cause = ?e, "gated connection ended");
+        }
+    };
+    Some((ours, Box::pin(proxy)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(htpasswd: &str, tokens: &[&str]) -> Policy {
+        Policy {
+            realm: "test".to_string(),
+            users: parse_htpasswd(htpasswd).unwrap(),
+            tokens: tokens.iter().map(|t| t.to_string()).collect(),
+            verified: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{user}:{password}"));
+        format!("Basic {credentials}")
+    }
+
+    #[test]
+    fn parses_htpasswd() {
+        let users = parse_htpasswd("# users\n\nalice:$2y$05$abc\n  bob:$2b$05$def  \n").unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users["alice"], "$2y$05$abc");
+        assert_eq!(users["bob"], "$2b$05$def");
+    }
+
+    #[test]
+    fn rejects_malformed_htpasswd() {
+        let e = parse_htpasswd("alice:$2y$05$abc\nbob\n").unwrap_err();
+        assert_eq!(e, "malformed line 2");
+        let e = parse_htpasswd("alice:{SHA}abc\n").unwrap_err();
+        assert!(e.contains("\"alice\""), "{e}");
+    }
+
+    #[tokio::test]
+    async fn checks_basic_auth() {
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        let policy = policy(&format!("alice:{hash}\n"), &[]);
+        assert!(policy.allows(Some(&basic("alice", "hunter2"))).await);
+        assert!(!policy.allows(Some(&basic("alice", "hunter3"))).await);
+        assert!(!policy.allows(Some(&basic("bob", "hunter2"))).await);
+        assert!(!policy.allows(Some("Basic not-base64!")).await);
+        assert!(!policy.allows(Some("Digest whatever")).await);
+        assert!(!policy.allows(None).await);
+        // The scheme is case insensitive
+        let credentials = basic("alice", "hunter2").replace("Basic", "bAsIc");
+        assert!(policy.allows(Some(&credentials)).await);
+    }
+
+    #[tokio::test]
+    async fn checks_bearer_tokens() {
+        let policy = policy("", &["s3cret", "other"]);
+        assert!(policy.allows(Some("Bearer s3cret")).await);
+        assert!(policy.allows(Some("bearer other")).await);
+        assert!(!policy.allows(Some("Bearer s3cre")).await);
+        assert!(!policy.allows(Some("Bearer")).await);
+    }
+
+    #[tokio::test]
+    async fn caches_only_verified_credentials() {
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        let policy = policy(&format!("alice:{hash}\n"), &[]);
+        assert!(!policy.allows(Some(&basic("alice", "wrong"))).await);
+        assert!(policy.verified.lock().unwrap().is_empty());
+        assert!(policy.allows(Some(&basic("alice", "hunter2"))).await);
+        assert!(policy.allows(Some(&basic("alice", "hunter2"))).await);
+        assert_eq!(policy.verified.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn verified_cache_is_capped() {
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        let policy = policy(&format!("alice:{hash}\n"), &[]);
+        policy
+            .verified
+            .lock()
+            .unwrap()
+            .extend((0..MAX_VERIFIED as u32).map(|i| {
+                let mut key = [0u8; 32];
+                key[..4].copy_from_slice(&i.to_be_bytes());
+                key
+            }));
+        assert!(policy.allows(Some(&basic("alice", "hunter2"))).await);
+        assert_eq!(policy.verified.lock().unwrap().len(), 1);
+    }
+}
@@ -3,18 +3,16 @@ use crate::{config::server as config, net as stnet};
 use snafu::prelude::*;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use tokio::net as tnet;
 use tokio::sync::mpsc;
 use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, trace};
+use tracing::{debug, error, info, trace, warn, Instrument};
 
 #[derive(Snafu, Debug)]
 pub enum ClientValidationError {
-    #[snafu(display(
-        "client sent configuration with remote_ports {tunnels:?}, which are already in use"
-    ))]
-    DuplicateTunnels { tunnels: Vec<u16> },
     #[snafu(display("Incorrect PSK from client"))]
     IncorrectPSK,
     #[snafu(display("transport error: {source}"))]
@@ -27,8 +25,27 @@ impl std::convert::From<stnet::Error> for ClientValidationError {
     }
 }
 
+impl ClientValidationError {
+    /// Short, label-safe name for the failure, used for metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ClientValidationError::IncorrectPSK => "incorrect_psk",
+            ClientValidationError::TransportError {
+                source: stnet::Error::IoTimeout { .. },
+            } => "timeout",
+            ClientValidationError::TransportError {
+                source: stnet::Error::UnexpectedFrame,
+            } => "bad_helo",
+            ClientValidationError::TransportError { .. } => "transport",
+        }
+    }
+}
+
 type ClientResult<T> = std::result::Result<T, ClientValidationError>;
 
+// How long the server tries to connect to a forward target
+const FORWARD_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
 pub struct ClientHandler<T>
 where
     T: stnet::Stream,
@@ -40,40 +57,97 @@ where
     transport: stnet::Transport<T>,
 
     active_tunnels: Arc<Mutex<ActiveTunnels>>,
+    vhosts: Arc<Mutex<super::vhost::VhostChannels>>,
+    secrets: Arc<Mutex<super::secret::SecretChannels>>,
+    sessions: Arc<Mutex<super::session::Sessions<T>>>,
+    registry: Arc<super::Registry>,
+    terminator: Option<Arc<super::terminate::Terminator>>,
+    auth: Option<Arc<super::auth::Authenticator>>,
+    id: super::ClientId,
+    // Cancelled when the server drains, or when the client asks to. The
+    // session ends once the live connections are done
+    drain: CancellationToken,
+
+    // The token the client may resume this session with, and where the new
+    // connection comes in when it does
+    session: Option<(
+        stnet::SessionToken,
+        mpsc::Receiver<super::session::Resumption<T>>,
+    )>,
+    replay: stnet::Replay,
+    // Cleared when the client ends the session itself
+    inform_client: bool,
 
     to_client: mpsc::Sender<stnet::RedirectorFrame>,
     from_tunnels: mpsc::Receiver<stnet::RedirectorFrame>,
     to_tunnels: Arc<Mutex<TunnelChannels>>,
 
-    js: JoinSet<u16>,
+    // The TunnelSupervisor for each remote_port held by this client
+    tunnels: HashMap<u16, (tokio::task::AbortHandle, CancellationToken)>,
+    js: JoinSet<(u16, stnet::Result<()>)>,
+    // The TunnelSupervisor for each hostname, or secret tunnel, held by
+    // this client
+    hosts: HashMap<stnet::Vhost, (tokio::task::AbortHandle, CancellationToken)>,
+    host_js: JoinSet<(stnet::Vhost, stnet::Result<()>)>,
+    // Whether the Internal of each tunnel is up, as reported by the client
+    health: HashMap<u16, Arc<AtomicBool>>,
+    host_health: HashMap<stnet::Vhost, Arc<AtomicBool>>,
+    // The connections to forward targets, and to secret tunnels, opened for
+    // this client
+    forwards: JoinSet<()>,
 }
 
 impl<T> ClientHandler<T>
 where
     T: stnet::Stream,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Arc<config::Config>,
         token: CancellationToken,
         active_tunnels: Arc<Mutex<ActiveTunnels>>,
+        vhosts: Arc<Mutex<super::vhost::VhostChannels>>,
+        secrets: Arc<Mutex<super::secret::SecretChannels>>,
+        sessions: Arc<Mutex<super::session::Sessions<T>>>,
+        registry: Arc<super::Registry>,
+        terminator: Option<Arc<super::terminate::Terminator>>,
+        auth: Option<Arc<super::auth::Authenticator>>,
         stream: stnet::AcceptedStream<T>,
     ) -> ClientHandler<T> {
         let (tx, rx) = mpsc::channel(config.channel_limits.core);
         let (peer_addr, stream) = stream;
         ClientHandler {
+            id: registry.next_client_id(),
+            drain: registry.drain_token().child_token(),
+            registry,
+            terminator,
+            auth,
             js: JoinSet::new(),
             peer_addr,
             transport: stnet::Transport::new(config.timeouts.clone(), stream),
             token,
             active_tunnels,
+            vhosts,
+            secrets,
+            sessions,
+            session: None,
+            replay: stnet::Replay::default(),
+            inform_client: true,
             config,
             to_tunnels: Arc::new(HashMap::new().into()),
+            tunnels: HashMap::new(),
+            hosts: HashMap::new(),
+            host_js: JoinSet::new(),
+            health: HashMap::new(),
+            host_health: HashMap::new(),
+            forwards: JoinSet::new(),
             to_client: tx,
             from_tunnels: rx,
         }
     }
 
     /// Validate the client and create external listeners
+    #[tracing::instrument(name = "handshake", level = "info", skip_all)]
     async fn auth(&mut self) -> ClientResult<()> {
         info!(addr = ?self.peer_addr, "accepted connection from client");
 
@@ -107,61 +181,587 @@ where
         Ok(())
     }
 
-    async fn validate_tunnels(&mut self) -> crate::Result<Vec<u16>> {
-        let tunnels = match self.transport.read_frame().await? {
+    #[tracing::instrument(name = "register_tunnels", level = "info", skip_all)]
+    async fn make_tunnels(&mut self, frame: stnet::Frame) -> crate::Result<()> {
+        // Ports held by other clients are rejected one by one below, like
+        // they are with AddTunnels
+        let tunnels = match frame {
             stnet::Frame::Tunnels(t) => t,
             _ => return Err(stnet::Error::UnexpectedFrame.into()),
         };
+        let results = self.spawn_tunnels(&tunnels).await;
+        let held = self.held_tunnels();
+        self.registry.add_client(
+            self.id,
+            self.peer_addr.clone(),
+            self.config.transport.clone(),
+            held.clone(),
+            self.token.clone(),
+        );
+
+        // Ack the config with the ports we managed to bind, then tell the
+        // client why the others failed
+        self.transport
+            .write_frame(stnet::Frame::Tunnels(held))
+            .await?;
+        if self.config.session_grace.is_some() {
+            let token = stnet::SessionToken::generate();
+            self.transport
+                .write_frame(stnet::Frame::Session(token))
+                .await?;
+            let (tx, rx) = mpsc::channel(1);
+            self.sessions.lock().unwrap().insert(token, tx);
+            self.session = Some((token, rx));
+            self.replay.keep();
+        }
+        let rejected: Vec<_> = results
+            .into_iter()
+            .filter(|r| matches!(r, stnet::TunnelResult::Rejected(..)))
+            .collect();
+        if !rejected.is_empty() {
+            self.transport
+                .write_frame(stnet::Frame::TunnelResults(rejected))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Bind and start a TunnelSupervisor for each port, unless it is held
+    /// by another client or the bind fails. Port 0 is assigned a free port
+    /// from the ephemeral pool
+    async fn spawn_tunnels(&mut self, ports: &[u16]) -> Vec<stnet::TunnelResult> {
+        use stnet::TunnelResult;
+
+        let mut results = Vec::with_capacity(ports.len());
+        for port in ports.iter().copied() {
+            if port == 0 {
+                results.push(match self.assign_port().await {
+                    Err(reason) => TunnelResult::Rejected(0, reason),
+                    Ok((port, external_listener)) => {
+                        info!(port = port, "assigned ephemeral port");
+                        self.start_tunnel(port, external_listener);
+                        TunnelResult::Assigned(port)
+                    }
+                });
+                continue;
+            }
+            if self.tunnels.contains_key(&port) {
+                results.push(TunnelResult::Added(port));
+                continue;
+            }
+            // Reserve the port before binding so no other client can race
+            // us for it
+            if !self.active_tunnels.lock().unwrap().insert(port) {
+                results.push(TunnelResult::Rejected(
+                    port,
+                    "in use by another client".to_string(),
+                ));
+                continue;
+            }
+            let external_listener = match super::TunnelSupervisor::bind(port).await {
+                Err(e) => {
+                    error!(cause = ?e, port = port, "tunnel creation error");
+                    self.active_tunnels.lock().unwrap().remove(&port);
+                    results.push(TunnelResult::Rejected(port, format!("bind failed: {e}")));
+                    continue;
+                }
+                Ok(l) => l,
+            };
+            self.start_tunnel(port, external_listener);
+            results.push(TunnelResult::Added(port));
+        }
+        results
+    }
+
+    /// Reserve and bind the first free port of the ephemeral pool
+    async fn assign_port(&mut self) -> Result<(u16, tnet::TcpListener), String> {
+        let Some(pool) = self.config.ephemeral_ports else {
+            return Err("no ephemeral port pool configured".to_string());
+        };
+        for port in pool.iter() {
+            if !self.active_tunnels.lock().unwrap().insert(port) {
+                continue;
+            }
+            match super::TunnelSupervisor::bind(port).await {
+                Ok(l) => return Ok((port, l)),
+                Err(e) => {
+                    trace!(cause = ?e, port = port, "ephemeral port unavailable");
+                    self.active_tunnels.lock().unwrap().remove(&port);
+                }
+            }
+        }
+        Err("ephemeral port pool exhausted".to_string())
+    }
 
-        let bad_tunnels: Vec<u16> = {
-            let active_tunnels = self.active_tunnels.lock().unwrap();
-            tunnels
-                .iter()
-                .filter(|x| active_tunnels.contains(x))
-                .cloned()
-                .collect()
+    fn supervisor(&self, port: u16, token: CancellationToken) -> super::TunnelSupervisor {
+        super::TunnelSupervisor::new(
+            self.config.clone(),
+            port,
+            token,
+            self.drain.clone(),
+            self.to_tunnels.clone(),
+            self.to_client.clone(),
+            self.registry.clone(),
+            self.id,
+            self.auth.clone(),
+        )
+    }
+
+    /// Run a TunnelSupervisor for an already reserved and bound port
+    fn start_tunnel(&mut self, port: u16, external_listener: tnet::TcpListener) {
+        let token = self.token.child_token();
+        let h = self.supervisor(port, token.clone());
+        let listener = match self.terminator.as_ref().and_then(|t| t.acceptor(port)) {
+            None => super::Listener::Tcp(external_listener),
+            Some(acceptor) => super::Listener::Tls(external_listener, acceptor),
         };
+        self.run_tunnel(port, h, token, listener);
+    }
 
-        if !bad_tunnels.is_empty() {
-            self.transport.write_frame(stnet::Frame::Kthxbai).await?;
-            return Err(ClientValidationError::DuplicateTunnels {
-                tunnels: bad_tunnels,
+    fn run_tunnel(
+        &mut self,
+        port: u16,
+        mut h: super::TunnelSupervisor,
+        token: CancellationToken,
+        listener: super::Listener,
+    ) {
+        self.health.insert(port, h.health());
+        let handle = self.js.spawn(async move {
+            trace!(port = ?port, "external listener start");
+            let ret = h.run(listener).await;
+            trace!(port = ?port, "external listener end");
+            (port, ret)
+        });
+        self.tunnels.insert(port, (handle, token));
+    }
+
+    /// Join the named pool of clients sharing each port, creating the pool
+    /// if the port is free. A port held by another client alone, or by a
+    /// pool of another name, is rejected
+    async fn join_pools(&mut self, pools: &[(u16, String)]) -> Vec<stnet::TunnelResult> {
+        use stnet::TunnelResult;
+
+        let mut results = Vec::with_capacity(pools.len());
+        for (port, name) in pools.iter() {
+            let port = *port;
+            if self.tunnels.contains_key(&port) {
+                results.push(TunnelResult::Added(port));
+                continue;
             }
-            .into());
+            let (tx, rx) = mpsc::channel(self.config.channel_limits.core);
+            let token = self.token.child_token();
+            let h = self.supervisor(port, token.clone());
+            // A new pool's listener is bound once it is reserved, so that no
+            // other client can race us for the port
+            let new_pool = {
+                let mut active_tunnels = self.active_tunnels.lock().unwrap();
+                match active_tunnels.pool_mut(port) {
+                    Some(pool) if pool.name() != name => {
+                        results.push(TunnelResult::Rejected(
+                            port,
+                            format!("in use by pool {:?}", pool.name()),
+                        ));
+                        continue;
+                    }
+                    Some(pool) => {
+                        pool.join(self.id, tx, h.live(), h.health());
+                        None
+                    }
+                    None => {
+                        let mut pool = super::pool::Pool::new(name, self.config.pool_balance);
+                        let pool_token = pool.token();
+                        pool.join(self.id, tx, h.live(), h.health());
+                        if !active_tunnels.insert_pool(port, pool) {
+                            results.push(TunnelResult::Rejected(
+                                port,
+                                "in use by another client".to_string(),
+                            ));
+                            continue;
+                        }
+                        Some(pool_token)
+                    }
+                }
+            };
+            if let Some(pool_token) = new_pool {
+                let external_listener = match super::TunnelSupervisor::bind(port).await {
+                    Err(e) => {
+                        error!(cause = ?e, port = port, "pool creation error");
+                        // Takes down anybody that joined in the meantime
+                        self.active_tunnels
+                            .lock()
+                            .unwrap()
+                            .remove_pool(port, self.id);
+                        results.push(TunnelResult::Rejected(port, format!("bind failed: {e}")));
+                        continue;
+                    }
+                    Ok(l) => l,
+                };
+                info!(port = port, pool = name, "created pool");
+                tokio::spawn(super::pool::serve(
+                    port,
+                    external_listener,
+                    self.active_tunnels.clone(),
+                    pool_token,
+                ));
+            }
+            let acceptor = self.terminator.as_ref().and_then(|t| t.acceptor(port));
+            self.run_tunnel(port, h, token, super::Listener::Pool { rx, acceptor });
+            results.push(TunnelResult::Added(port));
         }
-        self.transport
-            .write_frame(stnet::Frame::Tunnels(tunnels.clone()))
-            .await?; // Ack the config
+        results
+    }
 
-        Ok(tunnels)
+    /// Start a TunnelSupervisor for each hostname, fed by the matching
+    /// shared listener, unless it is held by another client
+    fn spawn_hosts(&mut self, hosts: &[stnet::Vhost]) -> Vec<stnet::TunnelResult> {
+        use stnet::{TunnelResult, VhostKind};
+
+        let mut results = Vec::with_capacity(hosts.len());
+        for vhost in hosts.iter() {
+            let addr = match vhost.kind {
+                VhostKind::Http => self
+                    .config
+                    .http_addr
+                    .ok_or("no HTTP vhost listener configured"),
+                VhostKind::Sni => self.config.sni_addr.ok_or("no SNI listener configured"),
+                VhostKind::Secret => Err("secret tunnels must be added with their secret"),
+            };
+            let addr = match addr {
+                Err(reason) => {
+                    results.push(TunnelResult::HostRejected(
+                        vhost.clone(),
+                        reason.to_string(),
+                    ));
+                    continue;
+                }
+                Ok(a) => a,
+            };
+            if self.hosts.contains_key(vhost) {
+                results.push(TunnelResult::HostAdded(vhost.clone()));
+                continue;
+            }
+            let (tx, rx) = mpsc::channel(self.config.channel_limits.core);
+            {
+                let mut vhosts = self.vhosts.lock().unwrap();
+                if vhosts.contains_key(vhost) {
+                    results.push(TunnelResult::HostRejected(
+                        vhost.clone(),
+                        "in use by another client".to_string(),
+                    ));
+                    continue;
+                }
+                vhosts.insert(vhost.clone(), tx);
+            }
+            let listener = super::Listener::Vhost {
+                vhost: vhost.clone(),
+                rx,
+            };
+            self.run_host(vhost.clone(), addr.port(), listener);
+            results.push(TunnelResult::HostAdded(vhost.clone()));
+        }
+        results
     }
 
-    async fn make_tunnels(&mut self) -> crate::Result<HashMap<u16, tokio::task::AbortHandle>> {
-        let tunnels = self.validate_tunnels().await?;
+    /// Start a TunnelSupervisor for each secret tunnel, fed by the visitors
+    /// that ask for it with the right secret, unless its name is held by
+    /// another client
+    fn spawn_secrets(&mut self, secrets: Vec<(String, stnet::Secret)>) -> Vec<stnet::TunnelResult> {
+        use stnet::{TunnelResult, VhostKind};
 
-        let mut tunnel_handlers: HashMap<u16, _> = HashMap::new();
-        let mut active_tunnels = self.active_tunnels.lock().unwrap();
-        tunnels.iter().for_each(|t| {
-            let to_client = self.to_client.clone();
-            let to_tunnels = self.to_tunnels.clone();
-            let port = *t;
-            let mtu = self.config.as_ref().mtu;
-            let token = self.token.clone();
-            let cfg = self.config.clone();
-            let h = self.js.spawn(async move {
-                trace!(port = ?port, "external listener start");
-                let mut h =
-                    super::TunnelSupervisor::new(cfg, port, mtu, token, to_tunnels, to_client);
-                if let Err(e) = h.run().await {
-                    error!(cause = ?e, port = port, "tunnel creation error");
+        let mut results = Vec::with_capacity(secrets.len());
+        for (name, secret) in secrets {
+            let vhost = stnet::Vhost {
+                kind: VhostKind::Secret,
+                hostname: name.clone(),
+            };
+            if self.hosts.contains_key(&vhost) {
+                results.push(TunnelResult::HostAdded(vhost));
+                continue;
+            }
+            let (tx, rx) = mpsc::channel(self.config.channel_limits.core);
+            {
+                let mut tunnels = self.secrets.lock().unwrap();
+                if tunnels.contains_key(&name) {
+                    results.push(TunnelResult::HostRejected(
+                        vhost,
+                        "in use by another client".to_string(),
+                    ));
+                    continue;
                 }
-                trace!(port = ?port, "external listener end");
-                port
-            });
-            tunnel_handlers.insert(port, h);
-            active_tunnels.insert(port);
+                tunnels.insert(name, super::secret::SecretTunnel { secret, tx });
+            }
+            let listener = super::Listener::Secret {
+                vhost: vhost.clone(),
+                rx,
+            };
+            // There's no port to speak of
+            self.run_host(vhost.clone(), 0, listener);
+            results.push(TunnelResult::HostAdded(vhost));
+        }
+        results
+    }
+
+    fn run_host(&mut self, vhost: stnet::Vhost, port: u16, listener: super::Listener) {
+        let token = self.token.child_token();
+        let mut h = self.supervisor(port, token.clone());
+        self.host_health.insert(vhost.clone(), h.health());
+        let v = vhost.clone();
+        let handle = self.host_js.spawn(async move {
+            trace!(host = %v, "vhost start");
+            let ret = h.run(listener).await;
+            trace!(host = %v, "vhost end");
+            (v, ret)
         });
-        Ok(tunnel_handlers)
+        self.hosts.insert(vhost, (handle, token));
+    }
+
+    /// Stop routing Externals, or visitors, for `vhost` to this client
+    fn release_host(&self, vhost: &stnet::Vhost) {
+        match vhost.kind {
+            stnet::VhostKind::Secret => {
+                self.secrets.lock().unwrap().remove(&vhost.hostname);
+            }
+            _ => {
+                self.vhosts.lock().unwrap().remove(vhost);
+            }
+        }
+    }
+
+    fn remove_hosts(&mut self, hosts: &[stnet::Vhost]) -> Vec<stnet::TunnelResult> {
+        use stnet::TunnelResult;
+
+        hosts
+            .iter()
+            .map(|vhost| match self.hosts.remove(vhost) {
+                None => {
+                    TunnelResult::HostRejected(vhost.clone(), "not held by this client".to_string())
+                }
+                Some((_, token)) => {
+                    token.cancel();
+                    self.release_host(vhost);
+                    self.host_health.remove(vhost);
+                    TunnelResult::HostRemoved(vhost.clone())
+                }
+            })
+            .collect()
+    }
+
+    fn held_hosts(&self) -> Vec<stnet::Vhost> {
+        let mut hosts: Vec<_> = self.hosts.keys().cloned().collect();
+        hosts.sort();
+        hosts
+    }
+
+    /// Stop the TunnelSupervisors for `ports`. Live connections on those
+    /// ports are closed
+    fn remove_tunnels(&mut self, ports: &[u16]) -> Vec<stnet::TunnelResult> {
+        use stnet::TunnelResult;
+
+        let mut active_tunnels = self.active_tunnels.lock().unwrap();
+        ports
+            .iter()
+            .map(|port| match self.tunnels.remove(port) {
+                None => TunnelResult::Rejected(*port, "not held by this client".to_string()),
+                Some((_, token)) => {
+                    token.cancel();
+                    active_tunnels.release(*port, self.id);
+                    self.health.remove(port);
+                    TunnelResult::Removed(*port)
+                }
+            })
+            .collect()
+    }
+
+    /// Forget a tunnel whose TunnelSupervisor exited on its own and let the
+    /// client know. The rest of the session carries on
+    async fn tunnel_closed(&mut self, port: u16, reason: String) -> stnet::Result<()> {
+        error!(port = port, reason = reason, "tunnel closed");
+        self.tunnels.remove(&port);
+        self.health.remove(&port);
+        self.active_tunnels.lock().unwrap().release(port, self.id);
+        self.registry.set_tunnels(self.id, self.held_tunnels());
+        self.transport
+            .write_frame(stnet::Frame::TunnelClosed(port, reason))
+            .await
+    }
+
+    /// Forget a tunnel that finished draining
+    fn tunnel_drained(&mut self, port: u16) {
+        if self.tunnels.remove(&port).is_some() {
+            self.active_tunnels.lock().unwrap().release(port, self.id);
+        }
+        self.health.remove(&port);
+        self.registry.set_tunnels(self.id, self.held_tunnels());
+    }
+
+    /// Same as above, for hostnames
+    fn host_drained(&mut self, host: &stnet::Vhost) {
+        if self.hosts.remove(host).is_some() {
+            self.release_host(host);
+        }
+        self.host_health.remove(host);
+        self.registry.set_hosts(self.id, self.held_hosts());
+    }
+
+    /// Whether every tunnel and forward of a draining client is done
+    fn drained(&self) -> bool {
+        self.js.is_empty() && self.host_js.is_empty() && self.forwards.is_empty()
+    }
+
+    /// Record the health of the Internals the client reported on. Reports
+    /// for tunnels this client doesn't hold are ignored
+    fn set_health<K>(health: &HashMap<K, Arc<AtomicBool>>, reports: &[(K, bool)])
+    where
+        K: std::hash::Hash + Eq + std::fmt::Display,
+    {
+        for (tunnel, healthy) in reports.iter() {
+            let Some(flag) = health.get(tunnel) else {
+                continue;
+            };
+            if flag.swap(*healthy, Ordering::Relaxed) == *healthy {
+                continue;
+            }
+            match healthy {
+                true => info!(tunnel = %tunnel, "client reports the Internal is up again"),
+                false => {
+                    warn!(tunnel = %tunnel, "client reports the Internal is down, turning away connections")
+                }
+            }
+        }
+    }
+
+    /// Open a connection to `target` for the connection `id` that the client
+    /// accepted on one of its local forwards, and shuffle data between the
+    /// two. The client is told to drop it if the target isn't allowed or
+    /// can't be reached
+    async fn start_forward(&mut self, id: SocketAddr, target: String) -> stnet::Result<()> {
+        use crate::redirector::Redirector;
+
+        if !self.config.forward_targets.contains(&target) {
+            warn!(target = target, for_ = ?id, "refused forward to a target that isn't allowed");
+            let kill = stnet::RedirectorFrame::KillListener(id);
+            return self.write_redirector(kill).await;
+        }
+        // Data the client sends while we connect waits in the channel
+        let (to_tunnel, from_client) = mpsc::channel(self.config.channel_limits.core);
+        self.to_tunnels.lock().unwrap().insert(id, to_tunnel);
+
+        let tunnels = self.to_tunnels.clone();
+        let to_client = self.to_client.clone();
+        let token = self.token.child_token();
+        let mtu = self.config.mtu;
+        let port = crate::config::target_port(&target).unwrap_or_default();
+        self.forwards.spawn(async move {
+            let connect = tnet::TcpStream::connect(target.as_str());
+            let stream = match tokio::time::timeout(FORWARD_CONNECT_TIMEOUT, connect).await {
+                Err(_) => Err("timed out".to_string()),
+                Ok(ret) => ret.map_err(|e| e.to_string()),
+            };
+            match stream {
+                Err(cause) => {
+                    warn!(cause = cause, target = target, for_ = ?id, "failed to connect to forward target");
+                    tunnels.lock().unwrap().remove(&id);
+                    let _ = to_client
+                        .send(stnet::RedirectorFrame::KillListener(id))
+                        .await;
+                }
+                Ok(stream) => {
+                    info!(target = target, for_ = ?id, "forwarding connection");
+                    let mut r = Redirector::with_stream(
+                        id,
+                        port,
+                        mtu,
+                        token,
+                        stream,
+                        to_client,
+                        from_client,
+                    );
+                    r.run().await;
+                    tunnels.lock().unwrap().remove(&id);
+                    trace!(target = target, for_ = ?id, "forward closed");
+                }
+            }
+        }.in_current_span());
+        Ok(())
+    }
+
+    /// Splice the connection `id` that the client accepted on one of its
+    /// visitors to the secret tunnel `name` of another client. The client is
+    /// told to drop it if there is no such tunnel or the secret is wrong
+    async fn start_visit(
+        &mut self,
+        id: SocketAddr,
+        name: String,
+        secret: stnet::Secret,
+    ) -> stnet::Result<()> {
+        use crate::redirector::Redirector;
+
+        let tx = match self.secrets.lock().unwrap().get(&name) {
+            Some(t) if t.secret.matches(&secret) => Some(t.tx.clone()),
+            _ => None,
+        };
+        let refused = match tx {
+            None => Some("refused visitor of an unknown secret tunnel, or with a wrong secret"),
+            // The tunnel tells its connections apart by this address
+            Some(_) if self.registry.has_connection(&id) => {
+                Some("refused visitor whose address is in use")
+            }
+            Some(_) => None,
+        };
+        if let Some(reason) = refused {
+            warn!(secret_name = name, for_ = ?id, "{reason}");
+            let kill = stnet::RedirectorFrame::KillListener(id);
+            return self.write_redirector(kill).await;
+        }
+        let (ours, theirs) = tokio::io::duplex(super::secret::PIPE_BUFFER);
+        // Waiting on a busy tunnel would hold up everything else of ours
+        let refused = match tx.unwrap().try_send((theirs, id)) {
+            Ok(()) => None,
+            Err(mpsc::error::TrySendError::Full(_)) => Some("secret tunnel is busy"),
+            Err(mpsc::error::TrySendError::Closed(_)) => Some("secret tunnel went away"),
+        };
+        if let Some(reason) = refused {
+            warn!(secret_name = name, for_ = ?id, "{reason}");
+            let kill = stnet::RedirectorFrame::KillListener(id);
+            return self.write_redirector(kill).await;
+        }
+        info!(secret_name = name, for_ = ?id, "visiting secret tunnel");
+
+        let (to_tunnel, from_client) = mpsc::channel(self.config.channel_limits.core);
+        self.to_tunnels.lock().unwrap().insert(id, to_tunnel);
+        let mut r = Redirector::with_stream(
+            id,
+            0,
+            self.config.mtu,
+            self.token.child_token(),
+            ours,
+            self.to_client.clone(),
+            from_client,
+        );
+        let tunnels = self.to_tunnels.clone();
+        self.forwards.spawn(
+            async move {
+                r.run().await;
+                tunnels.lock().unwrap().remove(&id);
+                trace!(secret_name = name, for_ = ?id, "visit closed");
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+
+    /// Find the port of the tunnel run by the task `id`, if it is still current
+    fn tunnel_port(&self, id: tokio::task::Id) -> Option<u16> {
+        self.tunnels
+            .iter()
+            .find(|(_, (h, _))| h.id() == id)
+            .map(|(port, _)| *port)
+    }
+
+    fn held_tunnels(&self) -> Vec<u16> {
+        let mut ports: Vec<_> = self.tunnels.keys().copied().collect();
+        ports.sort();
+        ports
     }
 
     fn get_tunnel_tx(&self, id: SocketAddr) -> Option<mpsc::Sender<stnet::RedirectorFrame>> {
@@ -169,16 +769,208 @@ where
         to_tunnels.get(&id).cloned()
     }
 
-    #[tracing::instrument(name = "Server", level = "info", skip_all)]
-    pub async fn run(&mut self) -> crate::Result<()> {
-        use tokio::time;
+    /// Send a Redirector frame to the client, holding on to it until the
+    /// client acknowledges it
+    async fn write_redirector(&mut self, frame: stnet::RedirectorFrame) -> stnet::Result<()> {
+        self.replay.record_sent(&frame);
+        self.transport.write_frame(frame.into()).await
+    }
 
+    async fn resumption(
+        session: &mut Option<(
+            stnet::SessionToken,
+            mpsc::Receiver<super::session::Resumption<T>>,
+        )>,
+    ) -> Option<super::session::Resumption<T>> {
+        match session {
+            None => std::future::pending().await,
+            Some((_, rx)) => rx.recv().await,
+        }
+    }
+
+    /// Carry on with the session over the connection of `r`, sending the
+    /// client what it missed. Returns whether that worked
+    async fn resume(&mut self, r: super::session::Resumption<T>) -> bool {
+        info!(addr = ?r.peer_addr, "client is resuming the session");
+        self.transport = r.transport;
+        self.peer_addr = r.peer_addr;
+        self.registry.set_addr(self.id, self.peer_addr.clone());
+        let Some(missed) = self.replay.since(r.received) else {
+            error!(
+                received = r.received,
+                "what the client missed is gone. Can't resume the session"
+            );
+            let _ = self
+                .transport
+                .write_frame(stnet::Frame::Resumed(None))
+                .await;
+            self.forget_session();
+            return false;
+        };
+        let received = Some(self.replay.received());
+        if let Err(e) = self
+            .transport
+            .write_frame(stnet::Frame::Resumed(received))
+            .await
+        {
+            error!(cause = ?e, "failed to resume the session");
+            return false;
+        }
+        let replayed = missed.len();
+        for frame in missed {
+            if let Err(e) = self.transport.write_frame(frame.into()).await {
+                error!(cause = ?e, "failed to resume the session");
+                return false;
+            }
+        }
+        info!(replayed = replayed, "client resumed the session");
+        true
+    }
+
+    /// Hold on to the session for the grace period, for the client to
+    /// resume it over a new connection. Returns whether it did
+    async fn await_resumption(&mut self) -> bool {
+        let Some(grace) = self.config.session_grace else {
+            return false;
+        };
+        if !self.replay.resumable() || self.token.is_cancelled() || self.drain.is_cancelled() {
+            return false;
+        }
+        info!(grace = ?grace, "holding on to the session for the client to resume");
+        let deadline = tokio::time::sleep(grace);
+        tokio::pin!(deadline);
+        loop {
+            let Some((_, rx)) = self.session.as_mut() else {
+                return false;
+            };
+            let r = tokio::select! {
+                maybe_r = rx.recv() => match maybe_r {
+                    None => return false,
+                    Some(r) => r,
+                },
+                _ = &mut deadline => {
+                    info!("client didn't resume the session in time");
+                    return false;
+                }
+                _ = self.token.cancelled() => return false,
+                _ = self.drain.cancelled() => return false,
+            };
+            if self.resume(r).await {
+                return true;
+            }
+        }
+    }
+
+    fn forget_session(&mut self) {
+        if let Some((token, _)) = self.session.take() {
+            self.sessions.lock().unwrap().remove(&token);
+        }
+    }
+
+    #[tracing::instrument(name = "Server", level = "info", skip_all, fields(client = self.id))]
+    pub async fn run(mut self) -> crate::Result<()> {
+        let metrics = crate::metrics::metrics();
         if let Err(e) = self.auth().await {
             error!(cause = ?e, "failed to authenticate client");
-            self.transport.write_frame(stnet::Frame::Kthxbai).await?;
+            metrics
+                .handshake_failures
+                .with_label_values(&[e.reason()])
+                .inc();
+            self.transport
+                .write_frame(stnet::Frame::Kthxbai(e.to_string()))
+                .await?;
             return Err(e.into());
         };
-        let handlers = self.make_tunnels().await?;
+        if self.registry.is_draining() {
+            info!("turned away client, the server is shutting down");
+            self.transport
+                .write_frame(stnet::Frame::Kthxbai("server is shutting down".to_string()))
+                .await?;
+            return Ok(());
+        }
+        let mut frame = self.transport.read_frame().await?;
+        if let stnet::Frame::Resume(token, received) = frame {
+            let tx = self.sessions.lock().unwrap().get(&token).cloned();
+            if let Some(tx) = tx {
+                let r = super::session::Resumption {
+                    peer_addr: self.peer_addr.clone(),
+                    transport: self.transport,
+                    received,
+                };
+                match tx.send(r).await {
+                    Ok(()) => {
+                        info!("handed the connection to the session it resumes");
+                        return Ok(());
+                    }
+                    // The session ended in the meantime
+                    Err(mpsc::error::SendError(r)) => self.transport = r.transport,
+                }
+            }
+            info!("client asked to resume a session that is gone. Starting a new one");
+            self.transport
+                .write_frame(stnet::Frame::Resumed(None))
+                .await?;
+            frame = self.transport.read_frame().await?;
+        }
+        self.make_tunnels(frame).await?;
+        metrics.connected_clients.inc();
+        let peer = self.peer_addr.to_string();
+        let occupancy = metrics
+            .channel_occupancy
+            .with_label_values(&["from_tunnels", &peer]);
+
+        // The session outlives its connection for as long as the client
+        // keeps coming back to resume it
+        let ret = loop {
+            let ret = self.serve(&occupancy).await;
+            if ret.is_err() && self.await_resumption().await {
+                continue;
+            }
+            break ret;
+        };
+
+        self.forget_session();
+        if self.inform_client {
+            let reason = if self.registry.is_draining() {
+                "server is shutting down"
+            } else if self.drain.is_cancelled() {
+                "drained"
+            } else {
+                "session closed by the server"
+            };
+            let frame = stnet::Frame::Kthxbai(reason.to_string());
+            if let Err(e) = self.transport.write_frame(frame).await {
+                error!(e=?e, "failed to inform client of shutdown");
+            }
+        }
+        {
+            let mut active_tunnels = self.active_tunnels.lock().unwrap();
+            trace!(tunnels = ?active_tunnels.iter(), "cleaning up tunnels");
+            for (t, (h, _)) in self.tunnels.drain() {
+                h.abort();
+                active_tunnels.release(t, self.id);
+            }
+            for (host, (h, _)) in std::mem::take(&mut self.hosts) {
+                h.abort();
+                self.release_host(&host);
+            }
+            self.forwards.abort_all();
+            let mut tunnels = self.to_tunnels.lock().unwrap();
+            tunnels.clear();
+        }
+        self.registry.remove_client(self.id);
+        metrics.connected_clients.dec();
+        let _ = metrics
+            .channel_occupancy
+            .remove_label_values(&["from_tunnels", &peer]);
+        info!("ending stream");
+        ret
+    }
+
+    async fn serve(&mut self, occupancy: &prometheus::IntGauge) -> crate::Result<()> {
+        use tokio::time;
+
+        let metrics = crate::metrics::metrics();
         let mut heartbeat_interval = time::interval(self.config.timeouts.heartbeat_interval);
         // SAFETY: The first .tick() resolves immediately. This ensures
         // that when the loop starts, the next time this interval ticks is
@@ -186,14 +978,15 @@ where
         heartbeat_interval.tick().await;
         let mut last_recv_heartbeat =
             std::time::Instant::now() + self.config.timeouts.heartbeat_interval;
+        let mut last_sent_heartbeat: Option<std::time::Instant> = None;
+        let mut draining = false;
 
-        let mut inform_client = true;
-
-        let ret = loop {
+        loop {
             // XXX You MUST NOT return in this loop
             tokio::select! {
                 _maybe_interval = heartbeat_interval.tick() => {
-                    info!("Channel backpressure: from_tunnels: {}/{}", self.from_tunnels.len(), self.config.channel_limits.core);
+                    occupancy.set(self.from_tunnels.len() as i64);
+                    debug!("Channel backpressure: from_tunnels: {}/{}", self.from_tunnels.len(), self.config.channel_limits.core);
                     if last_recv_heartbeat.elapsed() > 2*self.config.timeouts.heartbeat_interval {
                         error!("Missing heartbeat from client. Killing connection");
                         break Err(stnet::Error::ConnectionDead.into());
@@ -203,7 +996,13 @@ where
                         error!(e = ?e, "failed to send heartbeat");
                         break Err(e.into());
                     }
+                    last_sent_heartbeat = Some(std::time::Instant::now());
                     trace!("sent heartbeat to client");
+                    if let Some(count) = self.replay.ack() {
+                        if let Err(e) = self.transport.write_frame(stnet::Frame::Ack(count)).await {
+                            break Err(e.into());
+                        }
+                    }
                 }
 
                 maybe_rx = self.from_tunnels.recv() => {
@@ -211,7 +1010,7 @@ where
                         None => break Ok(()),
                         Some(data) => data,
                     };
-                    match self.transport.write_frame(rframe.into()).await {
+                    match self.write_redirector(rframe).await {
                         Ok(_) => (),
                         Err(_) => {
                             error!("Write operation timed out");
@@ -234,10 +1033,32 @@ where
                         }
                         Ok(f) => f,
                     };
+                    if let stnet::Frame::Redirector(_) = frame {
+                        if let Some(count) = self.replay.record_received() {
+                            if let Err(e) = self.transport.write_frame(stnet::Frame::Ack(count)).await {
+                                break Err(e.into());
+                            }
+                        }
+                    }
                     match frame {
                         stnet::Frame::Heartbeat => {
                             trace!("heartbeat received from client");
                             last_recv_heartbeat = std::time::Instant::now();
+                            if let Some(sent) = last_sent_heartbeat.take() {
+                                metrics.heartbeat_rtt.observe(sent.elapsed().as_secs_f64());
+                            }
+                        }
+
+                        stnet::Frame::Redirector(stnet::RedirectorFrame::StartForward(id, target)) => {
+                            if let Err(e) = self.start_forward(id, target).await {
+                                break Err(e.into());
+                            }
+                        }
+
+                        stnet::Frame::Redirector(stnet::RedirectorFrame::StartVisit(id, name, secret)) => {
+                            if let Err(e) = self.start_visit(id, name, secret).await {
+                                break Err(e.into());
+                            }
                         }
 
                         stnet::Frame::Redirector(r) => {
@@ -255,29 +1076,204 @@ where
                             }
                         }
 
-                        stnet::Frame::Kthxbai => {
-                            info!("client will shutdown");
-                            inform_client = false;
+                        stnet::Frame::AddTunnels(ports) => {
+                            let results = self.spawn_tunnels(&ports).await;
+                            info!(results = ?results, "client added tunnels");
+                            self.registry.set_tunnels(self.id, self.held_tunnels());
+                            if let Err(e) = self.transport.write_frame(stnet::Frame::TunnelResults(results)).await {
+                                break Err(e.into());
+                            }
+                        }
+
+                        stnet::Frame::JoinPools(pools) => {
+                            let results = self.join_pools(&pools).await;
+                            info!(results = ?results, "client joined pools");
+                            self.registry.set_tunnels(self.id, self.held_tunnels());
+                            if let Err(e) = self.transport.write_frame(stnet::Frame::TunnelResults(results)).await {
+                                break Err(e.into());
+                            }
+                        }
+
+                        stnet::Frame::RemoveTunnels(ports) => {
+                            let results = self.remove_tunnels(&ports);
+                            info!(results = ?results, "client removed tunnels");
+                            self.registry.set_tunnels(self.id, self.held_tunnels());
+                            if let Err(e) = self.transport.write_frame(stnet::Frame::TunnelResults(results)).await {
+                                break Err(e.into());
+                            }
+                        }
+
+                        stnet::Frame::AddHosts(hosts) => {
+                            let results = self.spawn_hosts(&hosts);
+                            info!(results = ?results, "client added hosts");
+                            self.registry.set_hosts(self.id, self.held_hosts());
+                            if let Err(e) = self.transport.write_frame(stnet::Frame::TunnelResults(results)).await {
+                                break Err(e.into());
+                            }
+                        }
+
+                        stnet::Frame::AddSecrets(secrets) => {
+                            let results = self.spawn_secrets(secrets);
+                            info!(results = ?results, "client added secret tunnels");
+                            self.registry.set_hosts(self.id, self.held_hosts());
+                            if let Err(e) = self.transport.write_frame(stnet::Frame::TunnelResults(results)).await {
+                                break Err(e.into());
+                            }
+                        }
+
+                        stnet::Frame::RemoveHosts(hosts) => {
+                            let results = self.remove_hosts(&hosts);
+                            info!(results = ?results, "client removed hosts");
+                            self.registry.set_hosts(self.id, self.held_hosts());
+                            if let Err(e) = self.transport.write_frame(stnet::Frame::TunnelResults(results)).await {
+                                break Err(e.into());
+                            }
+                        }
+
+                        stnet::Frame::TunnelHealth(ports) => Self::set_health(&self.health, &ports),
+
+                        stnet::Frame::HostHealth(hosts) => Self::set_health(&self.host_health, &hosts),
+
+                        stnet::Frame::Ping => {
+                            if let Err(e) = self.transport.write_frame(stnet::Frame::Pong).await {
+                                break Err(e.into());
+                            }
+                        }
+
+                        stnet::Frame::Kthxbai(reason) => {
+                            info!(reason = reason, "client ended the session");
+                            self.inform_client = false;
                             break Ok(())
                         }
 
+                        stnet::Frame::Ack(count) => self.replay.acknowledged(count),
+
+                        stnet::Frame::Drain => {
+                            info!("client asked to drain");
+                            self.drain.cancel();
+                        }
+
                         f => {
                             error!(frame = ?f, "unexpected frame");
                         }
                     };
                 }
 
-                maybe_js = self.js.join_next() => {
-                    match maybe_js {
-                        None => break Ok(()), // TODO should this be error?
-                        Some(Err(e)) => break Err(e.into()),
-                        Some(Ok(port)) => {
-                            let mut g = self.active_tunnels.lock().unwrap();
-                            g.remove(&port);
-                            // This is probably an awful idea
-                            // We want the client to be forced to reconnect if any tunnel dies
+                // A TunnelSupervisor exited. Tunnels removed at the client's
+                // request are already forgotten, anything else only takes
+                // down that one tunnel
+                maybe_js = self.js.join_next_with_id(), if !self.js.is_empty() => {
+                    if self.drain.is_cancelled() {
+                        match maybe_js {
+                            Some(Ok((_, (port, _)))) => self.tunnel_drained(port),
+                            Some(Err(e)) => {
+                                error!(cause = ?e, "tunnel task failed while draining");
+                                if let Some(port) = self.tunnel_port(e.id()) {
+                                    self.tunnel_drained(port);
+                                }
+                            }
+                            None => {}
+                        }
+                        if self.drained() {
+                            break Ok(());
+                        }
+                        continue;
+                    }
+                    let (port, reason) = match maybe_js {
+                        None => continue,
+                        Some(Err(e)) => match self.tunnel_port(e.id()) {
+                            None => continue,
+                            Some(port) => (port, format!("tunnel task failed: {e}")),
+                        },
+                        Some(Ok((task_id, (port, ret)))) => {
+                            if self.tunnel_port(task_id) != Some(port) {
+                                continue;
+                            }
+                            match ret {
+                                Err(e) => (port, e.to_string()),
+                                Ok(()) => (port, "listener closed".to_string()),
+                            }
+                        }
+                    };
+                    if let Err(e) = self.tunnel_closed(port, reason).await {
+                        break Err(e.into());
+                    }
+                }
+
+                // Same as above, for hostnames
+                maybe_js = self.host_js.join_next_with_id(), if !self.host_js.is_empty() => {
+                    let Some(ret) = maybe_js else { continue };
+                    if self.drain.is_cancelled() {
+                        match ret {
+                            Ok((_, (host, _))) => self.host_drained(&host),
+                            Err(e) => {
+                                error!(cause = ?e, "vhost task failed while draining");
+                                let host = self
+                                    .hosts
+                                    .iter()
+                                    .find(|(_, (h, _))| h.id() == e.id())
+                                    .map(|(host, _)| host.clone());
+                                if let Some(host) = host {
+                                    self.host_drained(&host);
+                                }
+                            }
+                        }
+                        if self.drained() {
                             break Ok(());
                         }
+                        continue;
+                    }
+                    let task_id = match ret {
+                        Err(ref e) => e.id(),
+                        Ok((id, _)) => id,
+                    };
+                    let Some(host) = self
+                        .hosts
+                        .iter()
+                        .find(|(_, (h, _))| h.id() == task_id)
+                        .map(|(host, _)| host.clone())
+                    else {
+                        continue;
+                    };
+                    let reason = match ret {
+                        Err(e) => format!("tunnel task failed: {e}"),
+                        Ok((_, (_, Err(e)))) => e.to_string(),
+                        Ok((_, (_, Ok(())))) => "listener closed".to_string(),
+                    };
+                    error!(host = %host, reason = reason, "vhost closed");
+                    self.hosts.remove(&host);
+                    self.host_health.remove(&host);
+                    self.release_host(&host);
+                    self.registry.set_hosts(self.id, self.held_hosts());
+                    let frame = stnet::Frame::TunnelResults(vec![stnet::TunnelResult::HostRejected(host, reason)]);
+                    if let Err(e) = self.transport.write_frame(frame).await {
+                        break Err(e.into());
+                    }
+                }
+
+                // The client came back over a new connection before we
+                // noticed the old one was gone
+                Some(r) = Self::resumption(&mut self.session) => {
+                    if !self.resume(r).await {
+                        break Err(stnet::Error::ConnectionDead.into());
+                    }
+                    last_recv_heartbeat = std::time::Instant::now();
+                }
+
+                // A forward ended. It cleans up after itself
+                _ = self.forwards.join_next(), if !self.forwards.is_empty() => {
+                    if self.drain.is_cancelled() && self.drained() {
+                        break Ok(());
+                    }
+                }
+
+                // The tunnels stop taking new Externals, and end once their
+                // live connections are done
+                _ = self.drain.cancelled(), if !draining => {
+                    info!("draining");
+                    draining = true;
+                    if self.drained() {
+                        break Ok(());
                     }
                 }
 
@@ -286,24 +1282,6 @@ where
                     break Ok(())
                 }
             }
-        };
-
-        if inform_client {
-            if let Err(e) = self.transport.write_frame(stnet::Frame::Kthxbai).await {
-                error!(e=?e, "failed to inform client of shutdown");
-            }
-        }
-        {
-            let mut active_tunnels = self.active_tunnels.lock().unwrap();
-            trace!(tunnels = ?active_tunnels.iter(), "cleaning up tunnels");
-            for (t, h) in handlers.iter() {
-                h.abort();
-                active_tunnels.remove(t);
-            }
-            let mut tunnels = self.to_tunnels.lock().unwrap();
-            tunnels.clear();
         }
-        info!("ending stream");
-        ret
     }
 }
@@ -8,7 +8,82 @@ use tokio::io::ReadBuf;
 use tokio::sync::mpsc;
 
 pub type TunnelChannels = HashMap<SocketAddr, mpsc::Sender<stnet::RedirectorFrame>>;
-pub type ActiveTunnels = HashSet<u16>;
+
+/// The remote_ports held by clients. A port is either held by a single
+/// client, or shared by the members of a pool
+#[derive(Default)]
+pub struct ActiveTunnels {
+    ports: HashSet<u16>,
+    pools: HashMap<u16, super::pool::Pool>,
+    // Members of pools dropped by remove_pool that haven't let go yet. The
+    // port may be held by somebody else by the time they do
+    orphans: HashSet<(u16, super::ClientId)>,
+}
+
+impl ActiveTunnels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserve `port`. Returns false if it is already held
+    pub fn insert(&mut self, port: u16) -> bool {
+        self.ports.insert(port)
+    }
+
+    pub fn remove(&mut self, port: &u16) -> bool {
+        self.ports.remove(port)
+    }
+
+    pub fn contains(&self, port: &u16) -> bool {
+        self.ports.contains(port)
+    }
+
+    pub fn iter(&self) -> std::collections::hash_set::Iter<'_, u16> {
+        self.ports.iter()
+    }
+
+    pub fn pool_mut(&mut self, port: u16) -> Option<&mut super::pool::Pool> {
+        self.pools.get_mut(&port)
+    }
+
+    /// Reserve `port` for a new pool. Returns false if it is already held
+    pub fn insert_pool(&mut self, port: u16, pool: super::pool::Pool) -> bool {
+        if !self.ports.insert(port) {
+            return false;
+        }
+        self.pools.insert(port, pool);
+        true
+    }
+
+    /// Drop the pool on `port` that `client` failed to create, along with
+    /// the members that joined it in the meantime. Their TunnelSupervisors
+    /// stop once the pool is gone, and the client of each is told the tunnel
+    /// closed
+    pub fn remove_pool(&mut self, port: u16, client: super::ClientId) {
+        if let Some(pool) = self.pools.remove(&port) {
+            pool.token().cancel();
+            self.ports.remove(&port);
+            self.orphans
+                .extend(pool.members().filter(|m| *m != client).map(|m| (port, m)));
+        }
+    }
+
+    /// Let go of `port` on behalf of `client`. A pooled port is only let
+    /// go of, and its listener stopped, once the last member leaves
+    pub fn release(&mut self, port: u16, client: super::ClientId) {
+        if self.orphans.remove(&(port, client)) {
+            return;
+        }
+        if let Some(pool) = self.pools.get_mut(&port) {
+            if !pool.leave(client) {
+                return;
+            }
+            pool.token().cancel();
+            self.pools.remove(&port);
+        }
+        self.ports.remove(&port);
+    }
+}
 
 pub struct QuicBox {
     send: quinn::SendStream,
@@ -1,11 +1,21 @@
+pub mod access;
+pub mod admin;
+pub mod auth;
 mod clientstream;
 mod common;
+mod pool;
 mod quic;
+mod registry;
+mod secret;
+mod session;
 mod tcp;
+pub mod terminate;
 mod tunnel;
+pub mod vhost;
 
 pub use clientstream::*;
 pub use common::*;
 pub use quic::*;
+pub use registry::*;
 pub use tcp::*;
 pub use tunnel::*;
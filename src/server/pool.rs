@@ -0,0 +1,223 @@
+//! Pools of clients serving one remote_port together, for redundancy. The
+//! server owns the port's listener and hands each External to one of the
+//! pool's members, so that the port stays up for as long as any member is
+//! connected.
+use super::common::ActiveTunnels;
+use crate::config::Balance;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net as tnet;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// An External on a pooled port, on its way to a member's TunnelSupervisor
+pub type PoolStream = (tnet::TcpStream, SocketAddr);
+
+struct Member {
+    client: super::ClientId,
+    tx: mpsc::Sender<PoolStream>,
+    // The member's live connections, for least-connections
+    live: Arc<Mutex<HashSet<SocketAddr>>>,
+    // Whether the member's Internal is up
+    healthy: Arc<AtomicBool>,
+}
+
+pub struct Pool {
+    name: String,
+    balance: Balance,
+    members: Vec<Member>,
+    // Where round-robin picks up next
+    next: usize,
+    // Stops the pool's listener
+    token: CancellationToken,
+}
+
+impl Pool {
+    pub fn new(name: &str, balance: Balance) -> Self {
+        Pool {
+            name: name.to_string(),
+            balance,
+            members: Vec::new(),
+            next: 0,
+            token: CancellationToken::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn join(
+        &mut self,
+        client: super::ClientId,
+        tx: mpsc::Sender<PoolStream>,
+        live: Arc<Mutex<HashSet<SocketAddr>>>,
+        healthy: Arc<AtomicBool>,
+    ) {
+        self.members.push(Member {
+            client,
+            tx,
+            live,
+            healthy,
+        });
+    }
+
+    /// The clients in the pool
+    pub fn members(&self) -> impl Iterator<Item = super::ClientId> + '_ {
+        self.members.iter().map(|m| m.client)
+    }
+
+    /// Remove `client` from the pool. Returns whether the pool is empty now
+    pub fn leave(&mut self, client: super::ClientId) -> bool {
+        self.members.retain(|m| m.client != client);
+        self.members.is_empty()
+    }
+
+    // The members in the order they should be offered the next External.
+    // Members whose Internal is down are left out
+    fn candidates(&mut self) -> Vec<mpsc::Sender<PoolStream>> {
+        if self.members.is_empty() {
+            return Vec::new();
+        }
+        let start = self.next % self.members.len();
+        self.next = self.next.wrapping_add(1);
+        let mut members: Vec<_> = self.members[start..]
+            .iter()
+            .chain(self.members[..start].iter())
+            .filter(|m| m.healthy.load(Ordering::Relaxed))
+            .collect();
+        if self.balance == Balance::LeastConnections {
+            // Stable, so that ties go round-robin
+            members.sort_by_key(|m| m.live.lock().unwrap().len());
+        }
+        members.into_iter().map(|m| m.tx.clone()).collect()
+    }
+}
+
+/// Accept Externals on a pooled port and hand each to a member, falling
+/// over to the next one if a member is busy or has gone away, until `token`
+/// is cancelled by the last member leaving
+#[tracing::instrument(name = "Pool", level = "info", skip(listener, active_tunnels, token))]
+pub async fn serve(
+    port: u16,
+    listener: tnet::TcpListener,
+    active_tunnels: Arc<Mutex<ActiveTunnels>>,
+    token: CancellationToken,
+) {
+    info!("pool listening");
+    loop {
+        let external = tokio::select! {
+            maybe_accept = listener.accept() => match maybe_accept {
+                Err(e) => {
+                    error!(cause = ?e, "failed to accept client");
+                    tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                    continue;
+                }
+                Ok(s) => s,
+            },
+            _ = token.cancelled() => break,
+        };
+        let candidates = match active_tunnels.lock().unwrap().pool_mut(port) {
+            None => Vec::new(),
+            Some(pool) => pool.candidates(),
+        };
+        // A member that is busy or gone is passed over rather than waited
+        // on, so that it can't hold up the pool
+        let mut external = Some(external);
+        for tx in candidates {
+            match tx.try_send(external.take().unwrap()) {
+                Ok(()) => break,
+                Err(mpsc::error::TrySendError::Full(e))
+                | Err(mpsc::error::TrySendError::Closed(e)) => external = Some(e),
+            }
+        }
+        if let Some((_, external_addr)) = external {
+            warn!(external_addr = ?external_addr, "no pool member available");
+        }
+    }
+    info!("pool closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(
+        pool: &mut Pool,
+        client: super::super::ClientId,
+        capacity: usize,
+    ) -> mpsc::Receiver<PoolStream> {
+        let (tx, rx) = mpsc::channel(capacity);
+        pool.join(
+            client,
+            tx,
+            Default::default(),
+            Arc::new(AtomicBool::new(true)),
+        );
+        rx
+    }
+
+    #[tokio::test]
+    async fn passes_over_busy_members() {
+        let listener = tnet::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut pool = Pool::new("test", Balance::RoundRobin);
+        let token = pool.token();
+        let busy = member(&mut pool, 1, 1);
+        let mut idle = member(&mut pool, 2, 8);
+        let mut active_tunnels = ActiveTunnels::new();
+        assert!(active_tunnels.insert_pool(addr.port(), pool));
+        let active_tunnels = Arc::new(Mutex::new(active_tunnels));
+        tokio::spawn(serve(
+            addr.port(),
+            listener,
+            active_tunnels.clone(),
+            token.clone(),
+        ));
+
+        // The first member's channel is full and nobody reads it
+        let _first = tnet::TcpStream::connect(addr).await.unwrap();
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while busy.is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await;
+        // Round-robin offers the third External to the busy member first
+        let _second = tnet::TcpStream::connect(addr).await.unwrap();
+        let _third = tnet::TcpStream::connect(addr).await.unwrap();
+        for _ in 0..2 {
+            let got = tokio::time::timeout(std::time::Duration::from_secs(5), idle.recv())
+                .await
+                .unwrap();
+            assert!(got.is_some());
+        }
+        assert_eq!(busy.len(), 1);
+        token.cancel();
+    }
+
+    #[test]
+    fn orphans_of_a_removed_pool_leave_the_port_alone() {
+        let mut pool = Pool::new("test", Balance::RoundRobin);
+        let _creator = member(&mut pool, 1, 1);
+        let _joiner = member(&mut pool, 2, 1);
+        let mut active_tunnels = ActiveTunnels::new();
+        assert!(active_tunnels.insert_pool(1234, pool));
+        active_tunnels.remove_pool(1234, 1);
+        assert!(!active_tunnels.contains(&1234));
+
+        // Somebody else takes the port before the joiner lets go
+        assert!(active_tunnels.insert(1234));
+        active_tunnels.release(1234, 2);
+        assert!(active_tunnels.contains(&1234));
+        active_tunnels.release(1234, 3);
+        assert!(!active_tunnels.contains(&1234));
+    }
+}
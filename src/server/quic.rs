@@ -10,12 +10,23 @@ pub struct QuicServer {
     config: Arc<config::Config>,
     token: CancellationToken,
     active_tunnels: Arc<Mutex<ActiveTunnels>>,
+    vhosts: Arc<Mutex<super::vhost::VhostChannels>>,
+    secrets: Arc<Mutex<super::secret::SecretChannels>>,
+    sessions: Arc<Mutex<super::session::Sessions<Box<QuicBox>>>>,
+    registry: Arc<super::Registry>,
+    terminator: Option<Arc<super::terminate::Terminator>>,
+    auth: Option<Arc<super::auth::Authenticator>>,
     server: quinn::Endpoint,
     handlers: JoinSet<()>,
 }
 
 impl QuicServer {
-    pub fn new(config: config::Config, token: CancellationToken) -> crate::Result<Self> {
+    /// Listens on `socket` if given, on the configured address otherwise
+    pub fn new(
+        config: config::Config,
+        token: CancellationToken,
+        socket: Option<std::net::UdpSocket>,
+    ) -> crate::Result<Self> {
         let mut server_config = match config.crypto {
             None => panic!("programmer error: missing cfg"),
             Some(ref crypto_paths) => {
@@ -30,21 +41,63 @@ impl QuicServer {
         tc.max_idle_timeout(Some(config.timeouts.quic.try_into().unwrap()));
 
         server_config.transport_config(Arc::new(tc));
-        let endpoint = quinn::Endpoint::server(server_config, config.addr).with_context(|_| {
-            stnet::IoSnafu {
-                message: "failed to create quinn endpoint",
-            }
+        let endpoint = match socket {
+            None => quinn::Endpoint::server(server_config, config.addr),
+            Some(socket) => quinn::Endpoint::new(
+                Default::default(),
+                Some(server_config),
+                socket,
+                Arc::new(quinn::TokioRuntime),
+            ),
+        }
+        .with_context(|_| stnet::IoSnafu {
+            message: "failed to create quinn endpoint",
         })?;
 
+        let terminator = match config.tls_termination {
+            None => None,
+            Some(ref t) => Some(Arc::new(super::terminate::Terminator::new(t)?)),
+        };
+        let auth = if config.http_auth.is_empty() {
+            None
+        } else {
+            Some(Arc::new(super::auth::Authenticator::new(
+                &config.http_auth,
+            )?))
+        };
+
         Ok(QuicServer {
             server: endpoint,
             config: config.into(),
             token,
             active_tunnels: Arc::new(ActiveTunnels::new().into()),
+            vhosts: Arc::new(Mutex::new(Default::default())),
+            secrets: Arc::new(Mutex::new(Default::default())),
+            sessions: Arc::new(Mutex::new(Default::default())),
+            registry: Arc::new(super::Registry::new()),
+            terminator,
+            auth,
             handlers: JoinSet::new(),
         })
     }
+    pub fn registry(&self) -> Arc<super::Registry> {
+        self.registry.clone()
+    }
+
+    pub fn vhosts(&self) -> Arc<Mutex<super::vhost::VhostChannels>> {
+        self.vhosts.clone()
+    }
+
+    pub fn terminator(&self) -> Option<Arc<super::terminate::Terminator>> {
+        self.terminator.clone()
+    }
+
+    pub fn auth(&self) -> Option<Arc<super::auth::Authenticator>> {
+        self.auth.clone()
+    }
+
     pub async fn shutdown(&mut self) -> Result<()> {
+        crate::systemd::stopping();
         self.token.cancel();
         while self.handlers.join_next().await.is_some() {
             // intentionally blank
@@ -55,6 +108,11 @@ impl QuicServer {
     #[tracing::instrument(name = "QuicListener", level = "info", skip_all)]
     pub async fn run(&mut self) -> Result<()> {
         info!("TLS enabled. All connections to Clients will be encrypted.");
+        crate::systemd::ready(&self.registry.summary());
+        let mut heartbeat = crate::systemd::Heartbeat::new();
+        // Handshakes run on their own, so that a peer stalling one holds up
+        // neither other Clients nor the watchdog
+        let mut handshakes = JoinSet::new();
         loop {
             let incoming = tokio::select! {
                 maybe_accept = self.server.accept() => {
@@ -67,53 +125,105 @@ impl QuicServer {
                     }
                 }
 
-                _ = self.token.cancelled() => {
-                    return Ok(())
+                Some(handshake) = handshakes.join_next(), if !handshakes.is_empty() => {
+                    let (id, conn) = match handshake {
+                        Err(e) => {
+                            error!(cause = ?e, "quic handshake task failed");
+                            continue;
+                        }
+                        Ok((id, Err(_))) => {
+                            crate::metrics::metrics()
+                                .handshake_failures
+                                .with_label_values(&["quic"])
+                                .inc();
+                            error!(addr = ?id, "Connect timed out");
+                            continue;
+                        }
+                        Ok((id, Ok(Err(e)))) => {
+                            crate::metrics::metrics()
+                                .handshake_failures
+                                .with_label_values(&["quic"])
+                                .inc();
+                            error!(e = ?e, addr = ?id, "Connect failed");
+                            continue;
+                        }
+                        Ok((id, Ok(Ok(conn)))) => (id, conn),
+                    };
+                    self.spawn_handler(id, conn);
+                    continue;
                 }
-            };
-            let id = incoming.orig_dst_cid();
-            let conn = match incoming.await {
-                Err(e) => {
-                    error!(e = ?e, "Connect failed");
+
+                _ = heartbeat.tick() => {
+                    heartbeat.beat(&self.registry.summary());
                     continue;
                 }
-                Ok(conn) => conn,
-            };
 
-            let mut h = QuicStream::new(
-                self.config.clone(),
-                self.token.child_token(),
-                self.active_tunnels.clone(),
-                id,
-                conn,
-            );
-            self.handlers.spawn(async move {
-                trace!(addr = ?id, "client handler start");
-                if let Err(e) = h.run().await {
-                    error!(cause = ?e, addr = ?id, "client connection dropped");
+                _ = self.token.cancelled() => {
+                    return Ok(())
                 }
-                trace!(addr = ?id, "client handler end");
+            };
+            let id = incoming.orig_dst_cid();
+            handshakes.spawn(async move {
+                let timeout = super::terminate::HANDSHAKE_TIMEOUT;
+                (id, tokio::time::timeout(timeout, incoming).await)
             });
         }
         self.shutdown().await?;
         Ok(())
     }
+
+    // Serve the Client connected as `id` over `conn`
+    fn spawn_handler(&mut self, id: quinn::ConnectionId, conn: quinn::Connection) {
+        let mut h = QuicStream::new(
+            self.config.clone(),
+            self.token.child_token(),
+            self.active_tunnels.clone(),
+            self.vhosts.clone(),
+            self.secrets.clone(),
+            self.sessions.clone(),
+            self.registry.clone(),
+            self.terminator.clone(),
+            self.auth.clone(),
+            id,
+            conn,
+        );
+        self.handlers.spawn(async move {
+            trace!(addr = ?id, "client handler start");
+            if let Err(e) = h.run().await {
+                error!(cause = ?e, addr = ?id, "client connection dropped");
+            }
+            trace!(addr = ?id, "client handler end");
+        });
+    }
 }
 
 struct QuicStream {
     config: Arc<config::Config>,
     token: CancellationToken,
     active_tunnels: Arc<Mutex<ActiveTunnels>>,
+    vhosts: Arc<Mutex<super::vhost::VhostChannels>>,
+    secrets: Arc<Mutex<super::secret::SecretChannels>>,
+    sessions: Arc<Mutex<super::session::Sessions<Box<QuicBox>>>>,
+    registry: Arc<super::Registry>,
+    terminator: Option<Arc<super::terminate::Terminator>>,
+    auth: Option<Arc<super::auth::Authenticator>>,
     id: quinn::ConnectionId,
     conn: quinn::Connection,
 
     handlers: JoinSet<()>,
 }
 impl QuicStream {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         config: Arc<config::Config>,
         token: CancellationToken,
         active_tunnels: Arc<Mutex<ActiveTunnels>>,
+        vhosts: Arc<Mutex<super::vhost::VhostChannels>>,
+        secrets: Arc<Mutex<super::secret::SecretChannels>>,
+        sessions: Arc<Mutex<super::session::Sessions<Box<QuicBox>>>>,
+        registry: Arc<super::Registry>,
+        terminator: Option<Arc<super::terminate::Terminator>>,
+        auth: Option<Arc<super::auth::Authenticator>>,
         id: quinn::ConnectionId,
         conn: quinn::Connection,
     ) -> Self {
@@ -121,6 +231,12 @@ impl QuicStream {
             config,
             token,
             active_tunnels,
+            vhosts,
+            secrets,
+            sessions,
+            registry,
+            terminator,
+            auth,
             handlers: JoinSet::new(),
             id,
             conn,
@@ -128,28 +244,34 @@ impl QuicStream {
     }
     #[tracing::instrument(name = "QuicSupervisor", level = "info", skip_all, fields(bind=self.config.addr.to_string()))]
     pub async fn run(&mut self) -> Result<()> {
-        loop {
+        let ret = loop {
             tokio::select! {
                     _ = self.token.cancelled() => {
-                        return Ok(());
+                        break Ok(());
                     }
 
                     stream = self.conn.accept_bi() => {
                     let stream = match stream {
                         Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
-                            return Ok(());
+                            break Ok(());
                         }
                         Err(e) => {
-                            return Err(e.into());
+                            break Err(e.into());
                         }
                         Ok(s) => s,
                     };
                         let id = stnet::StreamId::Quic(self.id, stream.0.id(), stream.1.id());
                         let b = QuicBox::new(stream.0, stream.1);
-                        let mut h = super::ClientHandler::new(
+                        let h = super::ClientHandler::new(
                             self.config.clone(),
                             self.token.child_token(),
                             self.active_tunnels.clone(),
+                            self.vhosts.clone(),
+                            self.secrets.clone(),
+                            self.sessions.clone(),
+                            self.registry.clone(),
+                            self.terminator.clone(),
+                            self.auth.clone(),
                             (id.clone(), Box::new(b)),
                         );
                         self.handlers.spawn(async move {
@@ -163,6 +285,10 @@ impl QuicStream {
                     }
 
             }
-        }
+        };
+        // The clients of this connection may resume their sessions over
+        // another one, so their handlers are left to end on their own
+        while self.handlers.join_next().await.is_some() {}
+        ret
     }
 }
@@ -0,0 +1,294 @@
+use crate::{config::Transport, net as stnet};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+pub type ClientId = u64;
+
+struct ClientEntry {
+    addr: stnet::StreamId,
+    transport: Transport,
+    connected_at: Instant,
+    tunnels: Vec<u16>,
+    hosts: Vec<stnet::Vhost>,
+    token: CancellationToken,
+}
+
+struct ConnectionEntry {
+    client: ClientId,
+    remote_port: u16,
+    started: Instant,
+    token: CancellationToken,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientInfo {
+    pub id: ClientId,
+    pub addr: String,
+    pub transport: Transport,
+    pub uptime_secs: u64,
+    pub tunnels: Vec<u16>,
+    pub hosts: Vec<String>,
+    // How each of `hosts` is routed: "http", "sni" or "secret"
+    pub host_kinds: Vec<&'static str>,
+    pub connections: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TunnelInfo {
+    pub remote_port: u16,
+    pub client: ClientId,
+    pub connections: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionInfo {
+    pub external_addr: SocketAddr,
+    pub remote_port: u16,
+    pub client: ClientId,
+    pub uptime_secs: u64,
+}
+
+/// Book-keeping of every connected client and live External connection on
+/// the server, along with the tokens needed to forcibly shut them down.
+#[derive(Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<ClientId, ClientEntry>>,
+    connections: Mutex<HashMap<SocketAddr, ConnectionEntry>>,
+    // Cancelled once the server stops taking new Externals, to shut down
+    // after the live connections are done
+    draining: CancellationToken,
+    // Notified whenever a client is removed
+    removed: Notify,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn next_client_id(&self) -> ClientId {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Register an authenticated client
+    pub fn add_client(
+        &self,
+        id: ClientId,
+        addr: stnet::StreamId,
+        transport: Transport,
+        tunnels: Vec<u16>,
+        token: CancellationToken,
+    ) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.insert(
+            id,
+            ClientEntry {
+                addr,
+                transport,
+                connected_at: Instant::now(),
+                tunnels,
+                hosts: Vec::new(),
+                token,
+            },
+        );
+    }
+
+    /// Forget a client along with all of its connections
+    pub fn remove_client(&self, id: ClientId) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.remove(&id);
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, c| c.client != id);
+        self.removed.notify_waiters();
+    }
+
+    /// Record the new connection of a client that resumed its session
+    pub fn set_addr(&self, id: ClientId, addr: stnet::StreamId) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(c) = clients.get_mut(&id) {
+            c.addr = addr;
+        }
+    }
+
+    pub fn set_tunnels(&self, id: ClientId, tunnels: Vec<u16>) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(c) = clients.get_mut(&id) {
+            c.tunnels = tunnels;
+        }
+    }
+
+    pub fn set_hosts(&self, id: ClientId, hosts: Vec<stnet::Vhost>) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(c) = clients.get_mut(&id) {
+            c.hosts = hosts;
+        }
+    }
+
+    pub fn add_connection(
+        &self,
+        client: ClientId,
+        remote_port: u16,
+        external_addr: SocketAddr,
+        token: CancellationToken,
+    ) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.insert(
+            external_addr,
+            ConnectionEntry {
+                client,
+                remote_port,
+                started: Instant::now(),
+                token,
+            },
+        );
+    }
+
+    pub fn has_connection(&self, external_addr: &SocketAddr) -> bool {
+        let connections = self.connections.lock().unwrap();
+        connections.contains_key(external_addr)
+    }
+
+    pub fn remove_connection(&self, external_addr: &SocketAddr) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.remove(external_addr);
+    }
+
+    /// Where the client is connecting from, if it's still around
+    pub fn client_addr(&self, id: ClientId) -> Option<String> {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&id).map(|c| c.addr.to_string())
+    }
+
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let clients = self.clients.lock().unwrap();
+        let connections = self.connections.lock().unwrap();
+        let mut ret: Vec<_> = clients
+            .iter()
+            .map(|(id, c)| ClientInfo {
+                id: *id,
+                addr: c.addr.to_string(),
+                transport: c.transport.clone(),
+                uptime_secs: c.connected_at.elapsed().as_secs(),
+                tunnels: c.tunnels.clone(),
+                hosts: c.hosts.iter().map(|v| v.hostname.clone()).collect(),
+                host_kinds: c.hosts.iter().map(|v| v.kind.as_str()).collect(),
+                connections: connections.values().filter(|x| x.client == *id).count(),
+            })
+            .collect();
+        ret.sort_by_key(|c| c.id);
+        ret
+    }
+
+    pub fn tunnels(&self) -> Vec<TunnelInfo> {
+        let clients = self.clients.lock().unwrap();
+        let connections = self.connections.lock().unwrap();
+        let mut ret: Vec<_> = clients
+            .iter()
+            .flat_map(|(id, c)| {
+                c.tunnels.iter().map(|port| TunnelInfo {
+                    remote_port: *port,
+                    client: *id,
+                    connections: connections
+                        .values()
+                        .filter(|x| x.client == *id && x.remote_port == *port)
+                        .count(),
+                })
+            })
+            .collect();
+        ret.sort_by_key(|t| t.remote_port);
+        ret
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let connections = self.connections.lock().unwrap();
+        let mut ret: Vec<_> = connections
+            .iter()
+            .map(|(addr, c)| ConnectionInfo {
+                external_addr: *addr,
+                remote_port: c.remote_port,
+                client: c.client,
+                uptime_secs: c.started.elapsed().as_secs(),
+            })
+            .collect();
+        ret.sort_by_key(|c| (c.remote_port, c.external_addr));
+        ret
+    }
+
+    /// Cancel a client's session. Returns false if no such client exists
+    pub fn disconnect_client(&self, id: ClientId) -> bool {
+        let clients = self.clients.lock().unwrap();
+        match clients.get(&id) {
+            None => false,
+            Some(c) => {
+                c.token.cancel();
+                true
+            }
+        }
+    }
+
+    /// Cancel a single External connection. Returns false if no such
+    /// connection exists
+    pub fn disconnect_connection(&self, external_addr: &SocketAddr) -> bool {
+        let connections = self.connections.lock().unwrap();
+        match connections.get(external_addr) {
+            None => false,
+            Some(c) => {
+                c.token.cancel();
+                true
+            }
+        }
+    }
+
+    /// Stop taking new Externals and let the live connections finish.
+    /// Returns false if the server was already draining
+    pub fn drain(&self) -> bool {
+        let draining = self.draining.is_cancelled();
+        self.draining.cancel();
+        !draining
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.is_cancelled()
+    }
+
+    /// Cancelled once the server drains. Each client drains on a child of
+    /// this, so that it can be drained alone too
+    pub fn drain_token(&self) -> CancellationToken {
+        self.draining.clone()
+    }
+
+    /// One-line summary of the server's load, for the service manager
+    pub fn summary(&self) -> String {
+        let (clients, tunnels) = {
+            let clients = self.clients.lock().unwrap();
+            let tunnels = clients.values().map(|c| c.tunnels.len()).sum::<usize>();
+            (clients.len(), tunnels)
+        };
+        let connections = self.connections.lock().unwrap().len();
+        let mut s = format!("{clients} clients, {tunnels} tunnels, {connections} connections");
+        if self.is_draining() {
+            s.push_str(", draining");
+        }
+        s
+    }
+
+    /// Resolves once no client is left
+    pub async fn idle(&self) {
+        loop {
+            // Created ahead of the check so that a removal in between isn't
+            // missed
+            let removed = self.removed.notified();
+            if self.clients.lock().unwrap().is_empty() {
+                return;
+            }
+            removed.await;
+        }
+    }
+}
@@ -0,0 +1,23 @@
+//! Secret tunnels, which the server doesn't serve on a port of its own.
+//! They are only reached by the visitors of other clients that ask for them
+//! by name and know their secret. Each visitor's connection is piped to the
+//! tunnel's TunnelSupervisor, which takes it like any External.
+use crate::net as stnet;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::sync::mpsc;
+
+// Buffered in the pipe between a visitor and the secret tunnel
+pub const PIPE_BUFFER: usize = 64 * 1024;
+
+/// The tunnel's end of the pipe from a visitor
+pub type VisitorStream = tokio::io::DuplexStream;
+
+pub struct SecretTunnel {
+    pub secret: stnet::Secret,
+    // Hands visitors to the tunnel's TunnelSupervisor
+    pub tx: mpsc::Sender<(VisitorStream, SocketAddr)>,
+}
+
+/// Secret tunnels by name
+pub type SecretChannels = HashMap<String, SecretTunnel>;
@@ -0,0 +1,16 @@
+//! Sessions the server holds on to after their connection dropped, for their
+//! client to pick up over a new one
+use crate::net as stnet;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+/// A new connection picking a session up
+pub struct Resumption<T> {
+    pub peer_addr: stnet::StreamId,
+    pub transport: stnet::Transport<T>,
+    // The Redirector frames the client received over the session
+    pub received: u64,
+}
+
+/// Where the new connection of each session goes, by the session's token
+pub type Sessions<T> = HashMap<stnet::SessionToken, mpsc::Sender<Resumption<T>>>;
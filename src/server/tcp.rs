@@ -1,5 +1,5 @@
 use super::common::*;
-use crate::{config::server as config, net::Result};
+use crate::{config::server as config, net as stnet, net::Result};
 use snafu::ResultExt;
 use std::sync::{Arc, Mutex};
 use tokio::net as tnet;
@@ -13,6 +13,12 @@ pub struct TcpServer {
     token: CancellationToken,
     listener: tnet::TcpListener,
     active_tunnels: Arc<Mutex<ActiveTunnels>>,
+    vhosts: Arc<Mutex<super::vhost::VhostChannels>>,
+    secrets: Arc<Mutex<super::secret::SecretChannels>>,
+    sessions: Arc<Mutex<super::session::Sessions<stnet::BoxedStream>>>,
+    registry: Arc<super::Registry>,
+    terminator: Option<Arc<super::terminate::Terminator>>,
+    auth: Option<Arc<super::auth::Authenticator>>,
 
     tls: Option<TlsAcceptor>,
     handlers: JoinSet<()>,
@@ -40,16 +46,51 @@ impl TcpServer {
             }
         };
 
+        let terminator = match config.tls_termination {
+            None => None,
+            Some(ref t) => Some(Arc::new(super::terminate::Terminator::new(t)?)),
+        };
+        let auth = if config.http_auth.is_empty() {
+            None
+        } else {
+            Some(Arc::new(super::auth::Authenticator::new(
+                &config.http_auth,
+            )?))
+        };
+
         Ok(TcpServer {
             config: config.into(),
             token,
             listener,
             active_tunnels: Arc::new(ActiveTunnels::new().into()),
+            vhosts: Arc::new(Mutex::new(Default::default())),
+            secrets: Arc::new(Mutex::new(Default::default())),
+            sessions: Arc::new(Mutex::new(Default::default())),
+            registry: Arc::new(super::Registry::new()),
+            terminator,
+            auth,
             tls: acceptor,
             handlers: JoinSet::new(),
         })
     }
+    pub fn registry(&self) -> Arc<super::Registry> {
+        self.registry.clone()
+    }
+
+    pub fn vhosts(&self) -> Arc<Mutex<super::vhost::VhostChannels>> {
+        self.vhosts.clone()
+    }
+
+    pub fn terminator(&self) -> Option<Arc<super::terminate::Terminator>> {
+        self.terminator.clone()
+    }
+
+    pub fn auth(&self) -> Option<Arc<super::auth::Authenticator>> {
+        self.auth.clone()
+    }
+
     pub async fn shutdown(&mut self) -> Result<()> {
+        crate::systemd::stopping();
         self.token.cancel();
         while self.handlers.join_next().await.is_some() {
             // intentionally blank
@@ -59,12 +100,19 @@ impl TcpServer {
 
     #[tracing::instrument(name = "TcpSupervisor", level = "info", skip_all)]
     pub async fn run(&mut self) -> Result<()> {
-        info!("listening on {}", &self.config.addr);
+        // Not necessarily addr, with a socket passed in by systemd
+        let addr = self.listener.local_addr().unwrap_or(self.config.addr);
+        info!("listening on {}", addr);
         if self.tls.is_some() {
             info!("TLS enabled. All connections to Clients will be encrypted.");
         } else {
             warn!("TLS *DISABLED*. All data is transmitted in the clear.");
         }
+        crate::systemd::ready(&self.registry.summary());
+        let mut heartbeat = crate::systemd::Heartbeat::new();
+        // TLS handshakes run on their own, so that a peer stalling one holds
+        // up neither other Clients nor the watchdog
+        let mut handshakes = JoinSet::new();
         let ret = loop {
             let (socket, addr) = tokio::select! {
                 maybe_accept = self.listener.accept() => {
@@ -77,6 +125,39 @@ impl TcpServer {
                     }
                 }
 
+                Some(handshake) = handshakes.join_next(), if !handshakes.is_empty() => {
+                    let (addr, socket) = match handshake {
+                        Err(e) => {
+                            error!(cause = ?e, "tls handshake task failed");
+                            continue;
+                        }
+                        Ok((addr, Err(_))) => {
+                            crate::metrics::metrics()
+                                .handshake_failures
+                                .with_label_values(&["tls"])
+                                .inc();
+                            error!(addr = ?addr, "client connection dropped (TLS negotiation timed out)");
+                            continue;
+                        }
+                        Ok((addr, Ok(Err(e)))) => {
+                            crate::metrics::metrics()
+                                .handshake_failures
+                                .with_label_values(&["tls"])
+                                .inc();
+                            error!(cause = ?e, addr = ?addr, "client connection dropped (failed to negotiate TLS)");
+                            continue;
+                        }
+                        Ok((addr, Ok(Ok(socket)))) => (addr, Box::new(socket) as stnet::BoxedStream),
+                    };
+                    self.spawn_handler(addr, socket);
+                    continue;
+                }
+
+                _ = heartbeat.tick() => {
+                    heartbeat.beat(&self.registry.summary());
+                    continue;
+                }
+
                 _ = self.token.cancelled() => {
                     break Ok(())
                 }
@@ -87,51 +168,42 @@ impl TcpServer {
             };
 
             if let Some(tls) = &self.tls {
-                let peer_addr = match socket.peer_addr() {
-                    Err(e) => {
-                        error!(e=?e, "failed to get peer_addr");
-                        continue;
-                    }
-                    Ok(s) => s,
-                };
-                let socket = match tls.accept(socket).await {
-                    Err(e) => {
-                        error!(cause = ?e, addr = ?addr, "client connection dropped (failed to negotiate TLS)");
-                        continue;
-                    }
-                    Ok(socket) => Box::new(socket),
-                };
-                let mut h = super::ClientHandler::new(
-                    self.config.clone(),
-                    self.token.clone(),
-                    self.active_tunnels.clone(),
-                    (peer_addr.into(), socket),
-                );
-                self.handlers.spawn(async move {
-                    trace!(addr = ?addr, "client handler start");
-                    if let Err(e) = h.run().await {
-                        error!(cause = ?e, addr = ?addr, "client connection dropped");
-                    }
-                    trace!(addr = ?addr, "client handler end");
+                let tls = tls.clone();
+                handshakes.spawn(async move {
+                    let timeout = super::terminate::HANDSHAKE_TIMEOUT;
+                    (
+                        addr,
+                        tokio::time::timeout(timeout, tls.accept(socket)).await,
+                    )
                 });
             } else {
-                let peer_addr = socket.peer_addr().expect("ip");
-                let mut h = super::ClientHandler::new(
-                    self.config.clone(),
-                    self.token.clone(),
-                    self.active_tunnels.clone(),
-                    (peer_addr.into(), Box::new(socket)),
-                );
-                self.handlers.spawn(async move {
-                    trace!(addr = ?addr, "client handler start");
-                    if let Err(e) = h.run().await {
-                        error!(cause = ?e, addr = ?addr, "client connection dropped");
-                    }
-                    trace!(addr = ?addr, "client handler end");
-                });
+                self.spawn_handler(addr, Box::new(socket));
             }
         };
         self.shutdown().await?;
         ret
     }
+
+    // Serve the Client at `addr` on `socket`
+    fn spawn_handler(&mut self, addr: std::net::SocketAddr, socket: stnet::BoxedStream) {
+        let h = super::ClientHandler::new(
+            self.config.clone(),
+            self.token.child_token(),
+            self.active_tunnels.clone(),
+            self.vhosts.clone(),
+            self.secrets.clone(),
+            self.sessions.clone(),
+            self.registry.clone(),
+            self.terminator.clone(),
+            self.auth.clone(),
+            (addr.into(), socket),
+        );
+        self.handlers.spawn(async move {
+            trace!(addr = ?addr, "client handler start");
+            if let Err(e) = h.run().await {
+                error!(cause = ?e, addr = ?addr, "client connection dropped");
+            }
+            trace!(addr = ?addr, "client handler end");
+        });
+    }
 }
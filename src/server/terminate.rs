@@ -0,0 +1,144 @@
+//! TLS termination for Externals, so that an Internal that only speaks
+//! plaintext can be exposed over TLS. Certificates are picked by the server
+//! name an External asks for, and can be reloaded from disk while running.
+use crate::config::server as config;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use snafu::ResultExt;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+
+/// Externals that don't finish the TLS handshake by then are dropped
+pub const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct Certs {
+    // By DNS name from the certificate, wildcards included as `*.example.com`
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    // For Externals without a server name, or one no certificate covers
+    default: Option<Arc<CertifiedKey>>,
+}
+
+#[derive(Debug, Default)]
+struct CertResolver(Mutex<Arc<Certs>>);
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.0.lock().unwrap().clone();
+        let Some(name) = hello.server_name() else {
+            return certs.default.clone();
+        };
+        let name = name.to_ascii_lowercase();
+        if let Some(c) = certs.by_name.get(&name) {
+            return Some(c.clone());
+        }
+        // A wildcard only covers a single label
+        if let Some((_, parent)) = name.split_once('.') {
+            if let Some(c) = certs.by_name.get(&format!("*.{parent}")) {
+                return Some(c.clone());
+            }
+        }
+        certs.default.clone()
+    }
+}
+
+/// Terminates TLS for Externals on the remote_ports in `tls_termination`
+pub struct Terminator {
+    config: config::TlsTermination,
+    ports: HashSet<u16>,
+    resolver: Arc<CertResolver>,
+    acceptor: TlsAcceptor,
+}
+
+impl Terminator {
+    pub fn new(config: &config::TlsTermination) -> crate::Result<Self> {
+        let resolver = Arc::new(CertResolver::default());
+        let tls_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        let terminator = Terminator {
+            config: config.clone(),
+            ports: config.ports.iter().copied().collect(),
+            resolver,
+            acceptor: TlsAcceptor::from(Arc::new(tls_config)),
+        };
+        terminator.reload()?;
+        Ok(terminator)
+    }
+
+    /// The acceptor to terminate TLS with for Externals on `remote_port`, if
+    /// it is configured for termination
+    pub fn acceptor(&self, remote_port: u16) -> Option<TlsAcceptor> {
+        self.ports
+            .contains(&remote_port)
+            .then(|| self.acceptor.clone())
+    }
+
+    /// Read the certificates from disk again. Handshakes already underway
+    /// keep the certificate they were given. On failure the certificates
+    /// in use are kept
+    pub fn reload(&self) -> crate::Result<()> {
+        let provider = rustls::crypto::CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+
+        let mut certs = Certs::default();
+        for paths in self.config.certs.iter() {
+            let crypto = config::Crypto::from_crypto_cfg(paths)?;
+            if crypto.certs.is_empty() {
+                return Err(crate::net::Error::Io {
+                    message: format!("no certificates in {:?}", paths.cert),
+                    source: std::io::ErrorKind::InvalidData.into(),
+                    backtrace: snafu::Backtrace::capture(),
+                }
+                .into());
+            }
+            let key = provider
+                .key_provider
+                .load_private_key(crypto.key)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+                .with_context(|_| crate::net::IoSnafu {
+                    message: format!("failed to load key {:?}", paths.key),
+                })?;
+            let names = dns_names(&crypto.certs);
+            if names.is_empty() {
+                warn!(cert = ?paths.cert, "certificate has no DNS names, only usable as the default");
+            }
+            let key = Arc::new(CertifiedKey::new(crypto.certs, key));
+            for name in names {
+                certs.by_name.entry(name).or_insert_with(|| key.clone());
+            }
+            if certs.default.is_none() {
+                certs.default = Some(key);
+            }
+        }
+        info!(names = ?certs.by_name.keys(), "loaded tls termination certificates");
+        *self.resolver.0.lock().unwrap() = Arc::new(certs);
+        Ok(())
+    }
+}
+
+// The lowercased DNS names in the subjectAltName of the leaf certificate
+fn dns_names(certs: &[rustls::pki_types::CertificateDer<'static>]) -> Vec<String> {
+    use x509_parser::prelude::*;
+
+    let Some(leaf) = certs.first() else {
+        return Vec::new();
+    };
+    let Ok((_, cert)) = X509Certificate::from_der(leaf) else {
+        return Vec::new();
+    };
+    let Ok(Some(san)) = cert.subject_alternative_name() else {
+        return Vec::new();
+    };
+    san.value
+        .general_names
+        .iter()
+        .filter_map(|n| match n {
+            GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+            _ => None,
+        })
+        .collect()
+}
@@ -1,114 +1,471 @@
 use super::common::*;
 use crate::{net as stnet, net::Result, redirector::Redirector};
-use snafu::ResultExt;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
 use tokio::{net as tnet, task::JoinSet};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, trace};
+use tracing::{error, info, Instrument};
+
+/// Where a TunnelSupervisor gets its Externals from
+pub enum Listener {
+    Tcp(tnet::TcpListener),
+    // Like Tcp, but TLS is terminated here and the Internal gets plaintext
+    Tls(tnet::TcpListener, tokio_rustls::TlsAcceptor),
+    // Externals routed by hostname from one of the shared listeners
+    Vhost {
+        vhost: stnet::Vhost,
+        rx: mpsc::Receiver<(super::vhost::VhostStream, SocketAddr)>,
+    },
+    // Externals handed out by the pool of clients sharing a port, with TLS
+    // terminated here if the port is configured for it
+    Pool {
+        rx: mpsc::Receiver<super::pool::PoolStream>,
+        acceptor: Option<tokio_rustls::TlsAcceptor>,
+    },
+    // Visitors of a secret tunnel, from the clients that asked for it
+    Secret {
+        vhost: stnet::Vhost,
+        rx: mpsc::Receiver<(super::secret::VisitorStream, SocketAddr)>,
+    },
+}
+
+// The address of the server the External at `external_addr` connected to,
+// or the unspecified address of its family on `remote_port` if that's not
+// known
+fn server_addr(
+    local: std::io::Result<SocketAddr>,
+    external_addr: SocketAddr,
+    remote_port: u16,
+) -> SocketAddr {
+    local.unwrap_or_else(|_| match external_addr {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, remote_port).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, remote_port).into(),
+    })
+}
+
+// An External fresh off a Listener
+enum Incoming {
+    Tcp(tnet::TcpStream),
+    Vhost(super::vhost::VhostStream),
+    Visitor(super::secret::VisitorStream),
+}
+
+impl Listener {
+    // The next External, along with the frame that has the client open a
+    // connection to the Internal for it. None once no more will come
+    async fn accept(
+        &mut self,
+        remote_port: u16,
+    ) -> Option<(Incoming, SocketAddr, stnet::RedirectorFrame)> {
+        let (incoming, external_addr, server_addr) = match self {
+            Listener::Tcp(listener) | Listener::Tls(listener, _) => loop {
+                match listener.accept().await {
+                    Err(e) => {
+                        error!(cause = ?e, "failed to accept client");
+                        // This typically means an exhaustion of client ports
+                        // so give it a few seconds
+                        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                    }
+                    Ok((s, addr)) => {
+                        let server_addr = server_addr(s.local_addr(), addr, remote_port);
+                        break (Incoming::Tcp(s), addr, server_addr);
+                    }
+                }
+            },
+            Listener::Vhost { vhost, rx } => {
+                let (s, addr) = rx.recv().await?;
+                let start = stnet::RedirectorFrame::StartVhostListener(
+                    addr,
+                    server_addr(s.get_ref().local_addr(), addr, remote_port),
+                    remote_port,
+                    vhost.clone(),
+                    stnet::ConnId::random(),
+                    stnet::TraceContext::default(),
+                );
+                return Some((Incoming::Vhost(s), addr, start));
+            }
+            Listener::Secret { vhost, rx } => {
+                let (s, addr) = rx.recv().await?;
+                // Visitors come through another client, not to an address
+                // of ours
+                let unknown = Err(std::io::ErrorKind::NotConnected.into());
+                let start = stnet::RedirectorFrame::StartVhostListener(
+                    addr,
+                    server_addr(unknown, addr, remote_port),
+                    remote_port,
+                    vhost.clone(),
+                    stnet::ConnId::random(),
+                    stnet::TraceContext::default(),
+                );
+                return Some((Incoming::Visitor(s), addr, start));
+            }
+            Listener::Pool { rx, .. } => {
+                let (s, addr) = rx.recv().await?;
+                let server_addr = server_addr(s.local_addr(), addr, remote_port);
+                (Incoming::Tcp(s), addr, server_addr)
+            }
+        };
+        let start = stnet::RedirectorFrame::StartListener(
+            external_addr,
+            server_addr,
+            remote_port,
+            stnet::ConnId::random(),
+            stnet::TraceContext::default(),
+        );
+        Some((incoming, external_addr, start))
+    }
+
+    // Terminate TLS for Externals with this, if set
+    fn acceptor(&self) -> Option<&tokio_rustls::TlsAcceptor> {
+        match self {
+            Listener::Tls(_, acceptor) => Some(acceptor),
+            Listener::Pool { acceptor, .. } => acceptor.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+// An External on a tunnel with an http_auth policy, with the stream for its
+// Redirector and the task gating its requests if it was let through
+type Admission = (
+    SocketAddr,
+    stnet::RedirectorFrame,
+    Option<(
+        tokio::io::DuplexStream,
+        futures::future::BoxFuture<'static, ()>,
+    )>,
+);
 
 pub struct TunnelSupervisor {
     config: Arc<crate::config::server::Config>,
     remote_port: u16,
-    mtu: u16,
     token: CancellationToken,
+    // Cancelled to stop taking new Externals, and to end once the live
+    // connections are done
+    drain: CancellationToken,
     to_client: mpsc::Sender<stnet::RedirectorFrame>,
     tunnels: Arc<Mutex<TunnelChannels>>,
+    registry: Arc<super::Registry>,
+    client: super::ClientId,
+    auth: Option<Arc<super::auth::Authenticator>>,
+    // External addresses of the live connections on this tunnel
+    live: Arc<Mutex<HashSet<SocketAddr>>>,
+    // Whether the client last reported the Internal as up
+    healthy: Arc<AtomicBool>,
     js: JoinSet<()>,
 }
 
 impl TunnelSupervisor {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Arc<crate::config::server::Config>,
         remote_port: u16,
-        mtu: u16,
         token: CancellationToken,
+        drain: CancellationToken,
         tunnels: Arc<Mutex<TunnelChannels>>,
         to_client: mpsc::Sender<stnet::RedirectorFrame>,
+        registry: Arc<super::Registry>,
+        client: super::ClientId,
+        auth: Option<Arc<super::auth::Authenticator>>,
     ) -> Self {
         TunnelSupervisor {
             config,
             remote_port,
-            mtu,
             token,
+            drain,
             tunnels,
             to_client,
+            registry,
+            client,
+            auth,
+            live: Arc::new(HashSet::new().into()),
+            healthy: Arc::new(AtomicBool::new(true)),
             js: JoinSet::new(),
         }
     }
 
-    async fn run2(&mut self, external_listener: tnet::TcpListener) -> Result<()> {
+    /// The live connections of this tunnel, by External address
+    pub fn live(&self) -> Arc<Mutex<HashSet<SocketAddr>>> {
+        self.live.clone()
+    }
+
+    /// Whether the Internal is up, as last reported by the client. Externals
+    /// are turned away while it is down
+    pub fn health(&self) -> Arc<AtomicBool> {
+        self.healthy.clone()
+    }
+
+    async fn run2(&mut self, mut listener: Listener) -> Result<()> {
+        // TLS handshakes with Externals are done off to the side so that a
+        // slow External can't hold up the others
+        let mut handshakes = JoinSet::new();
+        // As are the first requests on tunnels with an http_auth policy
+        let mut admissions = JoinSet::new();
         loop {
-            let (external_stream, external_addr) = tokio::select! {
-                maybe_accept = external_listener.accept() => match maybe_accept{
-                    Err(e) => {
-                        error!(cause = ?e, "failed to accept client");
-                        // This typically means an exhaustion of client ports
-                        // so give it a few seconds
-                        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-                        continue
+            tokio::select! {
+                incoming = listener.accept(self.remote_port) => match incoming {
+                    None => break Ok(()),
+                    Some((incoming, external_addr, _)) if !self.healthy.load(Ordering::Relaxed) => {
+                        info!(port = self.remote_port, external_addr = ?external_addr, "turned away connection, Internal is down");
+                        // Reset rather than close, so that the External fails fast
+                        if let Incoming::Tcp(s) = incoming {
+                            let _ = s.set_linger(Some(std::time::Duration::ZERO));
+                        }
+                    }
+                    Some((Incoming::Tcp(external_stream), external_addr, start)) => {
+                        match listener.acceptor() {
+                            None => {
+                                self.admit(external_stream, external_addr, start, &mut admissions)
+                                    .await?
+                            }
+                            Some(acceptor) => {
+                                let acceptor = acceptor.clone();
+                                handshakes.spawn(async move {
+                                    let timeout = super::terminate::HANDSHAKE_TIMEOUT;
+                                    let ret = tokio::time::timeout(timeout, acceptor.accept(external_stream)).await;
+                                    (external_addr, start, ret)
+                                });
+                            }
+                        }
+                    }
+                    Some((Incoming::Vhost(external_stream), external_addr, start)) => {
+                        self.admit(external_stream, external_addr, start, &mut admissions)
+                            .await?
+                    }
+                    Some((Incoming::Visitor(external_stream), external_addr, start)) => {
+                        self.admit(external_stream, external_addr, start, &mut admissions)
+                            .await?
                     }
-                    Ok(s) => s,
                 },
 
+                Some(handshake) = handshakes.join_next(), if !handshakes.is_empty() => {
+                    let (external_addr, start, external_stream) = match handshake {
+                        Err(e) => {
+                            error!(cause = ?e, "tls handshake task failed");
+                            continue;
+                        }
+                        Ok((external_addr, _, Err(_))) => {
+                            info!(external_addr = ?external_addr, "tls handshake timed out");
+                            continue;
+                        }
+                        Ok((external_addr, _, Ok(Err(e)))) => {
+                            info!(cause = ?e, external_addr = ?external_addr, "tls handshake failed");
+                            continue;
+                        }
+                        Ok((external_addr, start, Ok(Ok(s)))) => (external_addr, start, s),
+                    };
+                    self.admit(external_stream, external_addr, start, &mut admissions)
+                        .await?;
+                }
+
+                Some(admission) = admissions.join_next(), if !admissions.is_empty() => {
+                    self.admitted(admission).await?;
+                }
+
+                _ = self.drain.cancelled() => {
+                    // Externals that show up from now on are refused
+                    drop(listener);
+                    break self.drained().await;
+                }
+
                 _ = self.token.cancelled() => break Ok(()),
-            };
+            }
+        }
+    }
 
-            info!(port = self.remote_port, external_addr = ?external_addr, "incoming connection");
-            if let Err(e) = self
-                .to_client
-                .send(stnet::RedirectorFrame::StartListener(
-                    external_addr,
-                    self.remote_port,
-                ))
-                .await
-            {
-                error!(e=?e, "failed to send via channel");
-                break Err(stnet::Error::ConnectionDead);
+    /// Wait for the live connections to finish on their own
+    async fn drained(&mut self) -> Result<()> {
+        info!(
+            port = self.remote_port,
+            connections = self.live.lock().unwrap().len(),
+            "draining tunnel"
+        );
+        loop {
+            tokio::select! {
+                maybe_join = self.js.join_next() => {
+                    if maybe_join.is_none() {
+                        break;
+                    }
+                }
+                _ = self.token.cancelled() => break,
             }
+        }
+        info!(port = self.remote_port, "tunnel drained");
+        Ok(())
+    }
+
+    /// Redirect `external_stream` right away or, on tunnels with an
+    /// http_auth policy, once its first request has been let through
+    async fn admit<T: stnet::Stream + 'static>(
+        &mut self,
+        external_stream: T,
+        external_addr: SocketAddr,
+        start: stnet::RedirectorFrame,
+        admissions: &mut JoinSet<Admission>,
+    ) -> Result<()> {
+        let Some(policy) = self.policy(&start) else {
+            return self.redirect(external_stream, external_addr, start).await;
+        };
+        admissions.spawn(async move {
+            let admitted = super::auth::admit(external_stream, policy).await;
+            (external_addr, start, admitted)
+        });
+        Ok(())
+    }
 
-            let (to_tunnel, from_client) =
-                mpsc::channel::<stnet::RedirectorFrame>(self.config.channel_limits.core);
+    fn policy(&self, start: &stnet::RedirectorFrame) -> Option<Arc<super::auth::Policy>> {
+        let auth = self.auth.as_ref()?;
+        match start {
+            stnet::RedirectorFrame::StartListener(_, _, port, ..) => auth.for_port(*port),
+            stnet::RedirectorFrame::StartVhostListener(_, _, _, vhost, ..)
+                if vhost.kind == stnet::VhostKind::Http =>
             {
-                let mut tunnels = self.tunnels.lock().unwrap();
-                tunnels.insert(external_addr, to_tunnel);
+                auth.for_host(&vhost.hostname)
             }
+            _ => None,
+        }
+    }
+
+    async fn admitted(
+        &mut self,
+        admission: std::result::Result<Admission, tokio::task::JoinError>,
+    ) -> Result<()> {
+        let (external_addr, start, admitted) = match admission {
+            Err(e) => {
+                error!(cause = ?e, "admission task failed");
+                return Ok(());
+            }
+            Ok(a) => a,
+        };
+        let Some((external_stream, gate)) = admitted else {
+            info!(port = self.remote_port, external_addr = ?external_addr, "turned down connection");
+            return Ok(());
+        };
+        self.redirect(external_stream, external_addr, start).await?;
+        self.js.spawn(gate);
+        Ok(())
+    }
+
+    /// Have the client open a connection to the Internal with `start`, and
+    /// shuffle data between it and `external_stream`
+    #[tracing::instrument(name = "connection", level = "info", skip_all, fields(conn_id = start.conn_id().map(tracing::field::display)))]
+    async fn redirect<T: stnet::Stream + 'static>(
+        &mut self,
+        external_stream: T,
+        external_addr: SocketAddr,
+        mut start: stnet::RedirectorFrame,
+    ) -> Result<()> {
+        info!(port = self.remote_port, external_addr = ?external_addr, "incoming connection");
+        let conn_id = start.conn_id();
+        // The client's side of the connection goes in the same trace
+        start.set_trace(stnet::TraceContext::current());
+        if let Err(e) = self.to_client.send(start).await {
+            error!(e=?e, "failed to send via channel");
+            return Err(stnet::Error::ConnectionDead);
+        }
+
+        let (to_tunnel, from_client) =
+            mpsc::channel::<stnet::RedirectorFrame>(self.config.channel_limits.core);
+        {
+            let mut tunnels = self.tunnels.lock().unwrap();
+            tunnels.insert(external_addr, to_tunnel);
+        }
+
+        // Each connection gets its own token so that it can be killed
+        // individually via the admin API
+        let conn_token = self.token.child_token();
+        self.registry.add_connection(
+            self.client,
+            self.remote_port,
+            external_addr,
+            conn_token.clone(),
+        );
+        self.live.lock().unwrap().insert(external_addr);
 
-            let tunnels = self.tunnels.clone();
-            let mut r = Redirector::with_stream(
-                external_addr,
-                self.remote_port,
-                self.mtu,
-                self.token.clone(),
-                external_stream,
-                self.to_client.clone(),
-                from_client,
-            );
-            let port = self.remote_port;
-            self.js.spawn(async move {
-                r.run().await;
-                let mut tunnels = tunnels.lock().unwrap();
-                tunnels.remove(&external_addr);
-                trace!(port = port, external_addr = ?external_addr, "connection closed");
-            });
+        let tunnels = self.tunnels.clone();
+        let registry = self.registry.clone();
+        let live = self.live.clone();
+        let to_client = self.to_client.clone();
+        let parent_token = self.token.clone();
+        let mut r = Redirector::with_stream(
+            external_addr,
+            self.remote_port,
+            self.config.mtu,
+            conn_token.clone(),
+            external_stream,
+            self.to_client.clone(),
+            from_client,
+        );
+        if let Some(conn_id) = conn_id {
+            r = r.with_conn_id(conn_id);
         }
+        let access = super::access::Entry {
+            remote_port: self.remote_port,
+            client: self.client,
+            client_addr: self.registry.client_addr(self.client),
+            external_addr,
+            conn_id,
+        };
+        self.js.spawn(
+            async move {
+                let stats = r.run().await;
+                access.log(&stats);
+                registry.remove_connection(&external_addr);
+                live.lock().unwrap().remove(&external_addr);
+                {
+                    let mut tunnels = tunnels.lock().unwrap();
+                    tunnels.remove(&external_addr);
+                }
+                if conn_token.is_cancelled() && !parent_token.is_cancelled() {
+                    // Killed individually, so make sure the client lets go
+                    // of the Internal too
+                    let _ = to_client
+                        .send(stnet::RedirectorFrame::KillListener(external_addr))
+                        .await;
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(())
     }
 
-    #[tracing::instrument(name = "TunnelSupervisor", level = "info", skip_all)]
-    pub async fn run(&mut self) -> Result<()> {
+    /// Bind the External listener for `remote_port`. This is done ahead of
+    /// [`TunnelSupervisor::run`] so that a failure can be reported back to
+    /// the client as a rejection of that port alone
+    pub async fn bind(remote_port: u16) -> std::io::Result<tnet::TcpListener> {
         // TODO support more protocols, including TCP+TLS/QUIC
-        let external_listener = tnet::TcpListener::bind(format!("127.0.0.1:{}", self.remote_port))
-            .await
-            .with_context(|_| crate::net::IoSnafu {
-                message: "bind failed",
-            })?;
+        tnet::TcpListener::bind(format!("127.0.0.1:{}", remote_port)).await
+    }
 
-        let ret = self.run2(external_listener).await;
+    #[tracing::instrument(name = "TunnelSupervisor", level = "info", skip_all)]
+    pub async fn run(&mut self, listener: Listener) -> Result<()> {
+        let active_tunnels = &crate::metrics::metrics().active_tunnels;
+        active_tunnels.inc();
+        let ret = self.run2(listener).await;
 
         self.js.shutdown().await;
         while self.js.join_next().await.is_some() {
             // intentionally blank
         }
+        // Redirectors that were aborted above never got to clean up after
+        // themselves
+        let closed: Vec<_> = self.live.lock().unwrap().drain().collect();
+        {
+            let mut tunnels = self.tunnels.lock().unwrap();
+            for addr in closed.iter() {
+                self.registry.remove_connection(addr);
+                tunnels.remove(addr);
+            }
+        }
+        for addr in closed {
+            let _ = self
+                .to_client
+                .try_send(stnet::RedirectorFrame::KillListener(addr));
+        }
+        active_tunnels.dec();
 
         ret
     }
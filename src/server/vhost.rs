@@ -0,0 +1,154 @@
+//! Shared listeners that route each External to the client holding the
+//! hostname it asked for, so that many tunnels can share one port. HTTP
+//! Externals are routed by their `Host` header, TLS Externals by the server
+//! name in their ClientHello.
+use crate::net::{Vhost, VhostKind};
+use crate::{http, net as stnet, sni};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net as tnet;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, trace};
+
+// Externals that don't send a complete request head or ClientHello by then
+// are dropped
+const HEAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// An External with its request head or ClientHello already read
+pub type VhostStream = stnet::PrefixedStream<tnet::TcpStream>;
+
+/// Hands Externals to the TunnelSupervisor serving each hostname
+pub type VhostChannels = HashMap<Vhost, mpsc::Sender<(VhostStream, SocketAddr)>>;
+
+#[tracing::instrument(name = "Vhost", level = "info", skip(vhosts, token))]
+pub async fn serve(
+    addr: SocketAddr,
+    kind: VhostKind,
+    vhosts: Arc<Mutex<VhostChannels>>,
+    token: CancellationToken,
+) -> stnet::Result<()> {
+    let listener = tnet::TcpListener::bind(addr)
+        .await
+        .with_context(|_| stnet::IoSnafu {
+            message: format!("failed to bind vhost listener {addr}"),
+        })?;
+    info!("vhost listening on {addr}");
+
+    loop {
+        let (stream, external_addr) = tokio::select! {
+            maybe_accept = listener.accept() => match maybe_accept {
+                Err(e) => {
+                    error!(cause = ?e, "failed to accept vhost connection");
+                    continue;
+                }
+                Ok(s) => s,
+            },
+            _ = token.cancelled() => return Ok(()),
+        };
+        let vhosts = vhosts.clone();
+        match kind {
+            VhostKind::Http => tokio::spawn(route_http(stream, external_addr, vhosts)),
+            VhostKind::Sni => tokio::spawn(route_sni(stream, external_addr, vhosts)),
+            VhostKind::Secret => unreachable!("secret tunnels have no listener"),
+        };
+    }
+}
+
+async fn route_http(
+    mut stream: tnet::TcpStream,
+    external_addr: SocketAddr,
+    vhosts: Arc<Mutex<VhostChannels>>,
+) {
+    let (req, head) = match tokio::time::timeout(HEAD_TIMEOUT, http::read_head(&mut stream)).await {
+        Ok(Ok(r)) => r,
+        _ => {
+            trace!(external_addr = ?external_addr, "bad http request");
+            error_page(&mut stream, 400, "bad request").await;
+            return;
+        }
+    };
+    let Some(host) = req.host() else {
+        error_page(&mut stream, 400, "missing Host header").await;
+        return;
+    };
+
+    let vhost = Vhost {
+        kind: VhostKind::Http,
+        hostname: host,
+    };
+    let tx = vhosts.lock().unwrap().get(&vhost).cloned();
+    let Some(tx) = tx else {
+        info!(host = %vhost, external_addr = ?external_addr, "no tunnel for host");
+        error_page(&mut stream, 404, "no tunnel for this host").await;
+        return;
+    };
+    if let Err(e) = tx
+        .send((stnet::PrefixedStream::new(head, stream), external_addr))
+        .await
+    {
+        // The tunnel went away after we looked it up
+        let (mut stream, _) = e.0;
+        error_page(&mut stream, 502, "tunnel is unavailable").await;
+    }
+}
+
+async fn route_sni(
+    mut stream: tnet::TcpStream,
+    external_addr: SocketAddr,
+    vhosts: Arc<Mutex<VhostChannels>>,
+) {
+    // Externals that don't even send a ClientHello are just hung up on
+    let (hostname, hello) =
+        match tokio::time::timeout(HEAD_TIMEOUT, sni::read_client_hello(&mut stream)).await {
+            Ok(Ok((Some(hostname), hello))) => (hostname, hello),
+            Ok(Ok((None, _))) => {
+                trace!(external_addr = ?external_addr, "client hello without server name");
+                unrecognized_name(&mut stream).await;
+                return;
+            }
+            _ => {
+                trace!(external_addr = ?external_addr, "bad tls client hello");
+                return;
+            }
+        };
+
+    let vhost = Vhost {
+        kind: VhostKind::Sni,
+        hostname,
+    };
+    let tx = vhosts.lock().unwrap().get(&vhost).cloned();
+    let Some(tx) = tx else {
+        info!(host = %vhost, external_addr = ?external_addr, "no tunnel for server name");
+        unrecognized_name(&mut stream).await;
+        return;
+    };
+    if let Err(e) = tx
+        .send((stnet::PrefixedStream::new(hello, stream), external_addr))
+        .await
+    {
+        // The tunnel went away after we looked it up
+        let (mut stream, _) = e.0;
+        unrecognized_name(stream.get_mut()).await;
+    }
+}
+
+async fn unrecognized_name(stream: &mut tnet::TcpStream) {
+    if let Err(e) = stream.write_all(&sni::UNRECOGNIZED_NAME_ALERT).await {
+        trace!(cause = ?e, "failed to write tls alert");
+    }
+    let _ = stream.shutdown().await;
+}
+
+async fn error_page<T>(stream: &mut T, status: u16, message: &str)
+where
+    T: tokio::io::AsyncWrite + Unpin,
+{
+    let body = format!("{status} {}: {message}\n", http::reason(status));
+    if let Err(e) = http::write_response(stream, status, "text/plain", body.as_bytes()).await {
+        trace!(cause = ?e, "failed to write error page");
+    }
+}
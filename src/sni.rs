@@ -0,0 +1,267 @@
+//! Just enough of TLS to find the server name an External asked for in its
+//! ClientHello, without terminating TLS.
+use crate::net as stnet;
+use snafu::ResultExt;
+use tokio::io::AsyncReadExt;
+
+// A ClientHello bigger than this is either broken or not worth routing
+const MAX_HELLO_LEN: usize = 16 * 1024 + 5;
+
+const CONTENT_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// A fatal `unrecognized_name` alert, sent to Externals asking for a name no
+/// client serves
+pub const UNRECOGNIZED_NAME_ALERT: [u8; 7] = [0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 0x70];
+
+/// Read records off `stream` until a whole ClientHello has arrived. Returns
+/// the lowercased server name, if the ClientHello has one, along with every
+/// byte read so far so that they can be forwarded untouched
+pub async fn read_client_hello<T>(stream: &mut T) -> stnet::Result<(Option<String>, Vec<u8>)>
+where
+    T: tokio::io::AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream
+            .read(&mut chunk)
+            .await
+            .with_context(|_| stnet::IoSnafu {
+                message: "failed to read tls client hello",
+            })?;
+        if n == 0 {
+            return Err(stnet::Error::ConnectionDead);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(hello) = client_hello(&buf)? {
+            return Ok((server_name(&hello), buf));
+        }
+        if buf.len() > MAX_HELLO_LEN {
+            return Err(stnet::Error::UnexpectedFrame);
+        }
+    }
+}
+
+// Reassemble the ClientHello message from the handshake records in `buf`.
+// Returns None if more bytes are needed
+fn client_hello(buf: &[u8]) -> stnet::Result<Option<Vec<u8>>> {
+    let mut msg = Vec::new();
+    let mut rest = buf;
+    while rest.len() >= 5 {
+        if rest[0] != CONTENT_HANDSHAKE {
+            return Err(stnet::Error::UnexpectedFrame);
+        }
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        if rest.len() < 5 + len {
+            break;
+        }
+        msg.extend_from_slice(&rest[5..5 + len]);
+        rest = &rest[5 + len..];
+
+        if msg.len() >= 4 {
+            if msg[0] != HANDSHAKE_CLIENT_HELLO {
+                return Err(stnet::Error::UnexpectedFrame);
+            }
+            let hello_len = u32::from_be_bytes([0, msg[1], msg[2], msg[3]]) as usize;
+            if msg.len() >= 4 + hello_len {
+                msg.truncate(4 + hello_len);
+                return Ok(Some(msg.split_off(4)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The host_name in the server_name extension of a ClientHello body
+pub fn server_name(hello: &[u8]) -> Option<String> {
+    let mut r = Reader(hello);
+    // legacy_version and random
+    r.skip(2 + 32)?;
+    // legacy_session_id
+    let n = r.u8()? as usize;
+    r.skip(n)?;
+    // cipher_suites
+    let n = r.u16()? as usize;
+    r.skip(n)?;
+    // legacy_compression_methods
+    let n = r.u8()? as usize;
+    r.skip(n)?;
+
+    let n = r.u16()? as usize;
+    let mut exts = Reader(r.take(n)?);
+    while !exts.0.is_empty() {
+        let ty = exts.u16()?;
+        let n = exts.u16()? as usize;
+        let data = exts.take(n)?;
+        if ty != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut data = Reader(data);
+        let n = data.u16()? as usize;
+        let mut names = Reader(data.take(n)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let n = names.u16()? as usize;
+            let name = names.take(n)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                let name = name.trim_end_matches('.').to_ascii_lowercase();
+                return (!name.is_empty()).then_some(name);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ClientHello body asking for `name`, with an unrelated extension
+    // ahead of server_name
+    fn hello_body(name: Option<&str>) -> Vec<u8> {
+        let mut exts = vec![0x00, 0x17, 0x00, 0x00];
+        if let Some(name) = name {
+            let name = name.as_bytes();
+            let list_len = 3 + name.len() as u16;
+            exts.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+            exts.extend_from_slice(&(list_len + 2).to_be_bytes());
+            exts.extend_from_slice(&list_len.to_be_bytes());
+            exts.push(NAME_TYPE_HOST_NAME);
+            exts.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            exts.extend_from_slice(name);
+        }
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0xaa; 32]);
+        // legacy_session_id, one cipher suite, null compression
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+        body.extend_from_slice(&exts);
+        body
+    }
+
+    // `body` as a handshake message, split into records of at most `max`
+    // bytes
+    fn records(body: &[u8], max: usize) -> Vec<u8> {
+        let mut msg = vec![HANDSHAKE_CLIENT_HELLO];
+        msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        msg.extend_from_slice(body);
+        let mut out = Vec::new();
+        for chunk in msg.chunks(max) {
+            out.extend_from_slice(&[CONTENT_HANDSHAKE, 0x03, 0x01]);
+            out.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn reads_server_name() {
+        let bytes = records(&hello_body(Some("App.Example.Test.")), 1 << 14);
+        let (name, read) = read_client_hello(&mut &bytes[..]).await.unwrap();
+        assert_eq!(name.as_deref(), Some("app.example.test"));
+        assert_eq!(read, bytes);
+    }
+
+    #[tokio::test]
+    async fn reassembles_fragmented_hello() {
+        let bytes = records(&hello_body(Some("app.example.test")), 7);
+        let (name, _) = read_client_hello(&mut &bytes[..]).await.unwrap();
+        assert_eq!(name.as_deref(), Some("app.example.test"));
+    }
+
+    #[test]
+    fn waits_for_truncated_hello() {
+        let bytes = records(&hello_body(Some("app.example.test")), 1 << 14);
+        for end in 0..bytes.len() {
+            assert_eq!(client_hello(&bytes[..end]).unwrap(), None, "{end}");
+        }
+    }
+
+    #[tokio::test]
+    async fn hangup_mid_hello_is_an_error() {
+        let bytes = records(&hello_body(Some("app.example.test")), 1 << 14);
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(matches!(
+            read_client_hello(&mut &truncated[..]).await,
+            Err(stnet::Error::ConnectionDead)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_records_and_messages() {
+        let mut bytes = records(&hello_body(Some("app.example.test")), 1 << 14);
+        bytes[5] = 0x02; // ServerHello
+        assert!(client_hello(&bytes).is_err());
+        bytes[0] = 0x17; // application_data
+        assert!(client_hello(&bytes).is_err());
+        assert!(client_hello(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_oversized_hello() {
+        // Claims to be bigger than anything worth routing, and never ends
+        let mut bytes = vec![CONTENT_HANDSHAKE, 0x03, 0x01, 0x00, 0x04];
+        bytes.extend_from_slice(&[HANDSHAKE_CLIENT_HELLO, 0xff, 0xff, 0xff]);
+        while bytes.len() <= MAX_HELLO_LEN + 1024 {
+            bytes.extend_from_slice(&[CONTENT_HANDSHAKE, 0x03, 0x01, 0x01, 0x00]);
+            bytes.extend_from_slice(&[0; 256]);
+        }
+        assert!(matches!(
+            read_client_hello(&mut &bytes[..]).await,
+            Err(stnet::Error::UnexpectedFrame)
+        ));
+    }
+
+    #[test]
+    fn malformed_server_name() {
+        assert_eq!(server_name(&hello_body(None)), None);
+        assert_eq!(server_name(&hello_body(Some(""))), None);
+        assert_eq!(server_name(&[0x03, 0x03, 0x00]), None);
+
+        // Lengths pointing past the end of the message
+        let body = hello_body(Some("app.example.test"));
+        for end in 0..body.len() {
+            assert_eq!(server_name(&body[..end]), None, "{end}");
+        }
+        let mut body = hello_body(Some("app.example.test"));
+        let last = body.len() - "app.example.test".len() - 1;
+        body[last] = 0xff;
+        assert_eq!(server_name(&body), None);
+
+        // Not UTF-8
+        let mut body = hello_body(Some("app"));
+        let n = body.len();
+        body[n - 1] = 0xff;
+        assert_eq!(server_name(&body), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! Running under systemd: readiness and status notifications, watchdog pings
+//! and socket activation. Everything here is a no-op unless built with the
+//! `systemd` feature and started by systemd
+use std::time::Duration;
+
+// How often the status is refreshed when there's no watchdog to keep happy
+#[cfg(feature = "systemd")]
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tell systemd the service is up, along with its status
+pub fn ready(status: &str) {
+    #[cfg(feature = "systemd")]
+    notify(&[
+        sd_notify::NotifyState::Ready,
+        sd_notify::NotifyState::Status(status),
+    ]);
+    #[cfg(not(feature = "systemd"))]
+    let _ = status;
+}
+
+/// Tell systemd the service is shutting down
+pub fn stopping() {
+    #[cfg(feature = "systemd")]
+    notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+#[cfg(feature = "systemd")]
+fn notify(state: &[sd_notify::NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        tracing::warn!(cause = ?e, "failed to notify systemd");
+    }
+}
+
+/// The listening socket systemd passed in through `LISTEN_FDS`, if any. It
+/// has to be of type `ty`, and only the first one is used
+pub fn listen_socket(ty: socket2::Type) -> std::io::Result<Option<socket2::Socket>> {
+    #[cfg(feature = "systemd")]
+    {
+        use std::os::fd::{FromRawFd, OwnedFd};
+        let mut fds = sd_notify::listen_fds()?;
+        let Some(fd) = fds.next() else {
+            return Ok(None);
+        };
+        // SAFETY: systemd hands these over to us, and listen_fds() made sure
+        // they're meant for this process. Nothing else takes ownership
+        let socket = socket2::Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        if fds.next().is_some() {
+            tracing::warn!("systemd passed in more than one socket, only the first one is used");
+        }
+        if socket.r#type()? != ty {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("systemd passed in a socket of the wrong type, expected {ty:?}"),
+            ));
+        }
+        socket.set_nonblocking(true)?;
+        Ok(Some(socket))
+    }
+    #[cfg(not(feature = "systemd"))]
+    {
+        let _ = ty;
+        Ok(None)
+    }
+}
+
+/// Ticks for as long as there's a service manager to report to: often enough
+/// to keep its watchdog happy if it has one, every STATUS_INTERVAL otherwise
+pub struct Heartbeat {
+    interval: Option<tokio::time::Interval>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        #[cfg(feature = "systemd")]
+        let period = std::env::var_os("NOTIFY_SOCKET").map(|_| {
+            let mut usec = 0;
+            if sd_notify::watchdog_enabled(false, &mut usec) {
+                Duration::from_micros(usec / 2)
+            } else {
+                STATUS_INTERVAL
+            }
+        });
+        #[cfg(not(feature = "systemd"))]
+        let period: Option<Duration> = None;
+
+        Heartbeat {
+            interval: period.map(|p| {
+                let mut i = tokio::time::interval(p.max(Duration::from_millis(100)));
+                i.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                i
+            }),
+        }
+    }
+
+    /// Resolves on the next beat, never if not under systemd
+    pub async fn tick(&mut self) {
+        match self.interval {
+            Some(ref mut i) => {
+                i.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Ping the watchdog and report the current status
+    pub fn beat(&self, status: &str) {
+        #[cfg(feature = "systemd")]
+        notify(&[
+            sd_notify::NotifyState::Watchdog,
+            sd_notify::NotifyState::Status(status),
+        ]);
+        #[cfg(not(feature = "systemd"))]
+        let _ = status;
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
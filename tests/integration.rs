@@ -54,6 +54,17 @@ fn sigint(child: &Child) {
     }
 }
 
+// Where the config called `name` is written, in a directory of its own for
+// this test run
+fn config_path(name: &str) -> PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push(format!("nat-tunnel-tests-{}", std::process::id()));
+    std::fs::create_dir_all(&path).unwrap();
+    path.push(format!("{name}.integration.toml"));
+    path
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn setup(
     addr: &SocketAddr,
     server_port: u16,
@@ -61,12 +72,15 @@ async fn setup(
     transport: &str,
     skip_tls: bool,
     self_signed: bool,
+    sts_extra: &str,
+    stc_extra: &str,
 ) -> (PathBuf, PathBuf) {
     let mut stc_cfg = format!(
         "
 psk = \"abcd\"
 addr = \"127.0.0.1:{server_port}\"
 transport = \"{transport}\"
+{stc_extra}
 
 
 [[tunnels]]
@@ -103,6 +117,7 @@ ca = \"tests/ca.pem\"
 psk = \"abcd\"
 addr = \"127.0.0.1:{server_port}\"
 transport = \"{transport}\"
+{sts_extra}
 "
     );
 
@@ -116,13 +131,8 @@ cert = \"tests/server.crt.pem\"
         );
     }
 
-    let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    d.push("tests");
-
-    let mut stc_path = d.clone();
-    stc_path.push("stc.integration.toml");
-    let mut sts_path = d.clone();
-    sts_path.push("sts.integration.toml");
+    let stc_path = config_path("stc");
+    let sts_path = config_path("sts");
 
     {
         let mut stc_f = File::create(&stc_path).unwrap();
@@ -179,6 +189,17 @@ async fn start_(
     protocol: &str,
     allow_insecure: bool,
     allow_self_signed: bool,
+) -> (ChildGuard, ChildGuard, httptest::Server, String) {
+    start_with(protocol, allow_insecure, allow_self_signed, "", "").await
+}
+
+// Like start_, but appends extra top level config to sts.toml and stc.toml
+async fn start_with(
+    protocol: &str,
+    allow_insecure: bool,
+    allow_self_signed: bool,
+    sts_extra: &str,
+    stc_extra: &str,
 ) -> (ChildGuard, ChildGuard, httptest::Server, String) {
     let server = Server::run();
     let server_port = portpicker::pick_unused_port().expect("Failed to get random port");
@@ -194,6 +215,8 @@ async fn start_(
         protocol,
         allow_insecure,
         allow_self_signed,
+        sts_extra,
+        stc_extra,
     )
     .await;
 
@@ -394,9 +417,10 @@ async fn integration_client_failure_quic() {
 #[tokio::test]
 async fn integration_server_failure() {
     let _guard = MTX.lock();
-    // Server failure MUST trigger client shutdown
+    // Server failure MUST trigger client shutdown, once out of attempts
 
-    let (mut sts_h, mut stc_h, _, _) = start_("tcp", false, false).await;
+    let (mut sts_h, mut stc_h, _, _) =
+        start_with("tcp", false, false, "", "retry = { max_attempts = 5 }").await;
 
     sts_h.kill().unwrap();
     let _ = stc_h.wait();
@@ -412,9 +436,10 @@ async fn integration_server_failure() {
 #[tokio::test]
 async fn integration_server_failure_quic() {
     let _guard = MTX.lock();
-    // Server failure MUST trigger client shutdown
+    // Server failure MUST trigger client shutdown, once out of attempts
 
-    let (mut sts_h, mut stc_h, _, _) = start_("quic", false, true).await;
+    let (mut sts_h, mut stc_h, _, _) =
+        start_with("quic", false, true, "", "retry = { max_attempts = 5 }").await;
 
     sts_h.kill().unwrap();
     let _ = stc_h.wait();
@@ -426,3 +451,2461 @@ async fn integration_server_failure_quic() {
     sigint(&stc_h);
     assert_eq!(stc_h.wait().unwrap().code().unwrap(), 1);
 }
+
+#[tokio::test]
+async fn integration_metrics() {
+    let _guard = MTX.lock();
+
+    let sts_metrics = portpicker::pick_unused_port().expect("Failed to get random port");
+    let stc_metrics = portpicker::pick_unused_port().expect("Failed to get random port");
+    let (sts_h, stc_h, server, url) = start_with(
+        "tcp",
+        true,
+        false,
+        &format!("metrics_addr = \"127.0.0.1:{sts_metrics}\""),
+        &format!("metrics_addr = \"127.0.0.1:{stc_metrics}\""),
+    )
+    .await;
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/realpath"))
+            .times(1)
+            .respond_with(status_code(200)),
+    );
+    assert!(get(&url).await.unwrap().status().is_success());
+
+    let body = get(&format!("http://127.0.0.1:{sts_metrics}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(body.contains("nat_tunnel_connected_clients 1"));
+    assert!(body.contains("nat_tunnel_active_tunnels 1"));
+    assert!(body.contains("nat_tunnel_tunnel_bytes_total"));
+
+    let body = get(&format!("http://127.0.0.1:{stc_metrics}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(body.contains("nat_tunnel_active_connections"));
+
+    let resp = get(&format!("http://127.0.0.1:{stc_metrics}/nope"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status().as_u16(), 404);
+
+    shutdown(stc_h, sts_h)
+}
+
+async fn admin(method: reqwest::Method, url: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .request(method, url)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn integration_admin() {
+    use tokio::io::AsyncReadExt;
+    let _guard = MTX.lock();
+
+    let admin_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let (sts_h, stc_h, _server, url) = start_with(
+        "tcp",
+        true,
+        false,
+        &format!("admin_addr = \"127.0.0.1:{admin_port}\""),
+        "",
+    )
+    .await;
+    let base = format!("http://127.0.0.1:{admin_port}");
+    let tunnel_addr: SocketAddr = url
+        .trim_start_matches("http://")
+        .trim_end_matches("/realpath")
+        .parse()
+        .unwrap();
+
+    let clients: serde_json::Value = admin(reqwest::Method::GET, &format!("{base}/clients"))
+        .await
+        .json()
+        .await
+        .unwrap();
+    let clients = clients.as_array().unwrap();
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients[0]["transport"], "tcp");
+    assert_eq!(clients[0]["tunnels"][0], tunnel_addr.port());
+    let client_id = clients[0]["id"].as_u64().unwrap();
+
+    let tunnels: serde_json::Value = admin(reqwest::Method::GET, &format!("{base}/tunnels"))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(tunnels[0]["remote_port"], tunnel_addr.port());
+
+    // Hold a connection open, then kill it via the API
+    let mut external = TcpStream::connect(tunnel_addr).await.unwrap();
+    let local_addr = external.local_addr().unwrap();
+    sleep(Duration::from_millis(200)).await;
+    let conns: serde_json::Value = admin(reqwest::Method::GET, &format!("{base}/connections"))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert!(conns
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|c| c["external_addr"] == local_addr.to_string()));
+
+    let resp = admin(
+        reqwest::Method::DELETE,
+        &format!("{base}/connections/{local_addr}"),
+    )
+    .await;
+    assert_eq!(resp.status().as_u16(), 204);
+    let mut buf = [0u8; 16];
+    let n = tokio::time::timeout(Duration::from_secs(2), external.read(&mut buf))
+        .await
+        .expect("connection should have been closed")
+        .unwrap();
+    assert_eq!(n, 0);
+
+    let resp = admin(reqwest::Method::DELETE, &format!("{base}/clients/9999")).await;
+    assert_eq!(resp.status().as_u16(), 404);
+
+    // Kicking the client ends its session
+    let resp = admin(
+        reqwest::Method::DELETE,
+        &format!("{base}/clients/{client_id}"),
+    )
+    .await;
+    assert_eq!(resp.status().as_u16(), 204);
+    // The client comes back under a new id once its retry delay is up
+    let mut reconnected = false;
+    for _ in 0..50 {
+        sleep(Duration::from_millis(200)).await;
+        let clients: serde_json::Value = admin(reqwest::Method::GET, &format!("{base}/clients"))
+            .await
+            .json()
+            .await
+            .unwrap();
+        let ids: Vec<_> = clients
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["id"].as_u64().unwrap())
+            .collect();
+        assert!(!ids.contains(&client_id));
+        if !ids.is_empty() {
+            reconnected = true;
+            break;
+        }
+    }
+    assert!(reconnected);
+
+    shutdown(stc_h, sts_h);
+}
+
+#[tokio::test]
+async fn integration_status() {
+    let _guard = MTX.lock();
+
+    let mut sock = std::env::temp_dir();
+    sock.push(format!("stc-{}.sock", std::process::id()));
+    let (sts_h, stc_h, server, url) = start_with(
+        "tcp",
+        true,
+        false,
+        "",
+        &format!("control_socket = {:?}", sock.to_str().unwrap()),
+    )
+    .await;
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/realpath"))
+            .times(1)
+            .respond_with(status_code(200)),
+    );
+    assert!(get(&url).await.unwrap().status().is_success());
+
+    let stc_cfg = config_path("stc");
+    let out = test_bin::get_test_bin("stc")
+        .arg("-c")
+        .arg(&stc_cfg)
+        .arg("status")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    println!("{stdout}");
+    assert!(out.status.success());
+    assert!(stdout.contains("state: connected"));
+    assert!(stdout.contains("(acknowledged)"));
+
+    shutdown(stc_h, sts_h);
+
+    // Nothing is listening anymore
+    let out = test_bin::get_test_bin("stc")
+        .arg("-c")
+        .arg(&stc_cfg)
+        .arg("status")
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(2));
+}
+
+fn sighup(child: &Child) {
+    // SAFETY: See sigint
+    unsafe {
+        libc::kill(child.id() as i32, libc::SIGHUP);
+    }
+}
+
+async fn wait_for_tunnel(url: &String, up: bool) {
+    for _ in 0..50 {
+        let ok = matches!(get(url).await, Ok(r) if r.status().is_success());
+        if ok == up {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!(
+        "tunnel at {url} never went {}",
+        if up { "up" } else { "down" }
+    );
+}
+
+#[tokio::test]
+async fn integration_reload() {
+    let _guard = MTX.lock();
+
+    let (sts_h, stc_h, server, url) = start_with("tcp", true, false, "", "").await;
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/realpath"))
+            .times(1..)
+            .respond_with(status_code(200)),
+    );
+
+    let stc_path = config_path("stc");
+    let cfg = std::fs::read_to_string(&stc_path).unwrap();
+
+    // Add a tunnel next to the existing one
+    let new_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let new_tunnel = format!(
+        "
+[[tunnels]]
+remote_port = {new_port}
+local_hostname = \"::1\"
+local_port = {}
+",
+        server.addr().port()
+    );
+    std::fs::write(&stc_path, format!("{cfg}{new_tunnel}")).unwrap();
+    sighup(&stc_h);
+    let new_url = format!("http://127.0.0.1:{new_port}/realpath");
+    wait_for_tunnel(&new_url, true).await;
+    assert!(get(&url).await.unwrap().status().is_success());
+
+    // Then drop the original one
+    let head = &cfg[..cfg.find("[[tunnels]]").unwrap()];
+    let removed = format!("{head}{new_tunnel}");
+    std::fs::write(&stc_path, removed).unwrap();
+    sighup(&stc_h);
+    wait_for_tunnel(&url, false).await;
+    assert!(get(&new_url).await.unwrap().status().is_success());
+
+    // A broken config keeps the current tunnels
+    std::fs::write(&stc_path, "this is not toml").unwrap();
+    sighup(&stc_h);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(get(&new_url).await.unwrap().status().is_success());
+
+    shutdown(stc_h, sts_h);
+}
+
+#[tokio::test]
+async fn integration_tunnel_rejected() {
+    let _guard = MTX.lock();
+
+    let mut sock = std::env::temp_dir();
+    sock.push(format!("stc-{}.sock", std::process::id()));
+    let (sts_h, stc_h, server, url) = start_with(
+        "tcp",
+        true,
+        false,
+        "",
+        &format!("control_socket = {:?}", sock.to_str().unwrap()),
+    )
+    .await;
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/realpath"))
+            .times(1..)
+            .respond_with(status_code(200)),
+    );
+
+    // Something other than sts already holds this port
+    let busy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let busy_port = busy.local_addr().unwrap().port();
+    let new_port = portpicker::pick_unused_port().expect("Failed to get random port");
+
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    for port in [busy_port, new_port] {
+        cfg.push_str(&format!(
+            "
+[[tunnels]]
+remote_port = {port}
+local_hostname = \"::1\"
+local_port = {}
+",
+            server.addr().port()
+        ));
+    }
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+
+    // The rest of the tunnels are unaffected
+    let new_url = format!("http://127.0.0.1:{new_port}/realpath");
+    wait_for_tunnel(&new_url, true).await;
+    assert!(get(&url).await.unwrap().status().is_success());
+
+    let out = test_bin::get_test_bin("stc")
+        .arg("-c")
+        .arg(&stc_path)
+        .arg("status")
+        .arg("--json")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let status: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(status["reconnect_attempts"], 0);
+    for t in status["tunnels"].as_array().unwrap() {
+        assert_eq!(t["acknowledged"], t["remote_port"] != busy_port, "{t}");
+    }
+
+    drop(busy);
+    shutdown(stc_h, sts_h);
+}
+
+#[tokio::test]
+async fn integration_tunnel_rejected_on_connect() {
+    let _guard = MTX.lock();
+
+    let internal_port = echo_server().await;
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let held_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let free_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let _sts = spawn_sts_with(
+        "sts-dup",
+        port,
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+"
+        ),
+    )
+    .await;
+    let stc_cfg = |ports: &[u16]| {
+        let mut cfg = format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+"
+        );
+        for p in ports {
+            cfg.push_str(&format!(
+                "
+[[tunnels]]
+remote_port = {p}
+local_port = {internal_port}
+"
+            ));
+        }
+        cfg
+    };
+    let _first = spawn_stc_with("stc-dup-a", &stc_cfg(&[held_port]));
+    let held = format!("127.0.0.1:{held_port}");
+    wait_for_port(&held).await;
+
+    // Only the port the first client holds is turned down, the second
+    // client keeps its session and its other tunnel
+    let _second = spawn_stc_with("stc-dup-b", &stc_cfg(&[held_port, free_port]));
+    let free = format!("127.0.0.1:{free_port}");
+    wait_for_port(&free).await;
+    let mut external = TcpStream::connect(&free).await.unwrap();
+    assert_eq!(echo(&mut external, b"hello").await, b"hello");
+    sleep(Duration::from_millis(500)).await;
+    let mut external = TcpStream::connect(&free).await.unwrap();
+    assert_eq!(echo(&mut external, b"again").await, b"again");
+    let mut external = TcpStream::connect(&held).await.unwrap();
+    assert_eq!(echo(&mut external, b"first").await, b"first");
+}
+
+#[tokio::test]
+async fn integration_ephemeral_port() {
+    let _guard = MTX.lock();
+
+    let pool_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let mut sock = std::env::temp_dir();
+    sock.push(format!("stc-{}.sock", std::process::id()));
+    let (sts_h, stc_h, server, _url) = start_with(
+        "tcp",
+        true,
+        false,
+        &format!("ephemeral_ports = {{ start = {pool_port}, end = {pool_port} }}"),
+        &format!("control_socket = {:?}", sock.to_str().unwrap()),
+    )
+    .await;
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/realpath"))
+            .times(1..)
+            .respond_with(status_code(200)),
+    );
+
+    // Two tunnels that leave the port up to the server, which only has one
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    for remote_port in ["remote_port = 0", ""] {
+        cfg.push_str(&format!(
+            "
+[[tunnels]]
+{remote_port}
+local_hostname = \"::1\"
+local_port = {}
+",
+            server.addr().port()
+        ));
+    }
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+
+    wait_for_tunnel(&format!("http://127.0.0.1:{pool_port}/realpath"), true).await;
+
+    let out = test_bin::get_test_bin("stc")
+        .arg("-c")
+        .arg(&stc_path)
+        .arg("status")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    println!("{stdout}");
+    assert!(out.status.success());
+    assert!(stdout.contains(&format!("{pool_port} (assigned) -> ::1")));
+    assert!(stdout.contains("(unassigned) -> ::1"));
+
+    shutdown(stc_h, sts_h);
+}
+
+async fn get_host(url: &str, host: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(url)
+        .header("Host", host)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn integration_vhost() {
+    let _guard = MTX.lock();
+
+    let http_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let mut sock = std::env::temp_dir();
+    sock.push(format!("stc-{}.sock", std::process::id()));
+    let (sts_h, stc_h, server, _url) = start_with(
+        "tcp",
+        true,
+        false,
+        &format!("http_addr = \"127.0.0.1:{http_port}\""),
+        &format!("control_socket = {:?}", sock.to_str().unwrap()),
+    )
+    .await;
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/realpath"),
+            request::headers(contains(("host", "app.example.test:1234"))),
+        ])
+        .times(1..)
+        .respond_with(status_code(200)),
+    );
+
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    cfg.push_str(&format!(
+        "
+[[tunnels]]
+hostname = \"App.Example.Test\"
+local_hostname = \"::1\"
+local_port = {}
+",
+        server.addr().port()
+    ));
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+
+    let url = format!("http://127.0.0.1:{http_port}/realpath");
+    let mut tries = 50;
+    while get_host(&url, "app.example.test:1234").await.status() != 200 {
+        tries -= 1;
+        assert!(tries > 0, "vhost never came up");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(get_host(&url, "other.example.test").await.status(), 404);
+
+    let out = test_bin::get_test_bin("stc")
+        .arg("-c")
+        .arg(&stc_path)
+        .arg("status")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    println!("{stdout}");
+    assert!(stdout.contains("app.example.test -> ::1"));
+    assert!(!stdout.contains("not acknowledged"));
+
+    shutdown(stc_h, sts_h);
+}
+
+// The first flight of a TLS client asking for `server_name`
+fn client_hello(server_name: &str) -> Vec<u8> {
+    let provider = std::sync::Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(rustls::RootCertStore::empty())
+        .with_no_client_auth();
+    let name = rustls::pki_types::ServerName::try_from(server_name.to_string()).unwrap();
+    let mut conn = rustls::ClientConnection::new(std::sync::Arc::new(config), name).unwrap();
+    let mut hello = Vec::new();
+    conn.write_tls(&mut hello).unwrap();
+    hello
+}
+
+// Connect to `addr`, send `data` and return whatever comes back until it is
+// echoed, or the server hangs up or goes quiet
+async fn exchange(addr: &str, data: &[u8]) -> Vec<u8> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(data).await.unwrap();
+    let mut ret = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !ret.ends_with(data) {
+        match tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => ret.extend_from_slice(&buf[..n]),
+            _ => break,
+        }
+    }
+    ret
+}
+
+// An Internal that echoes back whatever it is sent. Returns its port
+async fn echo_server() -> u16 {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let internal = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let internal_port = internal.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut s, _)) = internal.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                while let Ok(n) = s.read(&mut buf).await {
+                    if n == 0 || s.write_all(&buf[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    internal_port
+}
+
+#[tokio::test]
+async fn integration_sni() {
+    let _guard = MTX.lock();
+
+    // The Internal echoes, so we can check that the ClientHello made it
+    // through untouched
+    let internal_port = echo_server().await;
+
+    let sni_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let admin_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let mut sock = std::env::temp_dir();
+    sock.push(format!("stc-{}.sock", std::process::id()));
+    let (sts_h, stc_h, _server, _url) = start_with(
+        "tcp",
+        true,
+        false,
+        &format!("sni_addr = \"127.0.0.1:{sni_port}\"\nadmin_addr = \"127.0.0.1:{admin_port}\""),
+        &format!("control_socket = {:?}", sock.to_str().unwrap()),
+    )
+    .await;
+
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    cfg.push_str(&format!(
+        "
+[[tunnels]]
+sni_hostname = \"Secure.Example.Test\"
+local_port = {internal_port}
+"
+    ));
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+
+    let addr = format!("127.0.0.1:{sni_port}");
+    let hello = client_hello("secure.example.test");
+    let mut tries = 50;
+    while exchange(&addr, &hello).await != hello {
+        tries -= 1;
+        assert!(tries > 0, "sni tunnel never came up");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    // Unknown names get a fatal unrecognized_name alert
+    let alert = exchange(&addr, &client_hello("other.example.test")).await;
+    assert_eq!(alert, nat_tunnel::sni::UNRECOGNIZED_NAME_ALERT);
+
+    let out = test_bin::get_test_bin("stc")
+        .arg("-c")
+        .arg(&stc_path)
+        .arg("status")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    println!("{stdout}");
+    assert!(stdout.contains("secure.example.test (sni) -> 127.0.0.1"));
+    assert!(!stdout.contains("not acknowledged"));
+
+    // The admin API lists bare hostnames, with their kind on the side
+    let clients: serde_json::Value = get(&format!("http://127.0.0.1:{admin_port}/clients"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        clients[0]["hosts"],
+        serde_json::json!(["secure.example.test"])
+    );
+    assert_eq!(clients[0]["host_kinds"], serde_json::json!(["sni"]));
+
+    shutdown(stc_h, sts_h);
+}
+
+#[tokio::test]
+async fn integration_tls_termination() {
+    let _guard = MTX.lock();
+
+    // Work on copies of the certificate, so that they can be broken below
+    let dir = std::env::temp_dir().join(format!("sts-certs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let tests = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let cert = dir.join("server.crt.pem");
+    let key = dir.join("server.key.pem");
+    std::fs::copy(tests.join("server.crt.pem"), &cert).unwrap();
+    std::fs::copy(tests.join("server.key.pem"), &key).unwrap();
+
+    let tls_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let (sts_h, stc_h, server, _url) = start_with(
+        "tcp",
+        true,
+        false,
+        &format!(
+            "tls_termination = {{ ports = [{tls_port}], certs = [{{ cert = {cert:?}, key = {key:?} }}] }}"
+        ),
+        "",
+    )
+    .await;
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/realpath"))
+            .times(1..)
+            .respond_with(status_code(200)),
+    );
+
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    cfg.push_str(&format!(
+        "
+[[tunnels]]
+remote_port = {tls_port}
+local_hostname = \"::1\"
+local_port = {}
+",
+        server.addr().port()
+    ));
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+
+    // The test certificate may have expired, which is beside the point here
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let url = format!("https://localhost:{tls_port}/realpath");
+    let mut tries = 50;
+    loop {
+        match client.get(&url).send().await {
+            Ok(r) if r.status() == 200 => break,
+            _ => (),
+        }
+        tries -= 1;
+        assert!(tries > 0, "tls tunnel never came up");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    // Plaintext Externals don't make it to the Internal
+    assert!(
+        reqwest::get(format!("http://localhost:{tls_port}/realpath"))
+            .await
+            .is_err()
+    );
+
+    // A broken certificate on disk is not picked up, the old one stays
+    std::fs::write(&cert, "garbage").unwrap();
+    sighup(&sts_h);
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let fresh = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    assert_eq!(fresh.get(&url).send().await.unwrap().status(), 200);
+
+    shutdown(stc_h, sts_h);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn integration_proxy_protocol() {
+    let _guard = MTX.lock();
+
+    let internal_port = echo_server().await;
+    let (sts_h, stc_h, _server, _url) = start_with("tcp", true, false, "", "").await;
+
+    let v1_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let v2_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    cfg.push_str(&format!(
+        "
+[[tunnels]]
+remote_port = {v1_port}
+local_port = {internal_port}
+proxy_protocol = \"v1\"
+
+[[tunnels]]
+remote_port = {v2_port}
+local_port = {internal_port}
+proxy_protocol = \"v2\"
+"
+    ));
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+
+    // The Internal echoes the header back ahead of the payload
+    let addr = format!("127.0.0.1:{v1_port}");
+    let mut tries = 50;
+    let echoed = loop {
+        if TcpStream::connect(&addr).await.is_ok() {
+            let echoed = exchange(&addr, b"hello").await;
+            if !echoed.is_empty() {
+                break echoed;
+            }
+        }
+        tries -= 1;
+        assert!(tries > 0, "proxy protocol tunnel never came up");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    };
+    let echoed = String::from_utf8(echoed).unwrap();
+    assert!(
+        echoed.starts_with("PROXY TCP4 127.0.0.1 127.0.0.1 "),
+        "{echoed:?}"
+    );
+    assert!(
+        echoed.ends_with(&format!(" {v1_port}\r\nhello")),
+        "{echoed:?}"
+    );
+
+    let addr = format!("127.0.0.1:{v2_port}");
+    let echoed = exchange(&addr, b"hello").await;
+    let signature = b"\r\n\r\n\0\r\nQUIT\n";
+    assert_eq!(&echoed[..12], signature);
+    // PROXY over TCP4, with 12 bytes of addresses
+    assert_eq!(&echoed[12..16], &[0x21, 0x11, 0x00, 0x0c]);
+    assert_eq!(&echoed[16..20], &[127, 0, 0, 1]);
+    assert_eq!(&echoed[20..24], &[127, 0, 0, 1]);
+    assert_eq!(&echoed[26..28], &v2_port.to_be_bytes());
+    assert_eq!(&echoed[28..], b"hello");
+
+    shutdown(stc_h, sts_h);
+}
+
+// An Internal that switches protocols on the first request and then echoes.
+// The request head it got is sent on `heads`
+async fn upgrade_server(heads: tokio::sync::mpsc::UnboundedSender<String>) -> u16 {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let internal = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let internal_port = internal.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut s, _)) = internal.accept().await {
+            let heads = heads.clone();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut buf = [0u8; 4096];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match s.read(&mut buf).await {
+                        Ok(n) if n > 0 => head.extend_from_slice(&buf[..n]),
+                        _ => return,
+                    }
+                }
+                let _ = heads.send(String::from_utf8_lossy(&head).into_owned());
+                s.write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+                )
+                .await
+                .unwrap();
+                while let Ok(n) = s.read(&mut buf).await {
+                    if n == 0 || s.write_all(&buf[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    internal_port
+}
+
+#[tokio::test]
+async fn integration_http_tunnel() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _guard = MTX.lock();
+
+    let (heads_tx, mut heads_rx) = tokio::sync::mpsc::unbounded_channel();
+    let upgrade_port = upgrade_server(heads_tx).await;
+    let (sts_h, stc_h, server, _url) = start_with("tcp", true, false, "", "").await;
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("POST", "/realpath"),
+            request::headers(contains(("x-forwarded-for", "6.6.6.6, 127.0.0.1"))),
+            request::headers(contains(("x-forwarded-proto", "https"))),
+            request::headers(not(contains(("x-forwarded-proto", "ftp")))),
+            request::headers(contains(("forwarded", "for=127.0.0.1;proto=https"))),
+            request::body("hello"),
+        ])
+        .times(2)
+        .respond_with(status_code(200).body("world")),
+    );
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("POST", "/realpath"),
+            request::headers(contains(("x-forwarded-for", "127.0.0.1"))),
+            request::headers(contains(("x-forwarded-proto", "https"))),
+            request::headers(contains(("forwarded", "for=127.0.0.1;proto=https"))),
+            request::body("hello"),
+        ])
+        .times(1)
+        .respond_with(status_code(200).body("world")),
+    );
+
+    let http_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let ws_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    cfg.push_str(&format!(
+        "
+[[tunnels]]
+remote_port = {http_port}
+local_hostname = \"::1\"
+local_port = {}
+http = true
+forwarded_proto = \"https\"
+
+[[tunnels]]
+remote_port = {ws_port}
+local_port = {upgrade_port}
+http = true
+",
+        server.addr().port()
+    ));
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+
+    let url = format!("http://127.0.0.1:{http_port}/realpath");
+    for tries in (0..50).rev() {
+        if TcpStream::connect(format!("127.0.0.1:{http_port}"))
+            .await
+            .is_ok()
+            && TcpStream::connect(format!("127.0.0.1:{ws_port}"))
+                .await
+                .is_ok()
+        {
+            break;
+        }
+        assert!(tries > 0, "http tunnels never came up");
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    // Several requests over one keep-alive connection, with forwarding
+    // headers of their own that the External is added to, or replaces
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{http_port}"))
+        .await
+        .unwrap();
+    let req = format!(
+        "POST /realpath HTTP/1.1\r\nHost: 127.0.0.1:{http_port}\r\nX-Forwarded-For: 6.6.6.6\r\nX-Forwarded-Proto: ftp\r\nContent-Length: 5\r\n\r\nhello"
+    );
+    for _ in 0..2 {
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut got = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !got.ends_with(b"world") {
+            let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+                .await
+                .expect("keep-alive response timed out")
+                .unwrap();
+            assert!(
+                n > 0,
+                "connection closed: {:?}",
+                String::from_utf8_lossy(&got)
+            );
+            got.extend_from_slice(&buf[..n]);
+        }
+        assert!(got.starts_with(b"HTTP/1.1 200"));
+    }
+    drop(stream);
+    let resp = reqwest::Client::new()
+        .post(&url)
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // Upgraded connections are passed through as is
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{ws_port}"))
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            b"GET /ws HTTP/1.1\r\nHost: x\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut got = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !got.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0);
+        got.extend_from_slice(&buf[..n]);
+    }
+    assert!(got.starts_with(b"HTTP/1.1 101"));
+    stream.write_all(b"ping").await.unwrap();
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"ping");
+    let head = heads_rx.recv().await.unwrap();
+    assert!(head.contains("X-Forwarded-For: 127.0.0.1\r\n"), "{head}");
+
+    shutdown(stc_h, sts_h);
+}
+
+#[tokio::test]
+async fn integration_http_auth() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _guard = MTX.lock();
+
+    let dir = std::env::temp_dir().join(format!("sts-auth-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let htpasswd = dir.join("htpasswd");
+    std::fs::write(
+        &htpasswd,
+        format!(
+            "# test users\nalice:{}\n",
+            bcrypt::hash("wonderland", 4).unwrap()
+        ),
+    )
+    .unwrap();
+
+    let auth_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let (sts_h, stc_h, server, _url) = start_with(
+        "tcp",
+        true,
+        false,
+        &format!(
+            "http_auth = [{{ ports = [{auth_port}], htpasswd = {htpasswd:?}, tokens = [\"letmein\"], realm = \"test\" }}]"
+        ),
+        "",
+    )
+    .await;
+    // Only requests that were let through make it to the Internal
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/realpath"))
+            .times(3)
+            .respond_with(status_code(200).body("ok")),
+    );
+
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    cfg.push_str(&format!(
+        "
+[[tunnels]]
+remote_port = {auth_port}
+local_hostname = \"::1\"
+local_port = {}
+",
+        server.addr().port()
+    ));
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+    for tries in (0..50).rev() {
+        if TcpStream::connect(format!("127.0.0.1:{auth_port}"))
+            .await
+            .is_ok()
+        {
+            break;
+        }
+        assert!(tries > 0, "gated tunnel never came up");
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let url = format!("http://127.0.0.1:{auth_port}/realpath");
+    let client = reqwest::Client::new();
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+    let challenges: Vec<_> = resp
+        .headers()
+        .get_all("www-authenticate")
+        .iter()
+        .map(|v| v.to_str().unwrap().to_string())
+        .collect();
+    assert!(
+        challenges.contains(&"Basic realm=\"test\", charset=\"UTF-8\"".to_string()),
+        "{challenges:?}"
+    );
+    assert!(
+        challenges.contains(&"Bearer realm=\"test\"".to_string()),
+        "{challenges:?}"
+    );
+
+    let resp = client
+        .get(&url)
+        .basic_auth("alice", Some("queen of hearts"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+    let resp = client.get(&url).bearer_auth("guess").send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+
+    let resp = client
+        .get(&url)
+        .basic_auth("alice", Some("wonderland"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = client
+        .get(&url)
+        .bearer_auth("letmein")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // Every request on a keep-alive connection is checked, not just the first
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{auth_port}"))
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET /realpath HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer letmein\r\n\r\n")
+        .await
+        .unwrap();
+    let mut got = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !got.ends_with(b"ok") {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0);
+        got.extend_from_slice(&buf[..n]);
+    }
+    assert!(got.starts_with(b"HTTP/1.1 200"));
+    stream
+        .write_all(b"GET /realpath HTTP/1.1\r\nHost: x\r\n\r\n")
+        .await
+        .unwrap();
+    let mut got = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut got))
+        .await
+        .expect("connection was not closed after a 401")
+        .unwrap();
+    assert!(got.starts_with(b"HTTP/1.1 401"), "{got:?}");
+
+    shutdown(stc_h, sts_h);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn integration_pool() {
+    let _guard = MTX.lock();
+
+    let (sts_h, stc_h, _server, _) = start_with("tcp", true, false, "", "").await;
+    let pool_port = portpicker::pick_unused_port().expect("Failed to get random port");
+
+    // Each client's Internal says which client it's behind
+    let mut internals = Vec::new();
+    for name in ["one", "two"] {
+        let internal = Server::run();
+        internal.expect(
+            Expectation::matching(request::method_path("GET", "/who"))
+                .times(0..)
+                .respond_with(status_code(200).body(name)),
+        );
+        internals.push(internal);
+    }
+    let tunnel = |internal: &Server| {
+        format!(
+            "
+[[tunnels]]
+remote_port = {pool_port}
+local_hostname = \"::1\"
+local_port = {}
+pool = \"web\"
+",
+            internal.addr().port()
+        )
+    };
+
+    let stc_path = config_path("stc");
+    let base = std::fs::read_to_string(&stc_path).unwrap();
+    std::fs::write(&stc_path, format!("{base}{}", tunnel(&internals[0]))).unwrap();
+    sighup(&stc_h);
+
+    let url = format!("http://127.0.0.1:{pool_port}/who");
+    wait_for_tunnel(&url, true).await;
+
+    // A second client joins the pool from its own config
+    let stc2_path = config_path("stc2");
+    let header = base.split("[[tunnels]]").next().unwrap();
+    std::fs::write(&stc2_path, format!("{header}{}", tunnel(&internals[1]))).unwrap();
+    let mut stc2_h = ChildGuard(
+        test_bin::get_test_bin("stc")
+            .arg("-c")
+            .arg(&stc2_path)
+            .arg("--allow-insecure-transport")
+            .spawn()
+            .unwrap(),
+    );
+
+    // Connections are spread across both
+    let mut seen = std::collections::HashSet::new();
+    for _ in 0..50 {
+        if let Ok(r) = get(&url).await {
+            if r.status().is_success() {
+                seen.insert(r.text().await.unwrap());
+            }
+        }
+        if seen.len() == 2 {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(seen.len(), 2, "{seen:?}");
+
+    // The port stays up with a member gone
+    sigint(&stc2_h);
+    assert!(stc2_h.wait().unwrap().success());
+    sleep(Duration::from_millis(500)).await;
+    for _ in 0..5 {
+        let resp = get(&url).await.unwrap();
+        assert!(resp.status().is_success());
+        assert_eq!(resp.text().await.unwrap(), "one");
+    }
+
+    shutdown(stc_h, sts_h);
+}
+
+#[tokio::test]
+async fn integration_backends() {
+    let _guard = MTX.lock();
+
+    let (sts_h, stc_h, _server, _) = start_with("tcp", true, false, "", "").await;
+    let checked_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let retried_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let dead_port = portpicker::pick_unused_port().expect("Failed to get random port");
+
+    // "b" fails its health checks, so it must never be connected to
+    let mut internals = Vec::new();
+    for name in ["a", "b", "c"] {
+        let internal = Server::run();
+        let (health, who) = match name {
+            "b" => (503, 0..=0),
+            _ => (200, 0..=usize::MAX),
+        };
+        internal.expect(
+            Expectation::matching(request::method_path("GET", "/health"))
+                .times(0..)
+                .respond_with(status_code(health)),
+        );
+        internal.expect(
+            Expectation::matching(request::method_path("GET", "/who"))
+                .times(who)
+                .respond_with(status_code(200).body(name)),
+        );
+        internals.push(internal);
+    }
+    let backend = |port: u16| format!("{{ local_hostname = \"::1\", local_port = {port} }}");
+
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    cfg.push_str(&format!(
+        "
+[[tunnels]]
+remote_port = {checked_port}
+backends = [{}, {}, {}]
+health_check = {{ interval = 1, timeout = 1, http_path = \"/health\" }}
+
+[[tunnels]]
+remote_port = {retried_port}
+backends = [{}, {}]
+",
+        backend(internals[0].addr().port()),
+        backend(internals[1].addr().port()),
+        backend(internals[2].addr().port()),
+        backend(dead_port),
+        backend(internals[0].addr().port()),
+    ));
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+
+    let checked_url = format!("http://127.0.0.1:{checked_port}/who");
+    let retried_url = format!("http://127.0.0.1:{retried_port}/who");
+    wait_for_tunnel(&retried_url, true).await;
+    // Give the first health checks time to finish
+    sleep(Duration::from_millis(1500)).await;
+
+    // Connections are spread over the healthy backends only
+    let mut seen = std::collections::HashSet::new();
+    for _ in 0..10 {
+        let resp = get(&checked_url).await.unwrap();
+        assert!(resp.status().is_success());
+        seen.insert(resp.text().await.unwrap());
+    }
+    assert_eq!(
+        seen,
+        ["a", "c"].iter().map(|s| s.to_string()).collect(),
+        "{seen:?}"
+    );
+
+    // A backend that can't be connected to is skipped for the next one
+    for _ in 0..4 {
+        let resp = get(&retried_url).await.unwrap();
+        assert!(resp.status().is_success());
+        assert_eq!(resp.text().await.unwrap(), "a");
+    }
+
+    shutdown(stc_h, sts_h);
+}
+
+#[tokio::test]
+async fn integration_internal_health() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let _guard = MTX.lock();
+
+    let (sts_h, stc_h, _server, _) = start_with(
+        "tcp",
+        true,
+        false,
+        "",
+        "health_check = { interval = 1, timeout = 1 }",
+    )
+    .await;
+    let remote_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let internal_port = portpicker::pick_unused_port().expect("Failed to get random port");
+
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    cfg.push_str(&format!(
+        "
+[[tunnels]]
+remote_port = {remote_port}
+local_hostname = \"::1\"
+local_port = {internal_port}
+"
+    ));
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+
+    // Nothing listens on the Internal's port yet, so once the server hears
+    // about it Externals are turned away right away instead of hanging
+    let mut turned_away = false;
+    for _ in 0..50 {
+        sleep(Duration::from_millis(100)).await;
+        let Ok(mut stream) = TcpStream::connect(format!("127.0.0.1:{remote_port}")).await else {
+            continue;
+        };
+        let _ = stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        let mut buf = [0u8; 16];
+        if let Ok(Ok(0) | Err(_)) =
+            tokio::time::timeout(Duration::from_millis(500), stream.read(&mut buf)).await
+        {
+            turned_away = true;
+            break;
+        }
+    }
+    assert!(turned_away, "Externals were never turned away");
+
+    // Once the Internal comes up, the tunnel serves again
+    let internal = tokio::net::TcpListener::bind(format!("[::1]:{internal_port}"))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut s, _)) = internal.accept().await else {
+                return;
+            };
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                if let Ok(n) = s.read(&mut buf).await {
+                    if n > 0 {
+                        let _ = s
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                            .await;
+                    }
+                }
+            });
+        }
+    });
+    wait_for_tunnel(&format!("http://127.0.0.1:{remote_port}/"), true).await;
+
+    shutdown(stc_h, sts_h);
+}
+
+// Start an sts without TLS listening for clients on `port`, with its shared
+// HTTP listener on `http_port`
+async fn spawn_sts(name: &str, port: u16, http_port: u16) -> ChildGuard {
+    spawn_sts_with(
+        name,
+        port,
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+http_addr = \"127.0.0.1:{http_port}\"
+"
+        ),
+    )
+    .await
+}
+
+// Start an sts without TLS with the config `cfg`, listening on `port`
+async fn spawn_sts_with(name: &str, port: u16, cfg: &str) -> ChildGuard {
+    let path = config_path(name);
+    std::fs::write(&path, cfg).unwrap();
+    let sts = ChildGuard(
+        test_bin::get_test_bin("sts")
+            .arg("-c")
+            .arg(path)
+            .arg("--allow-insecure-transport")
+            .spawn()
+            .unwrap(),
+    );
+    wait_for_server(&format!("127.0.0.1:{port}").parse().unwrap()).await;
+    sts
+}
+
+// Start an stc without TLS connected to every server in `ports`, exposing
+// the Internal at `internal_port` as app.test
+fn spawn_stc(name: &str, ports: &[u16], mode: &str, internal_port: u16) -> ChildGuard {
+    let servers: Vec<_> = ports.iter().map(|p| format!("\"127.0.0.1:{p}\"")).collect();
+    spawn_stc_with(
+        name,
+        &format!(
+            "
+psk = \"abcd\"
+servers = [{}]
+server_mode = \"{mode}\"
+transport = \"tcp\"
+
+[[tunnels]]
+hostname = \"app.test\"
+local_hostname = \"::1\"
+local_port = {internal_port}
+",
+            servers.join(", ")
+        ),
+    )
+}
+
+// Start an stc without TLS with the config `cfg`
+fn spawn_stc_with(name: &str, cfg: &str) -> ChildGuard {
+    let path = config_path(name);
+    std::fs::write(&path, cfg).unwrap();
+    ChildGuard(
+        test_bin::get_test_bin("stc")
+            .arg("-c")
+            .arg(path)
+            .arg("--allow-insecure-transport")
+            .spawn()
+            .unwrap(),
+    )
+}
+
+// Whether app.test is served on the shared HTTP listener at `http_port`
+async fn serves_app(http_port: u16) -> bool {
+    let url = format!("http://127.0.0.1:{http_port}/realpath");
+    reqwest::Client::new()
+        .get(&url)
+        .header("Host", "app.test")
+        .timeout(Duration::from_secs(2))
+        .send()
+        .await
+        .is_ok_and(|r| r.status().is_success())
+}
+
+async fn wait_for_app(http_port: u16, up: bool) {
+    for _ in 0..100 {
+        if serves_app(http_port).await == up {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!(
+        "app.test on {http_port} never went {}",
+        if up { "up" } else { "down" }
+    );
+}
+
+#[tokio::test]
+async fn integration_servers() {
+    let _guard = MTX.lock();
+
+    let internal = Server::run();
+    internal.expect(
+        Expectation::matching(request::method_path("GET", "/realpath"))
+            .times(1..)
+            .respond_with(status_code(200)),
+    );
+    let mut ports = Vec::new();
+    let mut http_ports = Vec::new();
+    for _ in 0..2 {
+        ports.push(portpicker::pick_unused_port().expect("Failed to get random port"));
+        http_ports.push(portpicker::pick_unused_port().expect("Failed to get random port"));
+    }
+    let mut sts_a = spawn_sts("sts-a", ports[0], http_ports[0]).await;
+    let sts_b = spawn_sts("sts-b", ports[1], http_ports[1]).await;
+
+    // Active-active: both servers serve the tunnel
+    let mut stc = spawn_stc("stc-aa", &ports, "active-active", internal.addr().port());
+    wait_for_app(http_ports[0], true).await;
+    wait_for_app(http_ports[1], true).await;
+    sigint(&stc);
+    assert!(stc.wait().unwrap().success());
+    wait_for_app(http_ports[0], false).await;
+    wait_for_app(http_ports[1], false).await;
+
+    // Failover: only the first server serves it, until it goes away
+    let stc = spawn_stc("stc-fo", &ports, "failover", internal.addr().port());
+    wait_for_app(http_ports[0], true).await;
+    sleep(Duration::from_millis(1500)).await;
+    assert!(!serves_app(http_ports[1]).await);
+
+    sigint(&sts_a);
+    assert!(sts_a.wait().unwrap().success());
+    wait_for_app(http_ports[1], true).await;
+
+    shutdown(stc, sts_b);
+}
+
+// Servers that turn the client away are given up on, even with several
+#[tokio::test]
+async fn integration_servers_refused() {
+    let _guard = MTX.lock();
+
+    let mut ports = Vec::new();
+    let mut servers = Vec::new();
+    for name in ["sts-refuse-a", "sts-refuse-b"] {
+        let port = portpicker::pick_unused_port().expect("Failed to get random port");
+        let cfg = format!(
+            "
+psk = \"not-abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+"
+        );
+        servers.push(spawn_sts_with(name, port, &cfg).await);
+        ports.push(port);
+    }
+    let mut stc = spawn_stc("stc-refused", &ports, "active-active", 1);
+    for tries in (0..100).rev() {
+        if let Some(status) = stc.try_wait().unwrap() {
+            assert!(!status.success());
+            return;
+        }
+        assert!(tries > 0, "stc kept retrying servers that turned it away");
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+async fn integration_forward() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let _guard = MTX.lock();
+
+    let target = Server::run();
+    let allowed = format!("[::1]:{}", target.addr().port());
+    let (sts_h, stc_h, _server, _) = start_with(
+        "tcp",
+        true,
+        false,
+        &format!("forward_targets = [\"{allowed}\"]"),
+        "",
+    )
+    .await;
+    target.expect(
+        Expectation::matching(request::method_path("GET", "/forwarded"))
+            .times(1..)
+            .respond_with(status_code(200).body("forwarded")),
+    );
+    let allowed_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let refused_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let unlisted = portpicker::pick_unused_port().expect("Failed to get random port");
+
+    let stc_path = config_path("stc");
+    let mut cfg = std::fs::read_to_string(&stc_path).unwrap();
+    cfg.push_str(&format!(
+        "
+[[forwards]]
+local_addr = \"127.0.0.1:{allowed_port}\"
+target = \"{allowed}\"
+
+[[forwards]]
+local_addr = \"127.0.0.1:{refused_port}\"
+target = \"[::1]:{unlisted}\"
+"
+    ));
+    std::fs::write(&stc_path, cfg).unwrap();
+    sighup(&stc_h);
+
+    let url = format!("http://127.0.0.1:{allowed_port}/forwarded");
+    wait_for_tunnel(&url, true).await;
+    assert_eq!(get(&url).await.unwrap().text().await.unwrap(), "forwarded");
+
+    // The target closing is passed on to the local connection
+    let mut s = tokio::net::TcpStream::connect(("127.0.0.1", allowed_port))
+        .await
+        .unwrap();
+    s.write_all(b"GET /forwarded HTTP/1.1\r\nHost: target\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), s.read_to_end(&mut response))
+        .await
+        .expect("forwarded connection was never closed")
+        .unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+
+    // Targets the server doesn't allow are closed right away
+    let mut s = tokio::net::TcpStream::connect(("127.0.0.1", refused_port))
+        .await
+        .unwrap();
+    let mut buf = Vec::new();
+    let n = tokio::time::timeout(Duration::from_secs(5), s.read_to_end(&mut buf))
+        .await
+        .expect("refused forward was never closed");
+    assert!(matches!(n, Ok(0) | Err(_)));
+
+    shutdown(stc_h, sts_h);
+}
+
+#[tokio::test]
+async fn integration_secret_tunnel() {
+    use tokio::io::AsyncReadExt;
+
+    let _guard = MTX.lock();
+
+    let internal = Server::run();
+    internal.expect(
+        Expectation::matching(request::method_path("GET", "/secret"))
+            .times(1..)
+            .respond_with(status_code(200).body("secret")),
+    );
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let http_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let visitor_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let wrong_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let sts = spawn_sts("sts-secret", port, http_port).await;
+    let owner = spawn_stc_with(
+        "stc-owner",
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+
+[[tunnels]]
+secret_name = \"db\"
+secret = \"hunter2\"
+local_hostname = \"::1\"
+local_port = {}
+",
+            internal.addr().port()
+        ),
+    );
+    let mut visitor = spawn_stc_with(
+        "stc-visitor",
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+tunnels = []
+
+[[visitors]]
+local_addr = \"127.0.0.1:{visitor_port}\"
+secret_name = \"db\"
+secret = \"hunter2\"
+
+[[visitors]]
+local_addr = \"127.0.0.1:{wrong_port}\"
+secret_name = \"db\"
+secret = \"hunter3\"
+"
+        ),
+    );
+
+    // Both clients have to be connected, which takes a while in debug builds
+    let url = format!("http://127.0.0.1:{visitor_port}/secret");
+    let mut up = false;
+    for _ in 0..200 {
+        if matches!(get(&url).await, Ok(r) if r.status().is_success()) {
+            up = true;
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(up, "secret tunnel never went up");
+    assert_eq!(get(&url).await.unwrap().text().await.unwrap(), "secret");
+
+    // Visitors with the wrong secret are closed right away
+    let mut s = tokio::net::TcpStream::connect(("127.0.0.1", wrong_port))
+        .await
+        .unwrap();
+    let mut buf = Vec::new();
+    let n = tokio::time::timeout(Duration::from_secs(5), s.read_to_end(&mut buf))
+        .await
+        .expect("visitor with the wrong secret was never closed");
+    assert!(matches!(n, Ok(0) | Err(_)));
+
+    // The tunnel isn't served on the server's shared listener
+    let r = reqwest::Client::new()
+        .get(format!("http://127.0.0.1:{http_port}/secret"))
+        .header("Host", "db")
+        .send()
+        .await
+        .unwrap();
+    assert!(!r.status().is_success());
+
+    sigint(&visitor);
+    assert!(visitor.wait().unwrap().success());
+    shutdown(owner, sts);
+}
+
+#[tokio::test]
+async fn integration_retry() {
+    let _guard = MTX.lock();
+
+    let internal = Server::run();
+    internal.expect(
+        Expectation::matching(request::method_path("GET", "/realpath"))
+            .times(1..)
+            .respond_with(status_code(200)),
+    );
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let http_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let mut sock = std::env::temp_dir();
+    sock.push(format!("stc-retry-{}.sock", std::process::id()));
+
+    // Nothing listens on port yet, so the client backs off
+    let stc = spawn_stc_with(
+        "stc-retry",
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+control_socket = {:?}
+
+[retry]
+initial_delay = 0.2
+max_delay = 1
+
+[[tunnels]]
+hostname = \"app.test\"
+local_hostname = \"::1\"
+local_port = {}
+",
+            sock.to_str().unwrap(),
+            internal.addr().port()
+        ),
+    );
+    let stc_cfg = config_path("stc-retry");
+    let mut report = serde_json::Value::Null;
+    for _ in 0..50 {
+        sleep(Duration::from_millis(200)).await;
+        let out = test_bin::get_test_bin("stc")
+            .arg("-c")
+            .arg(&stc_cfg)
+            .arg("status")
+            .arg("--json")
+            .output()
+            .unwrap();
+        report = serde_json::from_slice(&out.stdout).unwrap_or_default();
+        if report["retry"]["attempt"].as_u64() >= Some(6) {
+            break;
+        }
+    }
+    // Without max_attempts it never gives up
+    assert!(report["retry"]["attempt"].as_u64() >= Some(6), "{report}");
+    let delay = report["retry"]["delay_ms"].as_u64().unwrap();
+    assert!(delay <= 1000, "{report}");
+
+    // And it connects once the server comes up
+    let sts = spawn_sts("sts-retry", port, http_port).await;
+    wait_for_app(http_port, true).await;
+    shutdown(stc, sts);
+}
+
+// A proxy to the local `target` port that drops all of its connections each
+// time `cut` is notified. Returns its port
+async fn cuttable_proxy(target: u16, cut: std::sync::Arc<tokio::sync::Notify>) -> u16 {
+    let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_port = proxy.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut s, _)) = proxy.accept().await {
+            let cut = cut.clone();
+            tokio::spawn(async move {
+                let Ok(mut t) = TcpStream::connect(("127.0.0.1", target)).await else {
+                    return;
+                };
+                tokio::select! {
+                    _ = tokio::io::copy_bidirectional(&mut s, &mut t) => (),
+                    _ = cut.notified() => (),
+                }
+            });
+        }
+    });
+    proxy_port
+}
+
+#[tokio::test]
+async fn integration_session_resume() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _guard = MTX.lock();
+
+    let internal_port = echo_server().await;
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let tunnel_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let cut = std::sync::Arc::new(tokio::sync::Notify::new());
+    let proxy_port = cuttable_proxy(port, cut.clone()).await;
+
+    let sts = spawn_sts_with(
+        "sts-resume",
+        port,
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+session_grace = 10
+"
+        ),
+    )
+    .await;
+    let stc = spawn_stc_with(
+        "stc-resume",
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{proxy_port}\"
+transport = \"tcp\"
+
+[retry]
+initial_delay = 0.2
+
+[[tunnels]]
+remote_port = {tunnel_port}
+local_port = {internal_port}
+"
+        ),
+    );
+    let tunnel = format!("127.0.0.1:{tunnel_port}");
+    wait_for_port(&tunnel).await;
+    assert_eq!(exchange(&tunnel, b"up?").await, b"up?");
+
+    let mut external = TcpStream::connect(&tunnel).await.unwrap();
+    let mut buf = [0u8; 16];
+    external.write_all(b"before").await.unwrap();
+    let n = tokio::time::timeout(Duration::from_secs(2), external.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..n], b"before");
+
+    // The External and the Internal outlive the connection between the
+    // client and the server, and what is sent meanwhile gets through once
+    // the client is back
+    cut.notify_waiters();
+    external.write_all(b"during").await.unwrap();
+    let n = tokio::time::timeout(Duration::from_secs(10), external.read(&mut buf))
+        .await
+        .expect("session should have been resumed")
+        .unwrap();
+    assert_eq!(&buf[..n], b"during");
+
+    external.write_all(b"after").await.unwrap();
+    let n = tokio::time::timeout(Duration::from_secs(2), external.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..n], b"after");
+    assert_eq!(exchange(&tunnel, b"new").await, b"new");
+
+    shutdown(stc, sts);
+}
+
+fn sigterm(child: &Child) {
+    // SAFETY: see sigint
+    unsafe {
+        libc::kill(child.id() as i32, libc::SIGTERM);
+    }
+}
+
+// Wait for the tunnel listening at `addr` to take connections
+async fn wait_for_port(addr: &str) {
+    for _ in 0..50 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("tunnel {addr} never came up");
+}
+
+// Wait for `child` to exit on its own, for up to `secs`
+async fn wait_exit(child: &mut ChildGuard, secs: u64) -> Option<std::process::ExitStatus> {
+    for _ in 0..secs * 10 {
+        if let Ok(Some(status)) = child.as_mut().try_wait() {
+            return Some(status);
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    None
+}
+
+// Write `data` to `stream` and read it back from the echo server
+async fn echo(stream: &mut TcpStream, data: &[u8]) -> Vec<u8> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    stream.write_all(data).await.unwrap();
+    let mut buf = vec![0u8; data.len()];
+    tokio::time::timeout(Duration::from_secs(2), stream.read_exact(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    buf
+}
+
+#[tokio::test]
+async fn integration_drain() {
+    let _guard = MTX.lock();
+
+    let internal_port = echo_server().await;
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let tunnel_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let mut sts = spawn_sts_with(
+        "sts-drain",
+        port,
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+drain_timeout = 10
+"
+        ),
+    )
+    .await;
+    let _stc = spawn_stc_with(
+        "stc-drain",
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+
+[[tunnels]]
+remote_port = {tunnel_port}
+local_port = {internal_port}
+"
+        ),
+    );
+    let tunnel = format!("127.0.0.1:{tunnel_port}");
+    wait_for_port(&tunnel).await;
+    let mut external = TcpStream::connect(&tunnel).await.unwrap();
+    assert_eq!(echo(&mut external, b"before").await, b"before");
+
+    // New Externals are refused while the live one carries on
+    sigterm(&sts);
+    sleep(Duration::from_millis(500)).await;
+    assert!(TcpStream::connect(&tunnel).await.is_err());
+    assert_eq!(echo(&mut external, b"during").await, b"during");
+    assert!(sts.as_mut().try_wait().unwrap().is_none());
+
+    // and sts exits once it is done
+    drop(external);
+    let status = wait_exit(&mut sts, 5)
+        .await
+        .expect("sts should have drained");
+    assert!(status.success());
+}
+
+#[tokio::test]
+async fn integration_drain_client() {
+    let _guard = MTX.lock();
+
+    let internal_port = echo_server().await;
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let http_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let tunnel_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let mut sock = std::env::temp_dir();
+    sock.push(format!("stc-drain-{}.sock", std::process::id()));
+    let sts = spawn_sts("sts-drain-client", port, http_port).await;
+    let cfg = format!(
+        "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+control_socket = {:?}
+drain_timeout = 10
+
+[[tunnels]]
+remote_port = {tunnel_port}
+local_port = {internal_port}
+",
+        sock.to_str().unwrap()
+    );
+    let mut stc = spawn_stc_with("stc-drain-client", &cfg);
+    let tunnel = format!("127.0.0.1:{tunnel_port}");
+    wait_for_port(&tunnel).await;
+    let mut external = TcpStream::connect(&tunnel).await.unwrap();
+    assert_eq!(echo(&mut external, b"before").await, b"before");
+
+    let path = config_path("stc-drain-client");
+    let out = test_bin::get_test_bin("stc")
+        .arg("-c")
+        .arg(&path)
+        .arg("drain")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+
+    // The server stops sending new Externals to the client, and the live
+    // one carries on
+    sleep(Duration::from_millis(500)).await;
+    assert!(TcpStream::connect(&tunnel).await.is_err());
+    assert_eq!(echo(&mut external, b"during").await, b"during");
+    assert!(stc.as_mut().try_wait().unwrap().is_none());
+
+    // and stc exits once it is done, leaving sts be
+    drop(external);
+    let status = wait_exit(&mut stc, 5)
+        .await
+        .expect("stc should have drained");
+    assert!(status.success());
+    let mut sts = sts;
+    assert!(sts.as_mut().try_wait().unwrap().is_none());
+}
+
+#[cfg(feature = "systemd")]
+#[tokio::test]
+async fn integration_systemd() {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let _guard = MTX.lock();
+
+    let internal_port = echo_server().await;
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let unused_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let tunnel_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let mut notify = std::env::temp_dir();
+    notify.push(format!("sts-notify-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&notify);
+    let notify_socket = std::os::unix::net::UnixDatagram::bind(&notify).unwrap();
+    notify_socket
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+
+    // sts has to find the listener passed in as fd 3, and ignore addr
+    let listener = std::net::TcpListener::bind(format!("127.0.0.1:{port}")).unwrap();
+    let fd = listener.as_raw_fd();
+    let path = config_path("sts-systemd");
+    std::fs::write(
+        &path,
+        format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{unused_port}\"
+transport = \"tcp\"
+"
+        ),
+    )
+    .unwrap();
+    let sts = test_bin::get_test_bin("sts");
+    let mut cmd = std::process::Command::new("sh");
+    cmd.arg("-c")
+        .arg("LISTEN_PID=$$ exec \"$0\" -c \"$1\" --allow-insecure-transport")
+        .arg(sts.get_program())
+        .arg(&path)
+        .env("LISTEN_FDS", "1")
+        .env("NOTIFY_SOCKET", &notify);
+    // SAFETY: only dup2 runs between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::dup2(fd, 3) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let _sts = ChildGuard(cmd.spawn().unwrap());
+    drop(listener);
+
+    let mut buf = [0u8; 256];
+    let n = notify_socket.recv(&mut buf).unwrap();
+    let msg = String::from_utf8_lossy(&buf[..n]).to_string();
+    assert!(msg.contains("READY=1"), "{msg}");
+    assert!(msg.contains("STATUS=0 clients"), "{msg}");
+
+    let _stc = spawn_stc_with(
+        "stc-systemd",
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+
+[[tunnels]]
+remote_port = {tunnel_port}
+local_port = {internal_port}
+"
+        ),
+    );
+    let tunnel = format!("127.0.0.1:{tunnel_port}");
+    wait_for_port(&tunnel).await;
+    let mut external = TcpStream::connect(&tunnel).await.unwrap();
+    assert_eq!(echo(&mut external, b"activated").await, b"activated");
+    assert!(TcpStream::connect(format!("127.0.0.1:{unused_port}"))
+        .await
+        .is_err());
+    let _ = std::fs::remove_file(&notify);
+}
+
+// The JSON log lines in `path` with the message `message`
+fn log_lines(path: &std::path::Path, message: &str) -> Vec<serde_json::Value> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| serde_json::from_str::<serde_json::Value>(l).ok())
+        .filter(|l| l["fields"]["message"] == message)
+        .collect()
+}
+
+#[tokio::test]
+async fn integration_json_logs() {
+    let _guard = MTX.lock();
+
+    let internal_port = echo_server().await;
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let tunnel_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let mut sts_log = std::env::temp_dir();
+    sts_log.push(format!("sts-json-{}.log", std::process::id()));
+    let mut stc_log = std::env::temp_dir();
+    stc_log.push(format!("stc-json-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&sts_log);
+    let _ = std::fs::remove_file(&stc_log);
+
+    let _sts = spawn_sts_with(
+        "sts-json",
+        port,
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+
+[logging]
+format = \"json\"
+file = {:?}
+modules = {{ \"nat_tunnel::server::clientstream\" = \"warn\" }}
+",
+            sts_log.to_str().unwrap()
+        ),
+    )
+    .await;
+    let _stc = spawn_stc_with(
+        "stc-json",
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+
+[logging]
+format = \"json\"
+file = {:?}
+
+[[tunnels]]
+remote_port = {tunnel_port}
+local_port = {internal_port}
+",
+            stc_log.to_str().unwrap()
+        ),
+    );
+    let tunnel = format!("127.0.0.1:{tunnel_port}");
+    wait_for_port(&tunnel).await;
+    let mut external = TcpStream::connect(&tunnel).await.unwrap();
+    assert_eq!(echo(&mut external, b"hello").await, b"hello");
+    drop(external);
+
+    // Both ends log the External under the ID the server picked for it
+    let mut incoming = vec![];
+    let mut connecting = vec![];
+    for _ in 0..20 {
+        incoming = log_lines(&sts_log, "incoming connection");
+        connecting = log_lines(&stc_log, "connecting to Internal");
+        if !incoming.is_empty() && incoming.len() == connecting.len() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(!incoming.is_empty());
+    let ids: std::collections::HashSet<_> = incoming
+        .iter()
+        .map(|l| l["span"]["conn_id"].as_str().unwrap().to_string())
+        .collect();
+    for l in connecting.iter() {
+        let id = l["span"]["conn_id"].as_str().unwrap();
+        assert!(ids.contains(id), "{id} not in {ids:?}");
+    }
+    assert_eq!(connecting.len(), incoming.len());
+
+    // and the server's clientstream module logs at warn only
+    assert!(log_lines(&sts_log, "accepted connection from client").is_empty());
+    let _ = std::fs::remove_file(&sts_log);
+    let _ = std::fs::remove_file(&stc_log);
+}
+
+#[tokio::test]
+async fn integration_access_log() {
+    let _guard = MTX.lock();
+
+    let internal_port = echo_server().await;
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let tunnel_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let mut sts_log = std::env::temp_dir();
+    sts_log.push(format!("sts-main-{}.log", std::process::id()));
+    let mut access_log = std::env::temp_dir();
+    access_log.push(format!("sts-access-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&sts_log);
+    let _ = std::fs::remove_file(&access_log);
+
+    let _sts = spawn_sts_with(
+        "sts-access",
+        port,
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+access_log = {:?}
+
+[logging]
+format = \"json\"
+file = {:?}
+",
+            access_log.to_str().unwrap(),
+            sts_log.to_str().unwrap()
+        ),
+    )
+    .await;
+    let _stc = spawn_stc_with(
+        "stc-access",
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+
+[[tunnels]]
+remote_port = {tunnel_port}
+local_port = {internal_port}
+"
+        ),
+    );
+    let tunnel = format!("127.0.0.1:{tunnel_port}");
+    wait_for_port(&tunnel).await;
+    // wait_for_port's own connections are logged too, so look for this one
+    // by its address
+    let mut external = TcpStream::connect(&tunnel).await.unwrap();
+    let external_addr = external.local_addr().unwrap().to_string();
+    assert_eq!(echo(&mut external, b"hello").await, b"hello");
+    assert_eq!(echo(&mut external, b"again").await, b"again");
+    drop(external);
+
+    let mut entry = None;
+    for _ in 0..50 {
+        entry = log_lines(&access_log, "connection closed")
+            .into_iter()
+            .find(|l| l["fields"]["external_addr"] == external_addr.as_str());
+        if entry.is_some() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let entry = entry.expect("no access log line for the connection");
+    let fields = &entry["fields"];
+    assert_eq!(entry["target"], "nat_tunnel::access");
+    assert_eq!(fields["remote_port"], tunnel_port);
+    assert!(fields["client"].is_u64());
+    assert!(fields["client_addr"]
+        .as_str()
+        .unwrap()
+        .contains("127.0.0.1"));
+    assert_eq!(fields["bytes_in"], 10);
+    assert_eq!(fields["bytes_out"], 10);
+    assert_eq!(fields["reason"], "closed");
+    assert!(fields["duration_ms"].is_u64());
+    assert!(fields["conn_id"].is_string());
+
+    // and only there
+    assert!(!log_lines(&sts_log, "incoming connection").is_empty());
+    assert!(log_lines(&sts_log, "connection closed").is_empty());
+    let _ = std::fs::remove_file(&sts_log);
+    let _ = std::fs::remove_file(&access_log);
+}
+
+// Without access_log, access lines go with the rest even when the level
+// would drop them
+#[tokio::test]
+async fn integration_access_log_at_warn() {
+    let _guard = MTX.lock();
+
+    let internal_port = echo_server().await;
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let tunnel_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let mut sts_log = std::env::temp_dir();
+    sts_log.push(format!("sts-warn-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&sts_log);
+
+    let _sts = spawn_sts_with(
+        "sts-warn",
+        port,
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+
+[logging]
+format = \"json\"
+level = \"warn\"
+file = {:?}
+",
+            sts_log.to_str().unwrap()
+        ),
+    )
+    .await;
+    let _stc = spawn_stc_with(
+        "stc-warn",
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+
+[[tunnels]]
+remote_port = {tunnel_port}
+local_port = {internal_port}
+"
+        ),
+    );
+    let tunnel = format!("127.0.0.1:{tunnel_port}");
+    wait_for_port(&tunnel).await;
+    let mut external = TcpStream::connect(&tunnel).await.unwrap();
+    let external_addr = external.local_addr().unwrap().to_string();
+    assert_eq!(echo(&mut external, b"hello").await, b"hello");
+    drop(external);
+
+    let mut entry = None;
+    for _ in 0..50 {
+        entry = log_lines(&sts_log, "connection closed")
+            .into_iter()
+            .find(|l| l["fields"]["external_addr"] == external_addr.as_str());
+        if entry.is_some() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let entry = entry.expect("no access log line for the connection");
+    assert_eq!(entry["target"], "nat_tunnel::access");
+    // while the other info lines are still dropped
+    assert!(log_lines(&sts_log, "incoming connection").is_empty());
+    let _ = std::fs::remove_file(&sts_log);
+}
+
+// The spans in OTLP/JSON trace exports, along with the service they're from
+#[cfg(feature = "otel")]
+fn otlp_spans(exports: &[serde_json::Value]) -> Vec<(String, serde_json::Value)> {
+    let mut spans = vec![];
+    for export in exports {
+        for rs in export["resourceSpans"].as_array().into_iter().flatten() {
+            let service = rs["resource"]["attributes"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .find(|a| a["key"] == "service.name")
+                .and_then(|a| a["value"]["stringValue"].as_str())
+                .unwrap_or_default()
+                .to_string();
+            for ss in rs["scopeSpans"].as_array().into_iter().flatten() {
+                for span in ss["spans"].as_array().into_iter().flatten() {
+                    spans.push((service.clone(), span.clone()));
+                }
+            }
+        }
+    }
+    spans
+}
+
+#[cfg(feature = "otel")]
+#[tokio::test]
+async fn integration_otel() {
+    let _guard = MTX.lock();
+
+    use std::sync::{Arc, Mutex};
+
+    let traces = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+    let metrics = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+    let collector = Server::run();
+    for (path, exports) in [("/v1/traces", &traces), ("/v1/metrics", &metrics)] {
+        let exports = exports.clone();
+        collector.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", path),
+                request::body(json_decoded(move |v: &serde_json::Value| {
+                    exports.lock().unwrap().push(v.clone());
+                    true
+                })),
+            ])
+            .times(..)
+            .respond_with(status_code(200)),
+        );
+    }
+    let otel = format!(
+        "
+[otel]
+endpoint = \"http://{}\"
+protocol = \"http/json\"
+metrics_interval = 1
+",
+        collector.addr()
+    );
+
+    let internal_port = echo_server().await;
+    let port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let tunnel_port = portpicker::pick_unused_port().expect("Failed to get random port");
+    let _sts = spawn_sts_with(
+        "sts-otel",
+        port,
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+{otel}"
+        ),
+    )
+    .await;
+    let _stc = spawn_stc_with(
+        "stc-otel",
+        &format!(
+            "
+psk = \"abcd\"
+addr = \"127.0.0.1:{port}\"
+transport = \"tcp\"
+
+[[tunnels]]
+remote_port = {tunnel_port}
+local_port = {internal_port}
+{otel}"
+        ),
+    );
+    let tunnel = format!("127.0.0.1:{tunnel_port}");
+    wait_for_port(&tunnel).await;
+    let mut external = TcpStream::connect(&tunnel).await.unwrap();
+    assert_eq!(echo(&mut external, b"hello").await, b"hello");
+    drop(external);
+
+    // Spans are exported in batches every few seconds
+    let mut linked = false;
+    let mut complete = false;
+    for _ in 0..150 {
+        let spans = otlp_spans(&traces.lock().unwrap());
+        let has = |service: &str, name: &str| {
+            spans
+                .iter()
+                .any(|(s, span)| s == service && span["name"] == name)
+        };
+        // The client's side of a connection is part of the server's trace
+        linked = spans
+            .iter()
+            .filter(|(s, span)| s == "sts" && span["name"] == "connection")
+            .any(|(_, conn)| {
+                spans.iter().any(|(s, span)| {
+                    s == "stc"
+                        && span["name"] == "new_conn"
+                        && span["traceId"] == conn["traceId"]
+                        && span["parentSpanId"] == conn["spanId"]
+                })
+            });
+        complete = has("sts", "handshake")
+            && has("stc", "handshake")
+            && has("sts", "register_tunnels")
+            && has("stc", "register_tunnels")
+            && has("sts", "Redirector")
+            && has("stc", "Redirector");
+        if linked && complete {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(linked, "no client span in the trace of a server connection");
+    assert!(complete, "missing handshake, tunnel or connection spans");
+
+    let exported = |name: &str| {
+        metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|m| m.to_string().contains(name))
+    };
+    assert!(exported("nat_tunnel_active_tunnels"));
+    assert!(exported("nat_tunnel_tunnel_bytes_total"));
+}